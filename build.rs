@@ -10,7 +10,7 @@ fn main() {
 <trustInfo xmlns="urn:schemas-microsoft-com:asm.v3">
     <security>
         <requestedPrivileges>
-            <requestedExecutionLevel level="requireAdministrator" />
+            <requestedExecutionLevel level="asInvoker" />
         </requestedPrivileges>
     </security>
 </trustInfo>
@@ -170,7 +170,7 @@ pub mod main {
 use serde::{Deserialize, Serialize};
 
 /// 页面主题颜色，有亮色和暗色区分
-#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 pub enum Theme {
     /// 亮色模式
     Light,
@@ -189,13 +189,19 @@ use druid::{Color, Env, FontDescriptor, FontFamily, FontStyle, FontWeight};
 #[cfg(target_os = "windows")]
 fn get_font() -> FontFamily {
     use druid::piet::{D2DText, DwriteFactory, Text};
-    let dwrite = DwriteFactory::new().unwrap();
-    let mut text = D2DText::new_with_shared_fonts(dwrite, None);
-    text.font_family("微软雅黑")
-        .or_else(|| text.font_family("Segoe WPC"))
-        .or_else(|| text.font_family("Segoe UI"))
-        .or_else(|| text.font_family("Garamond"))
-        .unwrap_or(FontFamily::SYSTEM_UI)
+    // 切换主题时会重新设置配色，字体只需要查找一次
+    thread_local! {
+        static FONT: FontFamily = {
+            let dwrite = DwriteFactory::new().unwrap();
+            let mut text = D2DText::new_with_shared_fonts(dwrite, None);
+            text.font_family("微软雅黑")
+                .or_else(|| text.font_family("Segoe WPC"))
+                .or_else(|| text.font_family("Segoe UI"))
+                .or_else(|| text.font_family("Garamond"))
+                .unwrap_or(FontFamily::SYSTEM_UI)
+        };
+    }
+    FONT.with(|font| font.clone())
 }
 
 #[cfg(not(target_os = "windows"))]
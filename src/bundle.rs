@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use crate::download::download_file;
+use crate::manifest::{self, Channel};
+use crate::ncm_utils::NcmType;
+use crate::operations::Reporter;
+use crate::vc_runtime;
+
+const INDEX_FILE: &str = "bundle.json";
+const MANIFEST_FILE: &str = "manifest.json";
+/// Bundles with this name next to the installer are picked up automatically.
+const DEFAULT_BUNDLE_NAME: &str = "betterncm-offline";
+
+/// Maps every URL the installer would download to a file inside the bundle,
+/// so plans stay the same whether they run online or offline.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BundleIndex {
+    pub installer_version: String,
+    pub manifest: String,
+    pub artifacts: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+pub struct OfflineBundle {
+    pub root: PathBuf,
+    pub index: BundleIndex,
+}
+
+impl OfflineBundle {
+    /// Opens a bundle directory, or extracts a bundle zip archive to a temporary directory first.
+    pub fn open(path: &Path) -> Result<OfflineBundle> {
+        let root = if path.is_dir() {
+            path.to_path_buf()
+        } else {
+            let root =
+                env::temp_dir().join(format!("{DEFAULT_BUNDLE_NAME}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            ZipArchive::new(File::open(path)?)
+                .and_then(|mut archive| archive.extract(&root))
+                .with_context(|| format!("Failed to extract offline bundle {}", path.display()))?;
+            root
+        };
+        let index: BundleIndex = serde_json::from_str(
+            &fs::read_to_string(root.join(INDEX_FILE))
+                .with_context(|| format!("{} is not an offline bundle", path.display()))?,
+        )?;
+        tracing::info!(
+            path = %path.display(),
+            root = %root.display(),
+            artifacts = index.artifacts.len(),
+            "opened offline bundle"
+        );
+        Ok(OfflineBundle { root, index })
+    }
+
+    pub fn manifest(&self) -> Result<String> {
+        Ok(fs::read_to_string(self.root.join(&self.index.manifest))?)
+    }
+
+    pub fn artifact(&self, url: &str) -> Result<PathBuf> {
+        let path = self
+            .index
+            .artifacts
+            .get(url)
+            .map(|path| self.root.join(path))
+            .with_context(|| format!("{url} is not included in the offline bundle"))?;
+        if !path.exists() {
+            bail!("{} is missing from the offline bundle", path.display());
+        }
+        Ok(path)
+    }
+}
+
+static ACTIVE_BUNDLE: OnceLock<OfflineBundle> = OnceLock::new();
+
+pub fn activate(path: &Path) -> Result<()> {
+    let bundle = OfflineBundle::open(path)?;
+    if ACTIVE_BUNDLE.set(bundle).is_err() {
+        bail!("An offline bundle is already active");
+    }
+    Ok(())
+}
+
+pub fn active() -> Option<&'static OfflineBundle> {
+    ACTIVE_BUNDLE.get()
+}
+
+/// Looks for `betterncm-offline` or `betterncm-offline.zip` next to the installer.
+pub fn find_default_bundle() -> Option<PathBuf> {
+    let dir = env::current_exe().ok()?.parent()?.to_path_buf();
+    [
+        dir.join(DEFAULT_BUNDLE_NAME),
+        dir.join(format!("{DEFAULT_BUNDLE_NAME}.zip")),
+    ]
+    .into_iter()
+    .find(|path| path.exists())
+}
+
+fn artifact_file_name(index: usize, url: &str) -> String {
+    let name = url
+        .rsplit('/')
+        .next()
+        .and_then(|name| name.split(['?', '#']).next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("artifact");
+    format!("files/{index:03}-{name}")
+}
+
+/// Downloads everything the given channels reference into a bundle directory, or a zip archive
+/// when `dest` ends with `.zip`.
+pub fn create(
+    dest: &Path,
+    channels: &[Channel],
+    plugins: &[String],
+    reporter: &dyn Reporter,
+) -> Result<()> {
+    let (manifest, raw_manifest) = manifest::fetch()?;
+
+    let mut urls = vec![];
+    for channel in channels {
+        for entry in manifest.channel(*channel).values() {
+            urls.push(entry.url_x86.clone());
+            urls.push(entry.url_x64.clone());
+        }
+    }
+    urls.extend(
+        [NcmType::X86, NcmType::X64]
+            .iter()
+            .map(|arch| vc_runtime::redist_source(arch).url),
+    );
+    urls.extend(plugins.iter().cloned());
+
+    let is_zip = dest
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+    let root = if is_zip {
+        env::temp_dir().join(format!(
+            "{DEFAULT_BUNDLE_NAME}-build-{}",
+            std::process::id()
+        ))
+    } else {
+        dest.to_path_buf()
+    };
+    fs::create_dir_all(root.join("files"))?;
+
+    let mut index = BundleIndex {
+        installer_version: env!("CARGO_PKG_VERSION").to_string(),
+        manifest: MANIFEST_FILE.to_string(),
+        artifacts: BTreeMap::new(),
+    };
+    fs::write(root.join(MANIFEST_FILE), raw_manifest)?;
+    for (i, url) in urls.iter().enumerate() {
+        if index.artifacts.contains_key(url) {
+            continue;
+        }
+        let file = artifact_file_name(i, url);
+        download_file(url, &root.join(&file), reporter)?;
+        index.artifacts.insert(url.clone(), file);
+    }
+    fs::write(root.join(INDEX_FILE), serde_json::to_string_pretty(&index)?)?;
+    tracing::info!(
+        dest = %dest.display(),
+        artifacts = index.artifacts.len(),
+        "created offline bundle"
+    );
+
+    if is_zip {
+        zip_dir(&root, dest)?;
+        let _ = fs::remove_dir_all(&root);
+    }
+    Ok(())
+}
+
+fn zip_dir(root: &Path, dest: &Path) -> Result<()> {
+    let mut zip = ZipWriter::new(File::create(dest)?);
+    let options = FileOptions::default();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let name = path
+                .strip_prefix(root)?
+                .to_string_lossy()
+                .replace('\\', "/");
+            zip.start_file(name, options)?;
+            zip.write_all(&fs::read(&path)?)?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::copy_from_bundle;
+    use crate::manifest::ManifestSource;
+    use crate::ncm_utils::sha256_file;
+    use crate::operations::{self, Step};
+    use crate::test_util::TempDir;
+
+    const DLL_URL: &str = "https://betterncm.invalid/x64/msimg32.dll";
+    const MIRROR_URL: &str = "https://mirror.betterncm.invalid/x64/msimg32.dll";
+    const DLL: &[u8] = b"BetterNCM for x64";
+
+    struct NullReporter;
+
+    impl Reporter for NullReporter {
+        fn tip(&self, _tip: String) {}
+
+        fn progress(&self, _progress: f64) {}
+    }
+
+    /// A bundle that only has the DLL under its mirror URL, with a manifest hashing `sha256`.
+    fn fixture(name: &str, sha256: &str) -> TempDir {
+        let root = TempDir::new(name);
+        fs::create_dir_all(root.join("files")).unwrap();
+        fs::write(root.join("files/000-msimg32.dll"), DLL).unwrap();
+        let manifest = serde_json::json!({
+            "versions": {
+                ">=2.10.0": {
+                    "version": "1.0.0",
+                    "url_x86": "https://betterncm.invalid/x86/msimg32.dll",
+                    "url_x64": DLL_URL,
+                    "mirrors_x64": [MIRROR_URL],
+                    "sha256_x64": sha256
+                }
+            }
+        });
+        fs::write(root.join(MANIFEST_FILE), manifest.to_string()).unwrap();
+        let index = BundleIndex {
+            installer_version: env!("CARGO_PKG_VERSION").to_string(),
+            manifest: MANIFEST_FILE.to_string(),
+            artifacts: BTreeMap::from([(
+                MIRROR_URL.to_string(),
+                "files/000-msimg32.dll".to_string(),
+            )]),
+        };
+        fs::write(
+            root.join(INDEX_FILE),
+            serde_json::to_string(&index).unwrap(),
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn opens_bundle_directories_and_archives() {
+        let root = fixture("open", "00");
+        let bundle = OfflineBundle::open(&root).unwrap();
+        assert_eq!(fs::read(bundle.artifact(MIRROR_URL).unwrap()).unwrap(), DLL);
+        assert!(bundle.artifact(DLL_URL).is_err());
+
+        let dir = TempDir::new("open-zip");
+        let archive = dir.join("betterncm-offline.zip");
+        zip_dir(&root, &archive).unwrap();
+        let bundle = OfflineBundle::open(&archive).unwrap();
+        assert_eq!(bundle.index.artifacts.len(), 1);
+        assert_eq!(fs::read(bundle.artifact(MIRROR_URL).unwrap()).unwrap(), DLL);
+        assert!(manifest::Manifest::parse(&bundle.manifest().unwrap()).is_ok());
+
+        fs::remove_file(root.join("files/000-msimg32.dll")).unwrap();
+        assert!(OfflineBundle::open(&root)
+            .unwrap()
+            .artifact(MIRROR_URL)
+            .is_err());
+    }
+
+    #[test]
+    fn names_artifacts_after_their_urls() {
+        assert_eq!(
+            artifact_file_name(3, "https://example.com/a/VC_redist.x64.exe?x=1"),
+            "files/003-VC_redist.x64.exe"
+        );
+        assert_eq!(
+            artifact_file_name(12, "https://example.com/"),
+            "files/012-artifact"
+        );
+    }
+
+    #[test]
+    fn serves_manifest_and_downloads() {
+        let dir = TempDir::new("bundle-expected");
+        fs::write(dir.join("msimg32.dll"), DLL).unwrap();
+        let root = fixture("serve", &sha256_file(&dir.join("msimg32.dll")).unwrap());
+        let bundle = OfflineBundle::open(&root).unwrap();
+
+        let fetched = manifest::load_from_bundle(&bundle).unwrap();
+        assert_eq!(fetched.source, ManifestSource::Bundle);
+        let entry = fetched.manifest.versions.values().next().unwrap();
+
+        // Only the mirror URL is in the bundle, and the hosts don't resolve
+        let dest = dir.join("downloaded.dll");
+        let urls = [entry.url_x64.clone(), entry.mirrors_x64[0].clone()];
+        copy_from_bundle(&bundle, &urls, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), DLL);
+        assert!(copy_from_bundle(&bundle, &urls[..1], &dest).is_err());
+
+        let verify = |sha256: &str| {
+            [Step::VerifyFile {
+                path: dest.clone(),
+                sha256: sha256.to_string(),
+            }]
+        };
+        operations::run_steps(&verify(entry.sha256_x64.as_deref().unwrap()), &NullReporter)
+            .unwrap();
+        let err = operations::run_steps(&verify(&"0".repeat(64)), &NullReporter).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{err:#}");
+    }
+}
@@ -0,0 +1,664 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+
+use crate::bundle;
+use crate::compat;
+use crate::conflicts;
+use crate::deploy::{self, DeployResult, DeployStatus};
+use crate::diagnostics;
+use crate::download::{self, DownloadProgress};
+use crate::i18n::{fill, format_age, msgs, Lang};
+use crate::manifest::{self, Channel, Manifest};
+use crate::ncm_utils::{get_betterncm_profile_path, get_file_version, get_ncm_install_path, Ncm};
+use crate::network;
+use crate::operations::{self, Operation, Plan, Reporter, Step};
+use crate::policy::{self, Policy};
+use crate::privilege;
+use crate::receipt::{self, CheckOutcome};
+use crate::release_notes;
+use crate::status;
+use crate::verify::{self, Verification};
+
+const USAGE: &str = "\
+Usage: betterncm_installer [--lang <zh-CN|en-US>] [--offline <bundle>] [--proxy <url>] [COMMAND]
+
+Without a command the installer window is opened. --offline reads the manifest and every
+download from an offline bundle directory or zip archive instead of the network. --proxy
+sends every request through the given HTTP proxy.
+
+Commands:
+  install [--test] [--dry-run]        Install the adapted BetterNCM version
+  update [--test] [--dry-run]         Reinstall or update BetterNCM
+  uninstall [--dry-run]               Remove BetterNCM from NetEase Cloud Music
+  migrate-legacy [--dry-run]          Remove an old (v1) BetterNCM installation, keeping a backup
+  restore-legacy [<backup>] [--dry-run]
+                                      Undo migrate-legacy from the given or the newest backup
+  set-profile <dir> [--dry-run]       Set BETTERNCM_PROFILE to <dir>
+  reset-profile [--dry-run]           Remove BETTERNCM_PROFILE
+  check [--dry-run]                   Re-apply BetterNCM when an NCM update removed it or changed
+                                      the NCM version since the last install; meant for a
+                                      scheduled task
+  conflicts [--quarantine] [--dry-run]
+                                      List DLLs next to NCM that conflict with BetterNCM and
+                                      optionally move them into a backup folder
+  verify                              Check the installed files against the manifest
+  repair [--dry-run]                  Fix what verify reports, without touching anything else
+  status [--json]                     Print the installation state, as JSON for scripts
+  diagnostics [--output <file.zip>]   Export a diagnostics bundle for support requests
+  bundle <dir|file.zip> [--test] [--plugin <url>]...
+                                      Build an offline bundle from the online manifest,
+                                      including the test channel and extra plugins if given
+  deploy [--policy <file.json>] [--result <file.json>] [--dry-run]
+                                      Install or update BetterNCM as the policy file describes,
+                                      without any prompts. Uses the machine policy when no
+                                      policy is given and prints a JSON result
+  network [<url>]                     Show the network settings and the proxy used for <url>
+                                      (the manifest by default), then try to fetch it
+  help                                Print this message
+
+--dry-run prints what the command would do without changing anything.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CliCommand {
+    Install {
+        channel: Channel,
+        dry_run: bool,
+    },
+    Update {
+        channel: Channel,
+        dry_run: bool,
+    },
+    Uninstall {
+        dry_run: bool,
+    },
+    MigrateLegacy {
+        dry_run: bool,
+    },
+    RestoreLegacy {
+        backup: Option<PathBuf>,
+        dry_run: bool,
+    },
+    SetProfile {
+        path: PathBuf,
+        dry_run: bool,
+    },
+    ResetProfile {
+        dry_run: bool,
+    },
+    Check {
+        dry_run: bool,
+    },
+    Conflicts {
+        quarantine: bool,
+        dry_run: bool,
+    },
+    Verify,
+    Repair {
+        dry_run: bool,
+    },
+    Status {
+        json: bool,
+    },
+    Diagnostics {
+        output: Option<PathBuf>,
+    },
+    Deploy {
+        policy: Option<PathBuf>,
+        result: Option<PathBuf>,
+        dry_run: bool,
+    },
+    Bundle {
+        dest: PathBuf,
+        channels: Vec<Channel>,
+        plugins: Vec<String>,
+    },
+    Network {
+        url: Option<String>,
+    },
+    /// Runs steps for an unelevated installer; started by `privilege::run_elevated`.
+    /// `sha256` is the hash of the request, so a request changed after it was written is refused.
+    ElevatedHelper {
+        request: PathBuf,
+        result: PathBuf,
+        sha256: String,
+    },
+    Help,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CliArgs {
+    pub lang: Option<Lang>,
+    pub offline: Option<PathBuf>,
+    pub proxy: Option<String>,
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Default)]
+struct CommandArgs {
+    positional: Vec<String>,
+    dry_run: bool,
+    test: bool,
+    json: bool,
+    quarantine: bool,
+    output: Option<PathBuf>,
+    plugins: Vec<String>,
+    policy: Option<PathBuf>,
+    result: Option<PathBuf>,
+}
+
+impl CommandArgs {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<CommandArgs> {
+        let mut parsed = CommandArgs::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dry-run" => parsed.dry_run = true,
+                "--test" => parsed.test = true,
+                "--json" => parsed.json = true,
+                "--quarantine" => parsed.quarantine = true,
+                "--output" | "-o" => {
+                    parsed.output =
+                        Some(PathBuf::from(args.next().context("--output needs a path")?))
+                }
+                "--policy" => {
+                    parsed.policy =
+                        Some(PathBuf::from(args.next().context("--policy needs a path")?))
+                }
+                "--result" => {
+                    parsed.result =
+                        Some(PathBuf::from(args.next().context("--result needs a path")?))
+                }
+                "--plugin" => parsed
+                    .plugins
+                    .push(args.next().context("--plugin needs a URL")?),
+                _ if arg.starts_with('-') => bail!("Unknown argument: {arg}"),
+                _ => parsed.positional.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn channel(&self) -> Channel {
+        Channel::from_prerelease(self.test)
+    }
+}
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliArgs> {
+    let mut parsed = CliArgs::default();
+    let mut args = args.into_iter();
+    let mut command_args = vec![];
+
+    while let Some(arg) = args.next() {
+        if let Some(tag) = arg.strip_prefix("--lang=") {
+            parsed.lang = Lang::from_tag(tag);
+        } else if arg == "--lang" {
+            parsed.lang = args.next().and_then(|tag| Lang::from_tag(&tag));
+        } else if arg == "--proxy" {
+            parsed.proxy = Some(args.next().context("--proxy needs a URL")?);
+        } else if arg == "--offline" {
+            parsed.offline = Some(PathBuf::from(
+                args.next().context("--offline needs a bundle path")?,
+            ));
+        } else {
+            command_args.push(arg);
+        }
+    }
+
+    let mut command_args = command_args.into_iter();
+    let command = match command_args.next() {
+        Some(command) => command,
+        None => return Ok(parsed),
+    };
+    let args = CommandArgs::parse(command_args)?;
+    parsed.command = Some(match command.as_str() {
+        "install" => CliCommand::Install {
+            channel: args.channel(),
+            dry_run: args.dry_run,
+        },
+        "update" => CliCommand::Update {
+            channel: args.channel(),
+            dry_run: args.dry_run,
+        },
+        "uninstall" => CliCommand::Uninstall {
+            dry_run: args.dry_run,
+        },
+        "migrate-legacy" => CliCommand::MigrateLegacy {
+            dry_run: args.dry_run,
+        },
+        "restore-legacy" => CliCommand::RestoreLegacy {
+            backup: args.positional.first().map(PathBuf::from),
+            dry_run: args.dry_run,
+        },
+        "set-profile" => CliCommand::SetProfile {
+            path: PathBuf::from(
+                args.positional
+                    .first()
+                    .context("set-profile needs a directory")?,
+            ),
+            dry_run: args.dry_run,
+        },
+        "reset-profile" => CliCommand::ResetProfile {
+            dry_run: args.dry_run,
+        },
+        "check" => CliCommand::Check {
+            dry_run: args.dry_run,
+        },
+        "conflicts" => CliCommand::Conflicts {
+            quarantine: args.quarantine,
+            dry_run: args.dry_run,
+        },
+        "verify" => CliCommand::Verify,
+        "repair" => CliCommand::Repair {
+            dry_run: args.dry_run,
+        },
+        "status" => CliCommand::Status { json: args.json },
+        "diagnostics" => CliCommand::Diagnostics {
+            output: args.output,
+        },
+        "deploy" => CliCommand::Deploy {
+            policy: args.policy,
+            result: args.result,
+            dry_run: args.dry_run,
+        },
+        "bundle" => CliCommand::Bundle {
+            dest: PathBuf::from(
+                args.positional
+                    .first()
+                    .context("bundle needs a destination")?,
+            ),
+            channels: if args.test {
+                vec![Channel::Stable, Channel::Test]
+            } else {
+                vec![Channel::Stable]
+            },
+            plugins: args.plugins,
+        },
+        "network" => CliCommand::Network {
+            url: args.positional.first().cloned(),
+        },
+        "elevated-helper" => match args.positional.as_slice() {
+            [request, result, sha256] => CliCommand::ElevatedHelper {
+                request: PathBuf::from(request),
+                result: PathBuf::from(result),
+                sha256: sha256.clone(),
+            },
+            _ => bail!("elevated-helper needs a request path, a result path and a checksum"),
+        },
+        "help" | "--help" | "-h" => CliCommand::Help,
+        command => bail!("Unknown command: {command}\n\n{USAGE}"),
+    });
+
+    Ok(parsed)
+}
+
+/// The release build uses the windows subsystem, so reuse the console of the calling shell for output.
+pub fn attach_console() {
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+struct ConsoleReporter;
+
+impl Reporter for ConsoleReporter {
+    fn tip(&self, tip: String) {
+        if !tip.is_empty() {
+            println!("{tip}");
+        }
+    }
+
+    fn progress(&self, _progress: f64) {}
+
+    fn download(&self, progress: &DownloadProgress) {
+        // Keeps rewriting one line instead of printing a line per update
+        if progress.finished {
+            println!("\r{progress}");
+        } else {
+            print!("\r{progress}");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+/// Fetches the manifest and warns on stderr when only a cached copy was available.
+fn fetch_manifest() -> Result<Manifest> {
+    let fetched = manifest::fetch_with_source()?;
+    if let Some(age) = fetched.stale_age() {
+        eprintln!(
+            "{}",
+            fill(msgs().manifest_stale, &[("age", &format_age(age))])
+        );
+    }
+    Ok(fetched.manifest)
+}
+
+fn detect_ncm() -> Result<Ncm> {
+    get_ncm_install_path()
+        .and_then(Ncm::get_ncm_by_path)
+        .context("NetEase Cloud Music is not installed")
+}
+
+fn plan_install(operation: Operation, channel: Channel) -> Result<Plan> {
+    let ncm = detect_ncm()?;
+    if ncm.path.join("cloudmusicn.exe").exists() {
+        bail!("An old BetterNCM installation was found, run migrate-legacy first");
+    }
+    let installed = ncm.path.join("msimg32.dll").exists();
+    match (operation, installed) {
+        (Operation::Install, true) => bail!("BetterNCM is already installed, use update instead"),
+        (Operation::Update, false) => bail!("BetterNCM is not installed, use install instead"),
+        _ => {}
+    }
+
+    let conflicts = conflicts::scan(&ncm.path);
+    if !conflicts.is_empty() {
+        eprintln!("{}", conflicts::describe(&conflicts));
+    }
+
+    let manifest = fetch_manifest()?;
+    if !manifest.is_supported_by(&manifest::installer_version()) {
+        bail!("{}", msgs().manifest_unsupported);
+    }
+    // Same choice as the window and `deploy`, which honour the channel and version of the policy
+    let channel = policy::active().map_or(channel, |policy| policy.channel());
+    let pinned = policy::active().and_then(|policy| policy.version.as_ref());
+    let Some((_, entry)) = manifest.resolve(channel, &ncm, pinned)? else {
+        if let Some(version) = pinned {
+            bail!(
+                "BetterNCM {version} pinned by the policy is not adapted to NCM {}",
+                ncm.version
+            );
+        }
+        eprintln!("{}", compat::explain(&manifest, channel, &ncm.version)?);
+        bail!("No BetterNCM version is adapted to NCM {}", ncm.version);
+    };
+
+    let installed = get_file_version(&ncm.path.join("msimg32.dll")).ok();
+    let notes = release_notes::collect(&manifest, installed.as_ref(), &entry.version);
+    if !notes.is_empty() {
+        println!("{}", msgs().release_notes);
+        println!("{}\n", release_notes::render(&notes));
+    }
+    Ok(operations::plan_install(
+        operation,
+        &ncm,
+        channel,
+        entry,
+        &get_betterncm_profile_path(),
+    ))
+}
+
+fn verify_installation() -> Result<Verification> {
+    let ncm = detect_ncm()?;
+    let manifest = fetch_manifest()?;
+    Ok(verify::verify(&ncm, &manifest))
+}
+
+/// Scheduled runs must not kill a running NCM when the machine policy forbids it.
+fn ensure_may_close_ncm(plan: &Plan) -> Result<()> {
+    let closes_ncm = plan
+        .steps
+        .iter()
+        .any(|step| matches!(step, Step::KillProcess { .. }));
+    let may_close_ncm = policy::active().is_none_or(|policy| policy.allow_close_ncm);
+    if closes_ncm && !may_close_ncm && operations::is_process_running("cloudmusic.exe") {
+        bail!("NetEase Cloud Music is running and the policy does not allow closing it");
+    }
+    Ok(())
+}
+
+fn run_plan(plan: Plan, dry_run: bool) -> Result<i32> {
+    println!(
+        "{}",
+        fill(msgs().plan_title, &[("operation", &plan.operation.title())])
+    );
+    print!("{plan}");
+    if dry_run {
+        println!("{}", msgs().dry_run_notice);
+        return Ok(0);
+    }
+    let summary = operations::execute(&plan, &ConsoleReporter)?;
+    println!("{}", msgs().changes_made);
+    for change in &summary.changes {
+        println!("- {change}");
+    }
+    // Same code as the VC redist uses, so deployment scripts can schedule a restart
+    Ok(if summary.reboot_required { 3010 } else { 0 })
+}
+
+/// Runs a command without opening the window and returns the process exit code.
+pub fn run(command: CliCommand) -> Result<i32> {
+    match command {
+        CliCommand::Install { channel, dry_run } => {
+            run_plan(plan_install(Operation::Install, channel)?, dry_run)
+        }
+        CliCommand::Update { channel, dry_run } => {
+            run_plan(plan_install(Operation::Update, channel)?, dry_run)
+        }
+        CliCommand::Uninstall { dry_run } => {
+            run_plan(operations::plan_uninstall(&detect_ncm()?), dry_run)
+        }
+        CliCommand::MigrateLegacy { dry_run } => {
+            run_plan(operations::plan_migrate_legacy(&detect_ncm()?), dry_run)
+        }
+        CliCommand::RestoreLegacy { backup, dry_run } => {
+            let backup = backup
+                .or_else(operations::latest_legacy_backup)
+                .context(msgs().no_legacy_backup)?;
+            run_plan(
+                operations::plan_restore_legacy(&detect_ncm()?, &backup),
+                dry_run,
+            )
+        }
+        CliCommand::SetProfile { path, dry_run } => {
+            run_plan(operations::plan_set_profile(&path), dry_run)
+        }
+        CliCommand::ResetProfile { dry_run } => run_plan(operations::plan_reset_profile(), dry_run),
+        CliCommand::Check { dry_run } => {
+            let manifest = fetch_manifest()?;
+            match receipt::check(&manifest)? {
+                CheckOutcome::Unchanged => {
+                    println!("{}", msgs().install_unchanged);
+                    Ok(0)
+                }
+                CheckOutcome::Reapply { drift, plan } => {
+                    println!("{drift}");
+                    ensure_may_close_ncm(&plan)?;
+                    run_plan(plan, dry_run)
+                }
+                CheckOutcome::NoAdaptedVersion { drift, ncm_version } => {
+                    eprintln!(
+                        "{}",
+                        fill(
+                            msgs().reapply_not_adapted,
+                            &[("reason", &drift), ("version", &ncm_version)]
+                        )
+                    );
+                    // Same code as `deploy` uses when nothing is adapted
+                    Ok(5)
+                }
+            }
+        }
+        CliCommand::Conflicts {
+            quarantine,
+            dry_run,
+        } => {
+            let ncm = detect_ncm()?;
+            let conflicts = conflicts::scan(&ncm.path);
+            if conflicts.is_empty() {
+                println!("{}", msgs().verify_ok);
+                return Ok(0);
+            }
+            println!("{}", conflicts::describe(&conflicts));
+            if !quarantine {
+                return Ok(1);
+            }
+            run_plan(conflicts::plan_quarantine(&ncm, &conflicts), dry_run)
+        }
+        CliCommand::Verify => {
+            let verification = verify_installation()?;
+            print!("{verification}");
+            Ok(if verification.issues.is_empty() { 0 } else { 1 })
+        }
+        CliCommand::Repair { dry_run } => {
+            let verification = verify_installation()?;
+            print!("{verification}");
+            if verification.plan.steps.is_empty() {
+                return Ok(if verification.issues.is_empty() { 0 } else { 1 });
+            }
+            run_plan(verification.plan, dry_run)
+        }
+        CliCommand::Status { json } => {
+            let manifest = fetch_manifest()
+                .map_err(|err| tracing::warn!(error = ?err, "failed to fetch manifest"))
+                .ok();
+            let status = status::collect(None, manifest.as_ref());
+            if json {
+                println!("{}", serde_json::to_string_pretty(&status)?);
+            } else {
+                print!("{status}");
+            }
+            Ok(0)
+        }
+        CliCommand::Diagnostics { output } => {
+            let output =
+                output.unwrap_or_else(|| PathBuf::from(diagnostics::default_bundle_name()));
+            diagnostics::export_bundle(&output, None)?;
+            println!("{}", output.display());
+            Ok(0)
+        }
+        CliCommand::Deploy {
+            policy,
+            result,
+            dry_run,
+        } => {
+            let policy = match policy {
+                Some(path) => Policy::load(&path),
+                None => policy::machine_policy().map(Option::unwrap_or_default),
+            };
+            let deploy_result = match policy {
+                Ok(policy) => deploy::run(&policy, dry_run),
+                Err(err) => DeployResult::failed(DeployStatus::InvalidPolicy, err),
+            };
+            let json = serde_json::to_string_pretty(&deploy_result)?;
+            if let Some(path) = result {
+                fs::write(&path, &json)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+            }
+            println!("{json}");
+            Ok(deploy_result.exit_code)
+        }
+        CliCommand::Bundle {
+            dest,
+            channels,
+            plugins,
+        } => {
+            bundle::create(&dest, &channels, &plugins, &ConsoleReporter)?;
+            println!("{}", dest.display());
+            Ok(0)
+        }
+        CliCommand::Network { url } => {
+            let url = url.unwrap_or_else(manifest::manifest_url);
+            let settings = network::settings();
+            println!("{settings:#?}");
+            match network::proxy_for(&url) {
+                Some(proxy) => println!("{}", fill(msgs().network_proxy, &[("proxy", &proxy)])),
+                None => println!("{}", msgs().network_direct),
+            }
+            let started = Instant::now();
+            let response = network::get(&url, |request| request)
+                .with_context(|| format!("Failed to fetch {url}"))?;
+            let status = response.status();
+            let size = io::copy(&mut response.into_reader(), &mut io::sink())?;
+            println!(
+                "{}",
+                fill(
+                    msgs().network_fetched,
+                    &[
+                        ("url", &url),
+                        ("status", &status),
+                        ("size", &download::format_bytes(size)),
+                        ("time", &format!("{:.2}s", started.elapsed().as_secs_f64())),
+                    ],
+                )
+            );
+            Ok(0)
+        }
+        CliCommand::ElevatedHelper {
+            request,
+            result,
+            sha256,
+        } => privilege::run_helper(&request, &result, &sha256),
+        CliCommand::Help => {
+            println!("{USAGE}");
+            Ok(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliArgs> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn command(args: &[&str]) -> CliCommand {
+        parse(args).unwrap().command.unwrap()
+    }
+
+    #[test]
+    fn opens_the_window_without_a_command() {
+        assert_eq!(parse(&[]).unwrap(), CliArgs::default());
+    }
+
+    #[test]
+    fn parses_dry_run_for_every_mutating_command() {
+        assert_eq!(
+            command(&["install", "--dry-run"]),
+            CliCommand::Install {
+                channel: Channel::Stable,
+                dry_run: true
+            }
+        );
+        assert_eq!(
+            command(&["update", "--test"]),
+            CliCommand::Update {
+                channel: Channel::Test,
+                dry_run: false
+            }
+        );
+        assert_eq!(
+            command(&["uninstall", "--dry-run"]),
+            CliCommand::Uninstall { dry_run: true }
+        );
+        assert_eq!(
+            command(&["set-profile", r"D:\BetterNCM", "--dry-run"]),
+            CliCommand::SetProfile {
+                path: PathBuf::from(r"D:\BetterNCM"),
+                dry_run: true
+            }
+        );
+        assert_eq!(
+            command(&["restore-legacy"]),
+            CliCommand::RestoreLegacy {
+                backup: None,
+                dry_run: false
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["install", "--force"]).is_err());
+        assert!(parse(&["frobnicate"]).is_err());
+        assert!(parse(&["set-profile"]).is_err());
+        assert!(parse(&["elevated-helper", "request.json", "result.json"]).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use anyhow::{Context, Result};
+use semver::{Comparator, Op, Version, VersionReq};
+use serde::Serialize;
+
+use crate::i18n::{fill, msgs};
+use crate::manifest::{Channel, Manifest};
+
+/// Why a channel has nothing for an NCM version, and what would work instead.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Unadapted {
+    pub ncm_version: Version,
+    pub channel: Channel,
+    /// NCM version ranges the channel covers, yanked builds left out.
+    pub ranges: Vec<String>,
+    /// The newest supported NCM version older than `ncm_version`.
+    pub below: Option<Nearest>,
+    /// The oldest supported NCM version newer than `ncm_version`.
+    pub above: Option<Nearest>,
+    /// The BetterNCM version the other channel would install.
+    pub other_channel: Option<Version>,
+}
+
+/// The edge of a supported NCM range.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Nearest {
+    Version(Version),
+    /// Everything up to this version, which itself is not supported, as in `<2.10.0`.
+    Below(Version),
+}
+
+impl Nearest {
+    /// `Below(v)` sorts right before `Version(v)`.
+    fn key(&self) -> (&Version, bool) {
+        match self {
+            Nearest::Version(version) => (version, true),
+            Nearest::Below(version) => (version, false),
+        }
+    }
+
+    /// A version the range has to contain for this edge to be part of it.
+    fn probe(&self) -> Option<Version> {
+        match self {
+            Nearest::Version(version) => Some(version.clone()),
+            // The highest release below `version`
+            Nearest::Below(version) => match (version.major, version.minor, version.patch) {
+                (0, 0, 0) => None,
+                (major, 0, 0) => Some(Version::new(major - 1, u64::MAX, u64::MAX)),
+                (major, minor, 0) => Some(Version::new(major, minor - 1, u64::MAX)),
+                (major, minor, patch) => Some(Version::new(major, minor, patch - 1)),
+            },
+        }
+    }
+}
+
+impl PartialOrd for Nearest {
+    fn partial_cmp(&self, other: &Nearest) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Nearest {
+    fn cmp(&self, other: &Nearest) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl fmt::Display for Nearest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Nearest::Version(version) => write!(f, "{version}"),
+            Nearest::Below(version) => {
+                f.write_str(&fill(msgs().unadapted_any_below, &[("version", version)]))
+            }
+        }
+    }
+}
+
+/// NCM versions at the edges of a comparator; a range's closest members are always among these.
+fn boundary_versions(comparator: &Comparator) -> Vec<Nearest> {
+    let (major, minor, patch) = (comparator.major, comparator.minor, comparator.patch);
+    let base = Version::new(major, minor.unwrap_or(0), patch.unwrap_or(0));
+    let next_major = Nearest::Below(Version::new(major + 1, 0, 0));
+    let next_minor = |minor: u64| Nearest::Below(Version::new(major, minor + 1, 0));
+    let upper = match comparator.op {
+        Op::Greater => Some(Nearest::Version(match (minor, patch) {
+            (Some(_), Some(_)) => Version::new(major, base.minor, base.patch + 1),
+            (Some(_), None) => Version::new(major, base.minor + 1, 0),
+            _ => Version::new(major + 1, 0, 0),
+        })),
+        Op::Less => Some(match patch {
+            Some(patch) if patch > 0 => {
+                Nearest::Version(Version::new(major, base.minor, patch - 1))
+            }
+            _ => Nearest::Below(base.clone()),
+        }),
+        Op::LessEq | Op::Wildcard => Some(match (minor, patch) {
+            (Some(_), Some(_)) => Nearest::Version(base.clone()),
+            (Some(minor), None) => next_minor(minor),
+            _ => next_major,
+        }),
+        Op::Tilde => Some(match minor {
+            Some(minor) => next_minor(minor),
+            None => next_major,
+        }),
+        Op::Caret => Some(match (major, minor, patch) {
+            (0, Some(0), Some(_)) => Nearest::Version(base.clone()),
+            (0, Some(minor), _) => next_minor(minor),
+            _ => next_major,
+        }),
+        _ => None,
+    };
+    let mut versions = vec![Nearest::Version(base)];
+    versions.extend(upper);
+    versions
+}
+
+/// Explains why `channel` has no BetterNCM for `ncm_version`.
+pub fn explain(manifest: &Manifest, channel: Channel, ncm_version: &Version) -> Result<Unadapted> {
+    let mut ranges = vec![];
+    let mut below: Option<Nearest> = None;
+    let mut above: Option<Nearest> = None;
+    for (version_req, entry) in manifest.channel(channel) {
+        if entry.yanked {
+            continue;
+        }
+        let req = VersionReq::parse(version_req).context("Failed to parse version req")?;
+        ranges.push(version_req.clone());
+        for edge in req.comparators.iter().flat_map(boundary_versions) {
+            if !edge.probe().is_some_and(|probe| req.matches(&probe)) {
+                continue;
+            }
+            let (version, _) = edge.key();
+            let older = match &edge {
+                Nearest::Version(version) => version < ncm_version,
+                Nearest::Below(version) => version <= ncm_version,
+            };
+            if older {
+                if below.as_ref().is_none_or(|below| &edge > below) {
+                    below = Some(edge);
+                }
+            } else if version != ncm_version && above.as_ref().is_none_or(|above| &edge < above) {
+                above = Some(edge);
+            }
+        }
+    }
+    let other_channel = manifest
+        .find_adapted_to(channel.other(), ncm_version)?
+        .map(|(_, entry)| entry.version.clone());
+    Ok(Unadapted {
+        ncm_version: ncm_version.clone(),
+        channel,
+        ranges,
+        below,
+        above,
+        other_channel,
+    })
+}
+
+impl fmt::Display for Unadapted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = msgs();
+        let mut lines = vec![fill(
+            m.unadapted_reason,
+            &[
+                ("version", &self.ncm_version),
+                ("ranges", &self.ranges.join("; ")),
+            ],
+        )];
+        if let Some(below) = &self.below {
+            lines.push(fill(m.unadapted_below, &[("version", below)]));
+        }
+        if let Some(above) = &self.above {
+            lines.push(fill(m.unadapted_above, &[("version", above)]));
+        }
+        if let Some(version) = &self.other_channel {
+            let message = match self.channel {
+                Channel::Stable => m.unadapted_in_test,
+                Channel::Test => m.unadapted_in_stable,
+            };
+            lines.push(fill(message, &[("version", version)]));
+        }
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    fn nearest(text: &str) -> Nearest {
+        Nearest::Version(version(text))
+    }
+
+    fn any_below(text: &str) -> Nearest {
+        Nearest::Below(version(text))
+    }
+
+    fn entry(version: &str, yanked: bool) -> serde_json::Value {
+        serde_json::json!({
+            "version": version,
+            "url_x86": "https://example.com/x86.dll",
+            "url_x64": "https://example.com/x64.dll",
+            "yanked": yanked
+        })
+    }
+
+    fn manifest() -> Manifest {
+        let json = serde_json::json!({
+            "versions": {
+                "=2.9.5": entry("1.0.0", true),
+                "=2.9.8": entry("1.1.0", false),
+                ">=2.10.0, <2.10.6": entry("1.2.0", false)
+            },
+            "test": { ">=2.10.6": entry("1.3.0-beta", false) }
+        });
+        Manifest::parse(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn finds_the_closest_supported_versions() {
+        let manifest = manifest();
+        // NCM version, closest older and newer supported NCM
+        let table = [
+            ("2.9.8", None, Some("2.10.0")),
+            ("2.10.3", Some("2.10.0"), Some("2.10.5")),
+            // The yanked "=2.9.5" is not offered
+            ("2.9.7", None, Some("2.9.8")),
+            ("2.9.9", Some("2.9.8"), Some("2.10.0")),
+            ("3.0.0", Some("2.10.5"), None),
+        ];
+        for (ncm, below, above) in table {
+            let unadapted = explain(&manifest, Channel::Stable, &version(ncm)).unwrap();
+            assert_eq!(unadapted.ranges, ["=2.9.8", ">=2.10.0, <2.10.6"], "{ncm}");
+            assert_eq!(unadapted.below, below.map(nearest), "{ncm}");
+            assert_eq!(unadapted.above, above.map(nearest), "{ncm}");
+        }
+    }
+
+    #[test]
+    fn looks_in_the_other_channel() {
+        let manifest = manifest();
+        let stable = explain(&manifest, Channel::Stable, &version("2.10.8")).unwrap();
+        assert_eq!(stable.other_channel, Some(version("1.3.0-beta")));
+        let test = explain(&manifest, Channel::Test, &version("2.9.8")).unwrap();
+        assert_eq!(test.other_channel, Some(version("1.1.0")));
+        assert_eq!(test.above, Some(nearest("2.10.6")));
+        let none = explain(&manifest, Channel::Stable, &version("2.9.7")).unwrap();
+        assert_eq!(none.other_channel, None);
+    }
+
+    #[test]
+    fn takes_the_edges_of_each_comparator() {
+        let edges = |req: &str| boundary_versions(&VersionReq::parse(req).unwrap().comparators[0]);
+        let table = [
+            (">2.9", [nearest("2.9.0"), nearest("2.10.0")]),
+            (">2.9.8", [nearest("2.9.8"), nearest("2.9.9")]),
+            ("<2.10.6", [nearest("2.10.6"), nearest("2.10.5")]),
+            ("<2.10.0", [nearest("2.10.0"), any_below("2.10.0")]),
+            ("<=2.9", [nearest("2.9.0"), any_below("2.10.0")]),
+            ("~2.9.1", [nearest("2.9.1"), any_below("2.10.0")]),
+            ("2.9.*", [nearest("2.9.0"), any_below("2.10.0")]),
+            ("^2.9", [nearest("2.9.0"), any_below("3.0.0")]),
+        ];
+        for (req, expected) in table {
+            assert_eq!(edges(req), expected, "{req}");
+        }
+        assert_eq!(edges("=2.9.8"), [nearest("2.9.8")]);
+    }
+
+    #[test]
+    fn reports_exclusive_upper_bounds() {
+        let json = serde_json::json!({
+            "versions": {
+                ">=2.9.0, <2.10.0": entry("1.1.0", false),
+                ">=2.10.5": entry("1.2.0", false)
+            }
+        });
+        let manifest = Manifest::parse(&json.to_string()).unwrap();
+        let unadapted = explain(&manifest, Channel::Stable, &version("2.10.2")).unwrap();
+        // Any 2.9.x is supported, not only 2.9.0
+        assert_eq!(unadapted.below, Some(any_below("2.10.0")));
+        assert_eq!(unadapted.above, Some(nearest("2.10.5")));
+        assert!(unadapted.to_string().contains(&fill(
+            msgs().unadapted_below,
+            &[(
+                "version",
+                &fill(msgs().unadapted_any_below, &[("version", &"2.10.0")])
+            )]
+        )));
+        let older = explain(&manifest, Channel::Stable, &version("2.8.0")).unwrap();
+        assert_eq!((older.below, older.above), (None, Some(nearest("2.9.0"))));
+        // Nothing is below 0.0.0
+        assert!(any_below("0.0.0").probe().is_none());
+    }
+
+    #[test]
+    fn explains_every_part() {
+        let m = msgs();
+        let unadapted = explain(&manifest(), Channel::Stable, &version("2.10.8")).unwrap();
+        assert_eq!(
+            unadapted.to_string(),
+            [
+                fill(
+                    m.unadapted_reason,
+                    &[
+                        ("version", &"2.10.8"),
+                        ("ranges", &"=2.9.8; >=2.10.0, <2.10.6")
+                    ]
+                ),
+                fill(m.unadapted_below, &[("version", &"2.10.5")]),
+                fill(m.unadapted_in_test, &[("version", &"1.3.0-beta")]),
+            ]
+            .join("\n")
+        );
+    }
+}
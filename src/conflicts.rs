@@ -0,0 +1,298 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::i18n::{fill, msgs};
+use crate::ncm_utils::Ncm;
+use crate::operations::{Operation, Plan, Step};
+use crate::settings::installer_data_dir;
+
+/// System DLLs that NCM loads from its own directory first, which makes them popular for injection.
+const PROXY_DLL_NAMES: &[&str] = &[
+    "version.dll",
+    "winmm.dll",
+    "dbghelp.dll",
+    "winhttp.dll",
+    "wininet.dll",
+    "dinput8.dll",
+    "dsound.dll",
+    "d3d9.dll",
+    "dxgi.dll",
+    "uxtheme.dll",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    ProxyDll,
+    BetterNcmVariant,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub kind: ConflictKind,
+    pub company: Option<String>,
+    pub product: Option<String>,
+    pub exports: usize,
+    /// Exports that forward to another DLL, which is how proxy DLLs pass calls on to the real one.
+    pub forwarded_exports: usize,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let text = match self.kind {
+            ConflictKind::ProxyDll => fill(msgs().conflict_proxy_dll, &[("name", &name)]),
+            ConflictKind::BetterNcmVariant => {
+                fill(msgs().conflict_betterncm_variant, &[("name", &name)])
+            }
+        };
+        f.write_str(&text)
+    }
+}
+
+#[derive(Debug, Default)]
+struct DllDetails {
+    company: Option<String>,
+    product: Option<String>,
+    description: Option<String>,
+    exports: usize,
+    forwarded_exports: usize,
+}
+
+macro_rules! read_details {
+    ($file:expr) => {{
+        let file = $file;
+        let mut details = DllDetails::default();
+        if let Some(version_info) = file
+            .resources()
+            .ok()
+            .and_then(|res| res.version_info().ok())
+        {
+            if let Some(&lang) = version_info.translation().first() {
+                details.company = version_info.value(lang, "CompanyName");
+                details.product = version_info.value(lang, "ProductName");
+                details.description = version_info.value(lang, "FileDescription");
+            }
+        }
+        if let Ok(by) = file.exports().and_then(|exports| exports.by()) {
+            for (_, export) in by.iter_names() {
+                details.exports += 1;
+                if export.is_ok_and(|export| export.forward().is_some()) {
+                    details.forwarded_exports += 1;
+                }
+            }
+        }
+        details
+    }};
+}
+
+fn read_dll(path: &Path) -> Result<DllDetails> {
+    use pelite::pe32::{Pe as _, PeFile as PeFile32};
+    use pelite::pe64::{Pe as _, PeFile as PeFile64};
+    use pelite::FileMap;
+
+    let map = FileMap::open(path)?;
+    Ok(match PeFile32::from_bytes(&map) {
+        Ok(file) => read_details!(file),
+        Err(_) => read_details!(PeFile64::from_bytes(&map)?),
+    })
+}
+
+fn classify(path: &Path) -> Option<Conflict> {
+    let name = path.file_name()?.to_string_lossy().to_lowercase();
+    // That is the BetterNCM this installer manages
+    if name == "msimg32.dll" {
+        return None;
+    }
+    let details = read_dll(path).unwrap_or_default();
+    let mentions_betterncm = [&details.product, &details.description]
+        .into_iter()
+        .flatten()
+        .chain([&name])
+        .any(|text| text.to_lowercase().contains("betterncm"));
+    let kind = if mentions_betterncm {
+        ConflictKind::BetterNcmVariant
+    } else if PROXY_DLL_NAMES.contains(&name.as_str()) {
+        let genuine = details
+            .company
+            .as_deref()
+            .is_some_and(|company| company.contains("Microsoft"))
+            && details.forwarded_exports == 0;
+        if genuine {
+            return None;
+        }
+        ConflictKind::ProxyDll
+    } else {
+        return None;
+    };
+    Some(Conflict {
+        path: path.to_path_buf(),
+        kind,
+        company: details.company,
+        product: details.product,
+        exports: details.exports,
+        forwarded_exports: details.forwarded_exports,
+    })
+}
+
+/// Looks for other injectors next to NCM that tend to crash it together with BetterNCM.
+pub fn scan(ncm_dir: &Path) -> Vec<Conflict> {
+    let mut conflicts: Vec<Conflict> = fs::read_dir(ncm_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("dll"))
+        })
+        .filter_map(|path| classify(&path))
+        .collect();
+    conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+    if !conflicts.is_empty() {
+        tracing::warn!(conflicts = ?conflicts, "found conflicting DLLs");
+    }
+    conflicts
+}
+
+pub fn describe(conflicts: &[Conflict]) -> String {
+    let files: Vec<String> = conflicts
+        .iter()
+        .map(|conflict| conflict.to_string())
+        .collect();
+    fill(
+        msgs().conflicts_found,
+        &[("count", &conflicts.len()), ("files", &files.join(", "))],
+    )
+}
+
+/// Moves the conflicting files into a backup folder, from where they can be copied back by hand.
+pub fn plan_quarantine(ncm: &Ncm, conflicts: &[Conflict]) -> Plan {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let quarantine = installer_data_dir()
+        .join("quarantine")
+        .join(timestamp.to_string());
+
+    let mut steps = vec![Step::KillProcess {
+        name: "cloudmusic.exe".into(),
+    }];
+    for conflict in conflicts {
+        let name = conflict.path.file_name().unwrap_or_default();
+        steps.extend([
+            Step::CopyFile {
+                from: conflict.path.clone(),
+                to: quarantine.join(name),
+            },
+            Step::DeleteFile {
+                path: conflict.path.clone(),
+            },
+        ]);
+    }
+    steps.push(Step::LaunchNcm {
+        path: ncm.path.clone(),
+    });
+    Plan {
+        operation: Operation::Quarantine,
+        steps,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use super::*;
+    use crate::ncm_utils::NcmType;
+    use crate::test_util::TempDir;
+
+    fn ncm_dir(name: &str, files: &[&str]) -> TempDir {
+        let dir = TempDir::new(name);
+        for file in files {
+            fs::write(dir.join(file), b"not a PE file").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn finds_injectors_next_to_ncm() {
+        let dir = ncm_dir(
+            "conflicts",
+            &[
+                "version.dll",
+                "BetterNCM-old.dll",
+                "msimg32.dll",
+                "libcef.dll",
+                "betterncm.txt",
+            ],
+        );
+        let found: Vec<(String, ConflictKind)> = scan(&dir)
+            .into_iter()
+            .map(|conflict| {
+                let name = conflict.path.file_name().unwrap().to_string_lossy();
+                (name.to_string(), conflict.kind)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (
+                    "BetterNCM-old.dll".to_string(),
+                    ConflictKind::BetterNcmVariant
+                ),
+                ("version.dll".to_string(), ConflictKind::ProxyDll),
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_missing_directories_as_clean() {
+        assert!(scan(Path::new(r"Z:\betterncm-test\missing")).is_empty());
+    }
+
+    #[test]
+    fn quarantines_every_conflict_before_launching_ncm() {
+        let ncm = Ncm {
+            path: PathBuf::from(r"C:\Program Files\Netease\CloudMusic"),
+            version: Version::new(2, 10, 6),
+            ncm_type: NcmType::X64,
+        };
+        let conflict = Conflict {
+            path: ncm.path.join("version.dll"),
+            kind: ConflictKind::ProxyDll,
+            company: None,
+            product: None,
+            exports: 0,
+            forwarded_exports: 0,
+        };
+        let plan = plan_quarantine(&ncm, &[conflict.clone()]);
+        assert_eq!(plan.operation, Operation::Quarantine);
+        assert_eq!(plan.steps.len(), 4);
+        assert!(matches!(plan.steps[0], Step::KillProcess { .. }));
+        let Step::CopyFile { from, to } = &plan.steps[1] else {
+            panic!("{:?}", plan.steps);
+        };
+        assert_eq!(from, &conflict.path);
+        assert!(to.starts_with(installer_data_dir().join("quarantine")));
+        assert!(to.ends_with("version.dll"));
+        assert_eq!(
+            plan.steps[2],
+            Step::DeleteFile {
+                path: conflict.path
+            }
+        );
+        assert!(matches!(plan.steps[3], Step::LaunchNcm { .. }));
+    }
+}
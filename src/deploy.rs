@@ -0,0 +1,266 @@
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+use crate::download::DownloadProgress;
+use crate::i18n::msgs;
+use crate::manifest::{self, set_mirror};
+use crate::ncm_utils::{get_betterncm_profile_path, get_file_version, get_ncm_install_path, Ncm};
+use crate::network;
+use crate::operations::{self, is_process_running, Operation, Plan, Reporter, Step};
+use crate::policy::{plugin_file_name, Policy};
+
+/// Bumped whenever a field of [`DeployResult`] changes meaning or is removed.
+const RESULT_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployStatus {
+    Deployed,
+    UpToDate,
+    DryRun,
+    Failed,
+    InvalidPolicy,
+    NcmNotFound,
+    LegacyInstall,
+    NoAdaptedVersion,
+    NcmRunning,
+}
+
+impl DeployStatus {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            DeployStatus::Deployed | DeployStatus::UpToDate | DeployStatus::DryRun => 0,
+            DeployStatus::Failed => 1,
+            DeployStatus::InvalidPolicy => 2,
+            DeployStatus::NcmNotFound => 3,
+            DeployStatus::LegacyInstall => 4,
+            DeployStatus::NoAdaptedVersion => 5,
+            DeployStatus::NcmRunning => 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeployResult {
+    pub schema_version: u32,
+    pub status: DeployStatus,
+    pub exit_code: i32,
+    pub error: Option<String>,
+    pub ncm_version: Option<String>,
+    pub betterncm_version: Option<String>,
+    pub reboot_required: bool,
+    pub steps: Vec<Step>,
+}
+
+impl DeployResult {
+    fn new() -> DeployResult {
+        DeployResult {
+            schema_version: RESULT_SCHEMA_VERSION,
+            status: DeployStatus::Failed,
+            exit_code: DeployStatus::Failed.exit_code(),
+            error: None,
+            ncm_version: None,
+            betterncm_version: None,
+            reboot_required: false,
+            steps: vec![],
+        }
+    }
+
+    pub fn failed(status: DeployStatus, error: Error) -> DeployResult {
+        let mut result = DeployResult::new();
+        result.finish(Err(Failure(status, error)));
+        result
+    }
+
+    fn finish(&mut self, outcome: Result<DeployStatus, Failure>) {
+        let status = match outcome {
+            Ok(status) => status,
+            Err(Failure(status, error)) => {
+                tracing::error!(status = ?status, error = ?error, "deployment failed");
+                self.error = Some(format!("{error:#}"));
+                status
+            }
+        };
+        self.status = status;
+        self.exit_code = match status {
+            // Same code as the VC redist uses, so deployment scripts can schedule a restart
+            DeployStatus::Deployed if self.reboot_required => 3010,
+            status => status.exit_code(),
+        };
+    }
+}
+
+struct Failure(DeployStatus, Error);
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Failure {
+        Failure(DeployStatus::Failed, error)
+    }
+}
+
+/// Nobody watches an unattended run, so tips only go to the log.
+struct LogReporter;
+
+impl Reporter for LogReporter {
+    fn tip(&self, tip: String) {
+        if !tip.is_empty() {
+            tracing::info!(tip = %tip, "deploy progress");
+        }
+    }
+
+    fn progress(&self, _progress: f64) {}
+
+    fn download(&self, progress: &DownloadProgress) {
+        if progress.finished {
+            tracing::info!(progress = %progress, "deploy progress");
+        }
+    }
+}
+
+/// Brings this machine in line with the policy without asking anything.
+pub fn run(policy: &Policy, dry_run: bool) -> DeployResult {
+    tracing::info!(policy = ?policy, dry_run, "starting deployment");
+    let mut result = DeployResult::new();
+    let outcome = deploy(policy, dry_run, &mut result);
+    result.finish(outcome);
+    tracing::info!(result = ?result, "deployment finished");
+    result
+}
+
+fn deploy(
+    policy: &Policy,
+    dry_run: bool,
+    result: &mut DeployResult,
+) -> Result<DeployStatus, Failure> {
+    if policy.manifest_url.is_some() {
+        set_mirror(policy.manifest_url.clone());
+    }
+    if let Some(network) = &policy.network {
+        network::configure(network.clone());
+    }
+
+    let ncm = get_ncm_install_path()
+        .and_then(Ncm::get_ncm_by_path)
+        .map_err(|err| Failure(DeployStatus::NcmNotFound, err))?;
+    result.ncm_version = Some(ncm.version.to_string());
+    if ncm.path.join("cloudmusicn.exe").exists() {
+        return Err(Failure(
+            DeployStatus::LegacyInstall,
+            anyhow!("An old BetterNCM installation was found, run migrate-legacy first"),
+        ));
+    }
+
+    let (manifest, _) = manifest::fetch()?;
+    if !manifest.is_supported_by(&manifest::installer_version()) {
+        return Err(anyhow!("{}", msgs().manifest_unsupported).into());
+    }
+    let (_, entry) = manifest
+        .resolve(policy.channel(), &ncm, policy.version.as_ref())?
+        .ok_or_else(|| {
+            Failure(
+                DeployStatus::NoAdaptedVersion,
+                match &policy.version {
+                    Some(version) if manifest.yanked(version).is_some() => {
+                        anyhow!("BetterNCM {version} has been yanked, pin another version")
+                    }
+                    Some(version) => {
+                        anyhow!("BetterNCM {version} is not adapted to NCM {}", ncm.version)
+                    }
+                    None => anyhow!("No BetterNCM version is adapted to NCM {}", ncm.version),
+                },
+            )
+        })?;
+    result.betterncm_version = Some(entry.version.to_string());
+
+    let mut steps = vec![];
+    let current_profile = get_betterncm_profile_path();
+    if let Some(profile) = &policy.profile {
+        if profile != &current_profile {
+            steps.extend(operations::plan_set_profile(profile).steps);
+        }
+    }
+    let profile = policy.profile.clone().unwrap_or(current_profile);
+    for url in &policy.plugins {
+        let dest = profile.join("plugins").join(plugin_file_name(url));
+        if !dest.exists() {
+            steps.push(Step::Download {
+                url: url.clone(),
+                mirrors: vec![],
+                dest,
+            });
+        }
+    }
+
+    let dll = ncm.path.join("msimg32.dll");
+    if get_file_version(&dll).ok().as_ref() != Some(&entry.version) {
+        let operation = if dll.exists() {
+            Operation::Update
+        } else {
+            Operation::Install
+        };
+        let channel = policy.channel();
+        steps.extend(operations::plan_install(operation, &ncm, channel, entry, &profile).steps);
+    }
+    if !policy.relaunch_ncm {
+        steps.retain(|step| !matches!(step, Step::LaunchNcm { .. }));
+    }
+    result.steps = steps.clone();
+
+    if !policy.allow_close_ncm
+        && steps
+            .iter()
+            .any(|step| matches!(step, Step::KillProcess { .. }))
+        && is_process_running("cloudmusic.exe")
+    {
+        return Err(Failure(
+            DeployStatus::NcmRunning,
+            anyhow!("NetEase Cloud Music is running and the policy does not allow closing it"),
+        ));
+    }
+    if steps.is_empty() {
+        return Ok(DeployStatus::UpToDate);
+    }
+    if dry_run {
+        return Ok(DeployStatus::DryRun);
+    }
+
+    let plan = Plan {
+        operation: Operation::Deploy,
+        steps,
+    };
+    let summary = operations::execute(&plan, &LogReporter)?;
+    result.reboot_required = summary.reboot_required;
+    Ok(DeployStatus::Deployed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_follow_the_status() {
+        let mut result = DeployResult::new();
+        result.finish(Ok(DeployStatus::UpToDate));
+        assert_eq!((result.exit_code, result.error.as_deref()), (0, None));
+
+        let result = DeployResult::failed(DeployStatus::NcmNotFound, anyhow!("no NCM"));
+        assert_eq!(result.status, DeployStatus::NcmNotFound);
+        assert_eq!(result.exit_code, 3);
+        assert_eq!(result.error.as_deref(), Some("no NCM"));
+    }
+
+    #[test]
+    fn reboots_are_reported_like_the_vc_redist() {
+        let mut result = DeployResult::new();
+        result.reboot_required = true;
+        result.finish(Ok(DeployStatus::Deployed));
+        assert_eq!(result.exit_code, 3010);
+    }
+
+    #[test]
+    fn result_json_is_versioned() {
+        let json = serde_json::to_value(DeployResult::new()).unwrap();
+        assert_eq!(json["schema_version"], RESULT_SCHEMA_VERSION);
+        assert_eq!(json["status"], "failed");
+    }
+}
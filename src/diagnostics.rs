@@ -0,0 +1,173 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use crate::logging;
+use crate::manifest::Manifest;
+use crate::ncm_utils::Ncm;
+use crate::settings::installer_data_dir;
+use crate::status;
+
+pub fn last_manifest_path() -> PathBuf {
+    installer_data_dir().join("last_manifest.json")
+}
+
+pub fn default_bundle_name() -> String {
+    format!(
+        "betterncm-diagnostics-{}.zip",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    )
+}
+
+/// Length of the prefix of `text` that equals `needle` ignoring case, if there is one.
+fn match_ignore_case(text: &str, needle: &str) -> Option<usize> {
+    let mut chars = text.char_indices();
+    for expected in needle.chars() {
+        let (_, actual) = chars.next()?;
+        if !actual.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(chars.next().map_or(text.len(), |(index, _)| index))
+}
+
+/// Replaces `needle` ignoring case; `whole_word` skips matches inside a longer name.
+fn replace_ignore_case(text: &str, needle: &str, replacement: &str, whole_word: bool) -> String {
+    if needle.is_empty() {
+        return text.to_string();
+    }
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(next) = rest.chars().next() {
+        let matched = match_ignore_case(rest, needle).filter(|&len| {
+            !whole_word
+                || (!result.chars().next_back().is_some_and(is_word)
+                    && !rest[len..].chars().next().is_some_and(is_word))
+        });
+        match matched {
+            Some(len) => {
+                result.push_str(replacement);
+                rest = &rest[len..];
+            }
+            None => {
+                result.push(next);
+                rest = &rest[next.len_utf8()..];
+            }
+        }
+    }
+    result
+}
+
+fn redact_with(text: &str, home: Option<&str>, user: Option<&str>) -> String {
+    let mut text = text.to_string();
+    // Paths also show up JSON-escaped and with forward slashes, in any case
+    let forms = |path: &str| {
+        [
+            path.replace('\\', "\\\\"),
+            path.replace('\\', "/"),
+            path.to_string(),
+        ]
+    };
+    if let Some(home) = home.filter(|home| !home.is_empty()) {
+        for form in forms(home) {
+            text = replace_ignore_case(&text, &form, "%USERPROFILE%", false);
+        }
+    }
+    if let Some(user) = user.filter(|user| !user.is_empty()) {
+        for form in forms(&format!("\\Users\\{user}")) {
+            let redacted = form.replace(user, "<user>");
+            text = replace_ignore_case(&text, &form, &redacted, true);
+        }
+        // Short names anywhere else would mangle unrelated text
+        if user.chars().count() >= 3 {
+            text = replace_ignore_case(&text, user, "<user>", false);
+        }
+    }
+    text
+}
+
+/// Removes the user's home path and username from text that is about to leave the machine.
+pub fn redact(text: &str) -> String {
+    let home = dirs::home_dir().map(|home| home.display().to_string());
+    let user = std::env::var("USERNAME").ok();
+    redact_with(text, home.as_deref(), user.as_deref())
+}
+
+pub fn export_bundle(dest: &Path, selected_ncm: Option<Ncm>) -> Result<()> {
+    tracing::info!(dest = %dest.display(), "exporting diagnostics bundle");
+    let last_manifest = fs::read_to_string(last_manifest_path()).ok();
+    let manifest = last_manifest
+        .as_deref()
+        .and_then(|content| Manifest::parse(content).ok());
+    let report = status::collect(selected_ncm, manifest.as_ref());
+    tracing::info!(report = ?report, "collected diagnostics");
+
+    let mut zip = ZipWriter::new(File::create(dest)?);
+    let options = FileOptions::default();
+
+    zip.start_file("report.json", options)?;
+    zip.write_all(redact(&serde_json::to_string_pretty(&report)?).as_bytes())?;
+
+    if let Some(manifest) = last_manifest {
+        zip.start_file("manifest.json", options)?;
+        zip.write_all(manifest.as_bytes())?;
+    }
+
+    for log in logging::log_files() {
+        if let (Some(name), Ok(content)) = (log.file_name(), fs::read(&log)) {
+            zip.start_file(format!("logs/{}", name.to_string_lossy()), options)?;
+            zip.write_all(redact(&String::from_utf8_lossy(&content)).as_bytes())?;
+        }
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOME: &str = r"C:\Users\Alice";
+
+    #[test]
+    fn redacts_home_in_any_case_and_form() {
+        let text = r#"c:\users\alice\AppData "C:\\USERS\\ALICE\\x" C:/Users/alice/y"#;
+        assert_eq!(
+            redact_with(text, Some(HOME), Some("Alice")),
+            r#"%USERPROFILE%\AppData "%USERPROFILE%\\x" %USERPROFILE%/y"#
+        );
+    }
+
+    #[test]
+    fn redacts_long_usernames_anywhere() {
+        assert_eq!(
+            redact_with("user ALICE logged in", Some(HOME), Some("Alice")),
+            "user <user> logged in"
+        );
+    }
+
+    #[test]
+    fn redacts_short_usernames_only_in_paths() {
+        let text = r"D:\Users\Al\Music and d:/users/al/x, also \Users\Alan and Al";
+        assert_eq!(
+            redact_with(text, Some(r"C:\Users\Al"), Some("Al")),
+            r"D:\Users\<user>\Music and d:/Users/<user>/x, also \Users\Alan and Al"
+        );
+    }
+
+    #[test]
+    fn keeps_text_without_user_data() {
+        let text = "nothing to see, 网易云音乐";
+        assert_eq!(redact_with(text, None, None), text);
+        assert_eq!(redact_with(text, Some(""), Some("")), text);
+    }
+}
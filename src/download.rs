@@ -0,0 +1,423 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::bundle::{self, OfflineBundle};
+use crate::i18n::{fill, msgs};
+use crate::network;
+use crate::operations::Reporter;
+
+const REPORT_INTERVAL: Duration = Duration::from_millis(200);
+/// How much of each mirror is fetched to compare their speed.
+const PROBE_BYTES: u64 = 256 * 1024;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// A source delivering less than `STALL_MIN_BYTES` in this time is dropped for the next one.
+const STALL_WINDOW: Duration = Duration::from_secs(10);
+const STALL_MIN_BYTES: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadProgress {
+    pub name: String,
+    pub downloaded: u64,
+    /// `None` when the server sent no usable content length.
+    pub total: Option<u64>,
+    /// Bytes per second, smoothed over recent reports.
+    pub throughput: f64,
+    pub finished: bool,
+}
+
+impl DownloadProgress {
+    /// Between 0 and 1, or `None` when the total size is unknown.
+    pub fn fraction(&self) -> Option<f64> {
+        self.total
+            .map(|total| (self.downloaded as f64 / total as f64).min(1.))
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.saturating_sub(self.downloaded);
+        if self.throughput <= 0. {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / self.throughput))
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
+impl fmt::Display for DownloadProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let speed = format!("{}/s", format_bytes(self.throughput as u64));
+        let text = match (self.total, self.eta()) {
+            (Some(total), eta) => fill(
+                msgs().downloading_progress,
+                &[
+                    ("path", &self.name),
+                    ("done", &format_bytes(self.downloaded)),
+                    ("total", &format_bytes(total)),
+                    ("speed", &speed),
+                    ("eta", &eta.map_or_else(|| "-".to_string(), format_duration)),
+                ],
+            ),
+            (None, _) => fill(
+                msgs().downloading_progress_unknown,
+                &[
+                    ("path", &self.name),
+                    ("done", &format_bytes(self.downloaded)),
+                    ("speed", &speed),
+                ],
+            ),
+        };
+        f.write_str(&text)
+    }
+}
+
+/// Turns a stream of received bytes into throttled progress reports.
+struct ProgressTracker {
+    progress: DownloadProgress,
+    last_report: Instant,
+    last_downloaded: u64,
+}
+
+impl ProgressTracker {
+    fn new(name: String, total: Option<u64>) -> ProgressTracker {
+        ProgressTracker {
+            progress: DownloadProgress {
+                name,
+                downloaded: 0,
+                total,
+                throughput: 0.,
+                finished: false,
+            },
+            last_report: Instant::now(),
+            last_downloaded: 0,
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) -> Option<&DownloadProgress> {
+        self.progress.downloaded += bytes;
+        let elapsed = self.last_report.elapsed();
+        if elapsed < REPORT_INTERVAL {
+            return None;
+        }
+        let received = self.progress.downloaded - self.last_downloaded;
+        let rate = received as f64 / elapsed.as_secs_f64();
+        self.progress.throughput = if self.progress.throughput == 0. {
+            rate
+        } else {
+            self.progress.throughput * 0.7 + rate * 0.3
+        };
+        self.last_report = Instant::now();
+        self.last_downloaded = self.progress.downloaded;
+        Some(&self.progress)
+    }
+
+    /// Starts over after a source that could not continue a partial download.
+    fn restart(&mut self) {
+        self.progress.downloaded = 0;
+        self.last_downloaded = 0;
+        self.last_report = Instant::now();
+    }
+
+    fn finish(&mut self) -> &DownloadProgress {
+        self.progress.finished = true;
+        &self.progress
+    }
+}
+
+/// Host of `url`, to tell sources apart in messages.
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split(['/', '?', '#']).next().unwrap_or(rest)
+}
+
+#[derive(Debug, Clone)]
+struct Probe {
+    url: String,
+    latency: Duration,
+    /// Bytes per second over the probed range.
+    throughput: f64,
+}
+
+/// Fetches the start of `url` to see how quickly it answers and delivers.
+fn probe(url: &str) -> Result<Probe> {
+    let started = Instant::now();
+    let request = network::agent(url)?
+        .get(url)
+        .timeout(PROBE_TIMEOUT)
+        .set("Range", &format!("bytes=0-{}", PROBE_BYTES - 1));
+    let response = network::call(request)?;
+    let latency = started.elapsed();
+    // Servers that ignore the range send everything, so stop reading after the probed size
+    let received = io::copy(
+        &mut response.into_reader().take(PROBE_BYTES),
+        &mut io::sink(),
+    )?;
+    let transfer = started.elapsed().saturating_sub(latency);
+    Ok(Probe {
+        url: url.to_string(),
+        latency,
+        throughput: received as f64 / transfer.as_secs_f64().max(0.001),
+    })
+}
+
+/// Orders `urls` fastest first; sources that failed the probe are kept last as a fallback.
+fn rank_sources(urls: &[String]) -> Vec<String> {
+    let results: Vec<(&String, Result<Probe>)> = thread::scope(|scope| {
+        let probes: Vec<_> = urls
+            .iter()
+            .map(|url| (url, scope.spawn(move || probe(url))))
+            .collect();
+        probes
+            .into_iter()
+            .map(|(url, probe)| {
+                let result = probe
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("Probe of {url} panicked")));
+                (url, result)
+            })
+            .collect()
+    });
+
+    let mut probes = vec![];
+    let mut failed = vec![];
+    for (url, result) in results {
+        match result {
+            Ok(probe) => probes.push(probe),
+            Err(err) => {
+                tracing::warn!(url = %url, error = ?err, "download source probe failed");
+                failed.push(url.clone());
+            }
+        }
+    }
+    probes.sort_by(|a, b| {
+        b.throughput
+            .total_cmp(&a.throughput)
+            .then(a.latency.cmp(&b.latency))
+    });
+    tracing::info!(probes = ?probes, failed = ?failed, "ranked download sources");
+    probes
+        .into_iter()
+        .map(|probe| probe.url)
+        .chain(failed)
+        .collect()
+}
+
+/// Total size from a `Content-Range: bytes 0-99/1234` header.
+fn content_range_total(response: &ureq::Response) -> Option<u64> {
+    response
+        .header("content-range")?
+        .rsplit('/')
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Downloads from one source into `file`, resuming after what `tracker` has already received.
+fn download_from(
+    url: &str,
+    file: &mut BufWriter<File>,
+    tracker: &mut ProgressTracker,
+    reporter: &dyn Reporter,
+    has_fallback: bool,
+) -> Result<()> {
+    let mut builder = network::agent_builder(url)?;
+    if has_fallback {
+        // With another source to turn to, waiting out a long read timeout is pointless
+        builder = builder.timeout_read(STALL_WINDOW);
+    }
+    let offset = tracker.progress.downloaded;
+    let mut request = builder.build().get(url);
+    if offset > 0 {
+        request = request.set("Range", &format!("bytes={offset}-"));
+    }
+    let res = network::call(request)?;
+
+    let total = if res.status() == 206 {
+        content_range_total(&res)
+    } else {
+        if offset > 0 {
+            tracing::info!(url, offset, "source cannot resume, restarting the download");
+            file.seek(SeekFrom::Start(0))?;
+            file.get_ref().set_len(0)?;
+            tracker.restart();
+        }
+        // Chunked responses have no length, and a zero length says nothing about the real size
+        res.header("content-length")
+            .and_then(|length| length.parse::<u64>().ok())
+            .filter(|length| *length > 0)
+    };
+    tracing::info!(url, status = res.status(), offset, total = ?total, "download started");
+    match (tracker.progress.total, total) {
+        (Some(expected), Some(total)) if expected != total => {
+            bail!("{url} serves {total} bytes where {expected} were expected")
+        }
+        (None, Some(_)) => tracker.progress.total = total,
+        _ => {}
+    }
+    reporter.download(&tracker.progress);
+
+    let mut reader = res.into_reader();
+    let mut buf = vec![0; 64 * 1024];
+    let mut window_start = Instant::now();
+    let mut window_bytes = 0;
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buf[..read])?;
+        if let Some(progress) = tracker.advance(read as u64) {
+            reporter.download(progress);
+        }
+        window_bytes += read as u64;
+        if window_start.elapsed() >= STALL_WINDOW {
+            if has_fallback && window_bytes < STALL_MIN_BYTES {
+                bail!("{url} stalled at {window_bytes} bytes in {STALL_WINDOW:?}");
+            }
+            window_start = Instant::now();
+            window_bytes = 0;
+        }
+    }
+
+    if let Some(total) = tracker.progress.total {
+        if tracker.progress.downloaded != total {
+            bail!(
+                "Download of {url} was cut short: got {} of {total} bytes",
+                tracker.progress.downloaded
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Copies the artifact an offline bundle has for `urls` to `path`.
+pub fn copy_from_bundle(bundle: &OfflineBundle, urls: &[String], path: &Path) -> Result<()> {
+    let Some(primary) = urls.first() else {
+        bail!("No download source for {}", path.display());
+    };
+    // Bundles keep an artifact under whichever of its URLs they were built from
+    let source = match urls.iter().find_map(|url| bundle.artifact(url).ok()) {
+        Some(source) => source,
+        None => bundle.artifact(primary)?,
+    };
+    let size = fs::copy(&source, path).with_context(|| {
+        format!(
+            "Failed to copy {} from the offline bundle",
+            source.display()
+        )
+    })?;
+    tracing::info!(
+        url = %primary,
+        source = %source.display(),
+        path = %path.display(),
+        size,
+        "copied file from offline bundle"
+    );
+    Ok(())
+}
+
+pub fn download_file(url: &str, path: &Path, reporter: &dyn Reporter) -> Result<()> {
+    download_from_sources(&[url.to_string()], path, reporter)
+}
+
+/// Downloads one file that every URL in `urls` serves, from the fastest of them.
+///
+/// A source that fails or stalls is left for the next one, which continues where it stopped
+/// when it supports ranges.
+pub fn download_from_sources(urls: &[String], path: &Path, reporter: &dyn Reporter) -> Result<()> {
+    if urls.is_empty() {
+        bail!("No download source for {}", path.display());
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    reporter.tip(fill(msgs().downloading_file, &[("path", &name)]));
+
+    if let Some(bundle) = bundle::active() {
+        copy_from_bundle(bundle, urls, path)?;
+        reporter.tip(String::new());
+        return Ok(());
+    }
+
+    let sources = if urls.len() > 1 {
+        reporter.tip(fill(msgs().probing_sources, &[("count", &urls.len())]));
+        rank_sources(urls)
+    } else {
+        urls.to_vec()
+    };
+    tracing::info!(sources = ?sources, path = %path.display(), "downloading file");
+
+    let mut file = BufWriter::new(
+        File::create(path)
+            .with_context(|| format!("Failed to create file '{}'", path.display()))?,
+    );
+    let mut tracker = ProgressTracker::new(name, None);
+    let mut last_error = None;
+    for (index, url) in sources.iter().enumerate() {
+        let next = sources.get(index + 1);
+        match download_from(url, &mut file, &mut tracker, reporter, next.is_some()) {
+            Ok(()) => {
+                last_error = None;
+                break;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    url = %url,
+                    downloaded = tracker.progress.downloaded,
+                    error = ?err,
+                    "download source failed"
+                );
+                if let Some(next) = next {
+                    reporter.tip(fill(
+                        msgs().download_switching_source,
+                        &[("from", &host(url)), ("to", &host(next))],
+                    ));
+                }
+                last_error = Some(err.context(format!("Failed to download {url}")));
+            }
+        }
+    }
+    if let Some(err) = last_error {
+        return Err(err);
+    }
+    file.flush()?;
+
+    let progress = tracker.finish();
+    reporter.download(progress);
+    tracing::info!(
+        path = %path.display(),
+        size = progress.downloaded,
+        "downloaded file"
+    );
+    reporter.tip(String::new());
+    Ok(())
+}
@@ -0,0 +1,599 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Lang {
+    #[serde(rename = "zh-CN")]
+    ZhCn,
+    #[serde(rename = "en-US")]
+    EnUs,
+}
+
+pub const ALL_LANGS: [Lang; 2] = [Lang::ZhCn, Lang::EnUs];
+
+impl Lang {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Lang::ZhCn => "zh-CN",
+            Lang::EnUs => "en-US",
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Lang> {
+        let tag = tag.to_ascii_lowercase().replace('_', "-");
+        if tag.starts_with("zh") {
+            Some(Lang::ZhCn)
+        } else if tag.starts_with("en") {
+            Some(Lang::EnUs)
+        } else {
+            None
+        }
+    }
+
+    pub fn system() -> Lang {
+        sys_locale::get_locale()
+            .and_then(|locale| Lang::from_tag(&locale))
+            .unwrap_or(Lang::EnUs)
+    }
+
+    pub fn next(self) -> Lang {
+        let index = ALL_LANGS.iter().position(|lang| *lang == self).unwrap_or(0);
+        ALL_LANGS[(index + 1) % ALL_LANGS.len()]
+    }
+
+    pub fn msgs(self) -> &'static Messages {
+        match self {
+            Lang::ZhCn => &ZH_CN,
+            Lang::EnUs => &EN_US,
+        }
+    }
+}
+
+static CURRENT_LANG: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_lang(lang: Lang) {
+    let index = ALL_LANGS.iter().position(|l| *l == lang).unwrap_or(0);
+    CURRENT_LANG.store(index as u8, Ordering::Relaxed);
+}
+
+pub fn lang() -> Lang {
+    ALL_LANGS[CURRENT_LANG.load(Ordering::Relaxed) as usize]
+}
+
+pub fn msgs() -> &'static Messages {
+    lang().msgs()
+}
+
+/// Creates a label text that follows the current language, for use in druid `Label`s and `Button`s.
+///
+/// The text is resolved again whenever the app data changes, so `AppData::lang` must be
+/// updated together with [`set_lang`] for a language switch to show up.
+pub fn text<T>(key: fn(&'static Messages) -> &'static str) -> impl Fn(&T, &druid::Env) -> String {
+    move |_data: &T, _env: &druid::Env| key(msgs()).to_string()
+}
+
+/// Replaces `{name}` placeholders in a catalog message.
+pub fn fill(message: &str, args: &[(&str, &dyn std::fmt::Display)]) -> String {
+    let mut message = message.to_string();
+    for (name, value) in args {
+        message = message.replace(&format!("{{{name}}}"), &value.to_string());
+    }
+    message
+}
+
+/// Formats a duration in seconds as a rough age such as "3 hours".
+pub fn format_age(secs: u64) -> String {
+    let m = msgs();
+    let (count, unit) = match secs {
+        0..=3599 => ((secs / 60).max(1), m.age_minutes),
+        3600..=86399 => (secs / 3600, m.age_hours),
+        _ => (secs / 86400, m.age_days),
+    };
+    fill(unit, &[("count", &count)])
+}
+
+// Every catalog is a `Messages` value, so a key missing from any language is a compile error.
+#[cfg_attr(test, derive(Serialize))]
+pub struct Messages {
+    pub lang_name: &'static str,
+    pub installer_version: &'static str,
+    pub adapted_version: &'static str,
+    pub not_adapted: &'static str,
+    pub fetching: &'static str,
+    pub old_version_detected: &'static str,
+    pub ncm_version: &'static str,
+    pub ncm_not_installed: &'static str,
+    pub ncm_too_old: &'static str,
+    pub test_channel: &'static str,
+    pub install: &'static str,
+    pub installing: &'static str,
+    pub install_success: &'static str,
+    pub reinstall: &'static str,
+    pub reinstalling: &'static str,
+    pub reinstall_success: &'static str,
+    pub uninstall: &'static str,
+    pub uninstalling: &'static str,
+    pub uninstall_success: &'static str,
+    pub uninstall_old: &'static str,
+    pub set_data_path: &'static str,
+    pub reset_data_path: &'static str,
+    pub select_ncm: &'static str,
+    pub ncm_executable_filter: &'static str,
+    pub downloading_file: &'static str,
+    pub downloading: &'static str,
+    pub downloading_progress: &'static str,
+    pub downloading_progress_unknown: &'static str,
+    pub installing_vc: &'static str,
+    pub operation_failed: &'static str,
+    pub open_log: &'static str,
+    pub export_logs: &'static str,
+    pub logs_exported: &'static str,
+    pub export_diagnostics: &'static str,
+    pub diagnostics_exported: &'static str,
+    pub installer_outdated: &'static str,
+    pub update_installer: &'static str,
+    pub manifest_unsupported: &'static str,
+    pub migrating_legacy: &'static str,
+    pub legacy_migrated: &'static str,
+    pub updating_profile: &'static str,
+    pub profile_updated: &'static str,
+    pub plan_title: &'static str,
+    pub confirm: &'static str,
+    pub cancel: &'static str,
+    pub step_kill_process: &'static str,
+    pub step_download: &'static str,
+    pub step_install_vc_runtime: &'static str,
+    pub step_copy_file: &'static str,
+    pub step_rename_file: &'static str,
+    pub step_delete_file: &'static str,
+    pub step_delete_dir: &'static str,
+    pub step_set_registry: &'static str,
+    pub step_delete_registry: &'static str,
+    pub step_launch_ncm: &'static str,
+    pub dry_run_notice: &'static str,
+    pub reboot_required: &'static str,
+    pub vc_install_busy: &'static str,
+    pub vc_install_failed: &'static str,
+    pub offline_mode: &'static str,
+    pub deploy: &'static str,
+    pub deploying: &'static str,
+    pub deploy_success: &'static str,
+    pub settings_locked: &'static str,
+    pub step_record_install: &'static str,
+    pub dll_missing: &'static str,
+    pub ncm_updated: &'static str,
+    pub reapply_prompt: &'static str,
+    pub reapply_not_adapted: &'static str,
+    pub install_unchanged: &'static str,
+    pub repair: &'static str,
+    pub repairing: &'static str,
+    pub repair_success: &'static str,
+    pub verifying: &'static str,
+    pub verify_ok: &'static str,
+    pub issues_found: &'static str,
+    pub issue_dll_missing: &'static str,
+    pub issue_dll_modified: &'static str,
+    pub issue_dll_unknown: &'static str,
+    pub issue_legacy_executable: &'static str,
+    pub issue_legacy_config: &'static str,
+    pub issue_profile_missing: &'static str,
+    pub step_verify_file: &'static str,
+    pub step_create_dir: &'static str,
+    pub restore_legacy: &'static str,
+    pub restoring_legacy: &'static str,
+    pub legacy_restored: &'static str,
+    pub step_copy_dir: &'static str,
+    pub step_reset_proxy: &'static str,
+    pub proxy_removed: &'static str,
+    pub proxy_not_found: &'static str,
+    pub proxy_left_unchanged: &'static str,
+    pub changes_made: &'static str,
+    pub no_legacy_backup: &'static str,
+    pub conflicts_found: &'static str,
+    pub conflict_proxy_dll: &'static str,
+    pub conflict_betterncm_variant: &'static str,
+    pub quarantine: &'static str,
+    pub quarantining: &'static str,
+    pub quarantine_success: &'static str,
+    pub fetch_failed: &'static str,
+    pub manifest_stale: &'static str,
+    pub age_minutes: &'static str,
+    pub age_hours: &'static str,
+    pub age_days: &'static str,
+    pub issue_dll_yanked: &'static str,
+    pub update_to_recommended: &'static str,
+    pub unadapted_reason: &'static str,
+    pub unadapted_below: &'static str,
+    pub unadapted_above: &'static str,
+    pub unadapted_any_below: &'static str,
+    pub unadapted_in_test: &'static str,
+    pub unadapted_in_stable: &'static str,
+    pub release_notes: &'static str,
+    pub page_install: &'static str,
+    pub page_plugins: &'static str,
+    pub page_profile: &'static str,
+    pub page_diagnostics: &'static str,
+    pub page_settings: &'static str,
+    pub installed_version: &'static str,
+    pub no_plugins: &'static str,
+    pub open_plugins_dir: &'static str,
+    pub refresh: &'static str,
+    pub data_path: &'static str,
+    pub language: &'static str,
+    pub theme: &'static str,
+    pub theme_light: &'static str,
+    pub theme_dark: &'static str,
+    pub theme_system: &'static str,
+    pub operation_locked: &'static str,
+    pub operation_busy: &'static str,
+    pub already_running: &'static str,
+    pub waiting_for_elevation: &'static str,
+    pub elevation_cancelled: &'static str,
+    pub network_proxy: &'static str,
+    pub network_direct: &'static str,
+    pub network_fetched: &'static str,
+    pub step_download_mirrors: &'static str,
+    pub probing_sources: &'static str,
+    pub download_switching_source: &'static str,
+    pub step_extract_archive: &'static str,
+}
+
+pub static ZH_CN: Messages = Messages {
+    lang_name: "中文",
+    installer_version: "BetterNCM Installer 版本: ",
+    adapted_version: "适配 BetterNCM 版本: ",
+    not_adapted: "未适配",
+    fetching: "获取中...",
+    old_version_detected: "检测到老版本BetterNCM 请先卸载",
+    ncm_version: "网易云版本: ",
+    ncm_not_installed: "未安装",
+    ncm_too_old: "您的网易云版本太低，请更新",
+    test_channel: "测试通道",
+    install: "安装",
+    installing: "正在安装 BetterNCM…",
+    install_success: "安装成功！",
+    reinstall: "重装/更新",
+    reinstalling: "正在升级/重新安装 BetterNCM…",
+    reinstall_success: "升级/重新安装成功！",
+    uninstall: "卸载",
+    uninstalling: "正在卸载 BetterNCM…",
+    uninstall_success: "卸载完成！",
+    uninstall_old: "卸载老版本",
+    set_data_path: "修改数据地址",
+    reset_data_path: "重置数据地址",
+    select_ncm: "手动指定网易云",
+    ncm_executable_filter: "网易云可执行文件",
+    downloading_file: "正在下载: {path}",
+    downloading: "正在下载…",
+    downloading_progress: "正在下载：{path}（{done} / {total}，{speed}，剩余 {eta}）",
+    downloading_progress_unknown: "正在下载：{path}（已下载 {done}，{speed}）",
+    installing_vc: "正在安装 VC 运行时…",
+    operation_failed: "操作失败：{error}",
+    open_log: "打开日志",
+    export_logs: "导出日志",
+    logs_exported: "已导出 {count} 个日志文件",
+    export_diagnostics: "导出诊断包",
+    diagnostics_exported: "诊断包已导出",
+    installer_outdated: "安装器有新版本 {version}",
+    update_installer: "更新安装器",
+    manifest_unsupported: "版本清单需要更新版本的安装器，请先更新安装器",
+    migrating_legacy: "正在卸载老版本 BetterNCM…",
+    legacy_migrated: "老版本已卸载",
+    updating_profile: "正在修改数据地址…",
+    profile_updated: "数据地址已修改，重启网易云后生效",
+    plan_title: "{operation}：将执行以下操作",
+    confirm: "确认",
+    cancel: "取消",
+    step_kill_process: "结束进程 {name}",
+    step_download: "下载 {url} 到 {path}",
+    step_install_vc_runtime: "安装 VC 运行时（{arch}）：{path}",
+    step_copy_file: "复制 {from} 到 {to}",
+    step_rename_file: "将 {from} 重命名为 {to}",
+    step_delete_file: "删除文件 {path}",
+    step_delete_dir: "删除目录 {path}",
+    step_set_registry: "设置注册表 {key}\\{name} = {value}",
+    step_delete_registry: "删除注册表值 {key}\\{name}",
+    step_launch_ncm: "启动网易云 {path}",
+    dry_run_notice: "（预演模式，未做任何修改）",
+    reboot_required: "VC 运行时需要重启电脑后才能完全生效。",
+    vc_install_busy: "另一个安装程序正在运行，请稍后重试",
+    vc_install_failed: "VC 运行时安装失败（退出码 {code}）",
+    offline_mode: "离线模式：{path}",
+    deploy: "部署",
+    deploying: "正在部署",
+    deploy_success: "部署成功",
+    settings_locked: "部分设置已被管理员策略锁定",
+    step_record_install: "记录安装信息（BetterNCM {version}，网易云 {ncm_version}）",
+    dll_missing: "BetterNCM 已被移除（可能是网易云更新导致的）",
+    ncm_updated: "网易云已从 {from} 更新到 {to}",
+    reapply_prompt: "{reason}，确认后将重新应用 BetterNCM",
+    reapply_not_adapted: "{reason}，但暂无适配网易云 {version} 的 BetterNCM",
+    install_unchanged: "BetterNCM 安装状态与上次安装一致",
+    repair: "校验/修复",
+    repairing: "正在修复",
+    repair_success: "修复完成",
+    verifying: "正在校验安装",
+    verify_ok: "未发现问题",
+    issues_found: "发现以下问题：",
+    issue_dll_missing: "BetterNCM {version} 的 msimg32.dll 丢失",
+    issue_dll_modified: "msimg32.dll 与 BetterNCM {version} 的发布文件不一致",
+    issue_dll_unknown: "无法校验 msimg32.dll：清单中没有 BetterNCM {version}",
+    issue_legacy_executable: "存在老版本残留的 {path}",
+    issue_legacy_config: "存在老版本残留的配置目录 {path}",
+    issue_profile_missing: "数据目录 {path} 不存在",
+    step_verify_file: "校验 {path} 的 SHA-256",
+    step_create_dir: "创建目录 {path}",
+    restore_legacy: "恢复老版本",
+    restoring_legacy: "正在恢复老版本",
+    legacy_restored: "已恢复老版本",
+    step_copy_dir: "复制目录 {from} 到 {to}",
+    step_reset_proxy: "重置 {path} 中的代理设置",
+    proxy_removed: "已从 {path} 移除代理设置：{keys}",
+    proxy_not_found: "{path} 中没有代理设置，未做修改",
+    proxy_left_unchanged: "无法解析 {path}，其中的代理设置未做修改",
+    changes_made: "已进行的修改：",
+    no_legacy_backup: "没有找到老版本的备份",
+    conflicts_found: "检测到 {count} 个可能与 BetterNCM 冲突的文件：{files}",
+    conflict_proxy_dll: "{name}（注入用的代理 DLL）",
+    conflict_betterncm_variant: "{name}（其他版本的 BetterNCM）",
+    quarantine: "隔离冲突文件",
+    quarantining: "正在隔离冲突文件",
+    quarantine_success: "冲突文件已移至备份目录",
+    fetch_failed: "获取失败",
+    manifest_stale: "无法连接到服务器，正在使用 {age}前缓存的版本清单",
+    age_minutes: "{count} 分钟",
+    age_hours: "{count} 小时",
+    age_days: "{count} 天",
+    issue_dll_yanked: "已安装的 BetterNCM {version} 已被撤回，建议更新到推荐版本",
+    update_to_recommended: "更新到推荐版本",
+    unadapted_reason: "当前通道没有适配网易云 {version} 的版本，已适配的网易云版本范围：{ranges}",
+    unadapted_below: "已适配的较低网易云版本：{version}",
+    unadapted_above: "已适配的较高网易云版本：{version}",
+    unadapted_any_below: "低于 {version} 的版本",
+    unadapted_in_test: "测试通道中有适配的 BetterNCM {version}，可勾选“测试通道”后安装",
+    unadapted_in_stable: "正式通道中有适配的 BetterNCM {version}，可取消勾选“测试通道”后安装",
+    release_notes: "更新说明",
+    page_install: "安装与版本",
+    page_plugins: "插件",
+    page_profile: "数据目录",
+    page_diagnostics: "诊断",
+    page_settings: "设置",
+    installed_version: "已安装 BetterNCM 版本: ",
+    no_plugins: "数据目录中还没有插件",
+    open_plugins_dir: "打开插件目录",
+    refresh: "刷新",
+    data_path: "当前数据地址: ",
+    language: "语言",
+    theme: "主题",
+    theme_light: "浅色",
+    theme_dark: "深色",
+    theme_system: "跟随系统",
+    operation_locked: "另一个安装器进程（PID {pid}）正在执行 {operation}，请等待其完成后重试",
+    operation_busy: "另一个安装器进程正在修改 BetterNCM，请等待其完成后重试",
+    already_running: "BetterNCM Installer 已在运行",
+    waiting_for_elevation: "部分操作需要管理员权限，请在弹出的窗口中确认",
+    elevation_cancelled: "已取消授予管理员权限，操作未完成",
+    network_proxy: "使用代理：{proxy}",
+    network_direct: "不使用代理，直接连接",
+    network_fetched: "{url}：HTTP {status}，{size}，用时 {time}",
+    step_download_mirrors: "从 {url} 等 {count} 个下载源中最快的一个下载到 {path}",
+    probing_sources: "正在测试 {count} 个下载源的速度",
+    download_switching_source: "{from} 下载中断，改从 {to} 继续下载",
+    step_extract_archive: "从 {path} 解压 {count} 个文件",
+};
+
+pub static EN_US: Messages = Messages {
+    lang_name: "English",
+    installer_version: "BetterNCM Installer version: ",
+    adapted_version: "Adapted BetterNCM version: ",
+    not_adapted: "Not adapted",
+    fetching: "Fetching...",
+    old_version_detected: "Old BetterNCM detected, please uninstall it first",
+    ncm_version: "NetEase Cloud Music version: ",
+    ncm_not_installed: "Not installed",
+    ncm_too_old: "Your NetEase Cloud Music is too old, please update it",
+    test_channel: "Test channel",
+    install: "Install",
+    installing: "Installing BetterNCM…",
+    install_success: "Installed successfully!",
+    reinstall: "Reinstall/Update",
+    reinstalling: "Updating/reinstalling BetterNCM…",
+    reinstall_success: "Updated/reinstalled successfully!",
+    uninstall: "Uninstall",
+    uninstalling: "Uninstalling BetterNCM…",
+    uninstall_success: "Uninstalled!",
+    uninstall_old: "Remove old version",
+    set_data_path: "Change data path",
+    reset_data_path: "Reset data path",
+    select_ncm: "Locate NCM manually",
+    ncm_executable_filter: "NCM Executable",
+    downloading_file: "Downloading: {path}",
+    downloading: "Downloading…",
+    downloading_progress: "Downloading: {path} ({done} / {total}, {speed}, {eta} left)",
+    downloading_progress_unknown: "Downloading: {path} ({done} so far, {speed})",
+    installing_vc: "Installing VC runtime…",
+    operation_failed: "Operation failed: {error}",
+    open_log: "Open log",
+    export_logs: "Export logs",
+    logs_exported: "Exported {count} log file(s)",
+    export_diagnostics: "Export diagnostics",
+    diagnostics_exported: "Diagnostics bundle exported",
+    installer_outdated: "Installer {version} is available",
+    update_installer: "Update installer",
+    manifest_unsupported: "The release manifest needs a newer installer, please update it first",
+    migrating_legacy: "Removing old BetterNCM…",
+    legacy_migrated: "Old version removed",
+    updating_profile: "Updating data path…",
+    profile_updated: "Data path updated, restart NCM to apply",
+    plan_title: "{operation}: the following will be done",
+    confirm: "Confirm",
+    cancel: "Cancel",
+    step_kill_process: "Kill process {name}",
+    step_download: "Download {url} to {path}",
+    step_install_vc_runtime: "Install VC runtime ({arch}): {path}",
+    step_copy_file: "Copy {from} to {to}",
+    step_rename_file: "Rename {from} to {to}",
+    step_delete_file: "Delete file {path}",
+    step_delete_dir: "Delete directory {path}",
+    step_set_registry: "Set registry value {key}\\{name} = {value}",
+    step_delete_registry: "Delete registry value {key}\\{name}",
+    step_launch_ncm: "Launch NCM in {path}",
+    dry_run_notice: "(dry run, nothing was changed)",
+    reboot_required: "The VC runtime needs a restart to take full effect.",
+    vc_install_busy: "Another installation is in progress, please try again later",
+    vc_install_failed: "VC runtime installation failed (exit code {code})",
+    offline_mode: "Offline mode: {path}",
+    deploy: "Deploy",
+    deploying: "Deploying",
+    deploy_success: "Deployed successfully",
+    settings_locked: "Some settings are locked by your administrator's policy",
+    step_record_install: "Record the installation (BetterNCM {version}, NCM {ncm_version})",
+    dll_missing: "BetterNCM was removed, probably by a NetEase Cloud Music update",
+    ncm_updated: "NetEase Cloud Music was updated from {from} to {to}",
+    reapply_prompt: "{reason}. Confirm to re-apply BetterNCM.",
+    reapply_not_adapted: "{reason}, but no BetterNCM version is adapted to NCM {version} yet.",
+    install_unchanged: "BetterNCM is still installed as recorded",
+    repair: "Verify/Repair",
+    repairing: "Repairing",
+    repair_success: "Repaired successfully",
+    verifying: "Verifying the installation",
+    verify_ok: "No problems found",
+    issues_found: "Found these problems:",
+    issue_dll_missing: "msimg32.dll of BetterNCM {version} is missing",
+    issue_dll_modified: "msimg32.dll does not match the BetterNCM {version} release",
+    issue_dll_unknown: "Cannot verify msimg32.dll: BetterNCM {version} is not in the manifest",
+    issue_legacy_executable: "Leftover from an old version: {path}",
+    issue_legacy_config: "Leftover config directory from an old version: {path}",
+    issue_profile_missing: "The data directory {path} does not exist",
+    step_verify_file: "Verify the SHA-256 of {path}",
+    step_create_dir: "Create the directory {path}",
+    restore_legacy: "Restore old version",
+    restoring_legacy: "Restoring the old version",
+    legacy_restored: "The old version was restored",
+    step_copy_dir: "Copy the directory {from} to {to}",
+    step_reset_proxy: "Reset the proxy settings in {path}",
+    proxy_removed: "Removed proxy settings from {path}: {keys}",
+    proxy_not_found: "{path} has no proxy settings and was left unchanged",
+    proxy_left_unchanged: "Could not read {path}, so its proxy settings were left unchanged",
+    changes_made: "Changes made:",
+    no_legacy_backup: "No backup of the old version was found",
+    conflicts_found: "Found {count} file(s) that may conflict with BetterNCM: {files}",
+    conflict_proxy_dll: "{name} (injected proxy DLL)",
+    conflict_betterncm_variant: "{name} (another BetterNCM variant)",
+    quarantine: "Quarantine conflicts",
+    quarantining: "Quarantining conflicting files",
+    quarantine_success: "The conflicting files were moved to a backup folder",
+    fetch_failed: "Failed to fetch",
+    manifest_stale: "Offline: using the release manifest cached {age} ago",
+    age_minutes: "{count} minutes",
+    age_hours: "{count} hours",
+    age_days: "{count} days",
+    issue_dll_yanked: "The installed BetterNCM {version} has been withdrawn, please move to the recommended version",
+    update_to_recommended: "Update to recommended version",
+    unadapted_reason: "This channel has nothing for NCM {version}; it supports NCM {ranges}",
+    unadapted_below: "Closest supported older NCM: {version}",
+    unadapted_above: "Closest supported newer NCM: {version}",
+    unadapted_any_below: "any version below {version}",
+    unadapted_in_test: "The test channel has BetterNCM {version} for this NCM, enable the test channel to install it",
+    unadapted_in_stable: "The stable channel has BetterNCM {version} for this NCM, disable the test channel to install it",
+    release_notes: "Release notes",
+    page_install: "Install & versions",
+    page_plugins: "Plugins",
+    page_profile: "Profile & data location",
+    page_diagnostics: "Diagnostics",
+    page_settings: "Settings",
+    installed_version: "Installed BetterNCM: ",
+    no_plugins: "No plugins in the data folder yet",
+    open_plugins_dir: "Open plugins folder",
+    refresh: "Refresh",
+    data_path: "Current data path: ",
+    language: "Language",
+    theme: "Theme",
+    theme_light: "Light",
+    theme_dark: "Dark",
+    theme_system: "Follow system",
+    operation_locked: "Another installer process (PID {pid}) is running {operation}, try again once it finishes",
+    operation_busy: "Another installer process is modifying BetterNCM, try again once it finishes",
+    already_running: "BetterNCM Installer is already running",
+    waiting_for_elevation: "Some steps need administrator rights, please confirm the prompt",
+    elevation_cancelled: "Administrator rights were not granted, the operation was not completed",
+    network_proxy: "Using proxy: {proxy}",
+    network_direct: "No proxy, connecting directly",
+    network_fetched: "{url}: HTTP {status}, {size} in {time}",
+    step_download_mirrors: "Download {url} or the fastest of its {count} sources to {path}",
+    probing_sources: "Testing the speed of {count} download sources",
+    download_switching_source: "Download from {from} stopped, continuing from {to}",
+    step_extract_archive: "Extract {count} files from {path}",
+};
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use super::*;
+
+    /// Every message of `lang` by its key, taken from the fields of `Messages`.
+    fn catalog(lang: Lang) -> BTreeMap<String, String> {
+        serde_json::from_value(serde_json::to_value(lang.msgs()).unwrap()).unwrap()
+    }
+
+    fn placeholders(message: &str) -> BTreeSet<&str> {
+        message
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}'))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn every_language_has_its_own_catalog() {
+        let names: BTreeSet<_> = ALL_LANGS.iter().map(|lang| lang.msgs().lang_name).collect();
+        assert_eq!(names.len(), ALL_LANGS.len());
+    }
+
+    #[test]
+    fn messages_are_not_empty() {
+        for lang in ALL_LANGS {
+            for (key, value) in catalog(lang) {
+                assert!(!value.trim().is_empty(), "{}.{key} is empty", lang.tag());
+            }
+        }
+    }
+
+    #[test]
+    fn placeholders_match_across_languages() {
+        let reference = catalog(ALL_LANGS[0]);
+        for lang in &ALL_LANGS[1..] {
+            for (key, value) in catalog(*lang) {
+                assert_eq!(
+                    placeholders(&reference[&key]),
+                    placeholders(&value),
+                    "placeholders of {key} differ between {} and {}",
+                    ALL_LANGS[0].tag(),
+                    lang.tag()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fill_replaces_every_occurrence() {
+        assert_eq!(
+            fill("{a} and {b}, {a}", &[("a", &1), ("b", &"two")]),
+            "1 and two, 1"
+        );
+        assert_eq!(fill("{missing}", &[("other", &1)]), "{missing}");
+    }
+
+    #[test]
+    fn parses_language_tags() {
+        assert_eq!(Lang::from_tag("zh_CN"), Some(Lang::ZhCn));
+        assert_eq!(Lang::from_tag("zh-Hant-TW"), Some(Lang::ZhCn));
+        assert_eq!(Lang::from_tag("EN-gb"), Some(Lang::EnUs));
+        assert_eq!(Lang::from_tag("ja-JP"), None);
+        assert_eq!(Lang::ZhCn.next(), Lang::EnUs);
+        assert_eq!(Lang::EnUs.next(), Lang::ZhCn);
+    }
+}
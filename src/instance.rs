@@ -0,0 +1,82 @@
+use std::ffi::OsStr;
+use std::iter;
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+use winapi::shared::winerror::ERROR_ALREADY_EXISTS;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::synchapi::CreateMutexW;
+use winapi::um::winuser::{
+    FindWindowW, IsIconic, MessageBoxW, SetForegroundWindow, ShowWindow, MB_ICONINFORMATION, MB_OK,
+    SW_RESTORE,
+};
+
+use crate::i18n::msgs;
+
+const MUTEX_NAME: &str = "Local\\BetterNCMInstallerGui";
+
+static MUTEX: AtomicPtr<winapi::ctypes::c_void> = AtomicPtr::new(ptr::null_mut());
+
+fn wide(text: &str) -> Vec<u16> {
+    OsStr::new(text)
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect()
+}
+
+/// Claims the GUI for this process, or returns `false` if another installer window is open.
+///
+/// The mutex is held until [`release`] or until Windows drops it when the process exits.
+pub fn claim() -> bool {
+    let name = wide(MUTEX_NAME);
+    unsafe {
+        let handle = CreateMutexW(ptr::null_mut(), 0, name.as_ptr());
+        // Without a mutex there is nothing to compare against, so let the window open
+        if handle.is_null() {
+            return true;
+        }
+        if GetLastError() == ERROR_ALREADY_EXISTS {
+            CloseHandle(handle);
+            return false;
+        }
+        MUTEX.store(handle, Ordering::SeqCst);
+        true
+    }
+}
+
+/// Lets another installer window open, used right before this one starts its replacement.
+///
+/// Returns whether this process held the claim.
+pub fn release() -> bool {
+    let handle = MUTEX.swap(ptr::null_mut(), Ordering::SeqCst);
+    if handle.is_null() {
+        return false;
+    }
+    unsafe {
+        CloseHandle(handle);
+    }
+    true
+}
+
+/// Brings the window of the running installer to the front, or says it is running.
+pub fn focus_existing(title: &str) {
+    let title = wide(title);
+    unsafe {
+        let window = FindWindowW(ptr::null(), title.as_ptr());
+        if window.is_null() {
+            MessageBoxW(
+                ptr::null_mut(),
+                wide(msgs().already_running).as_ptr(),
+                title.as_ptr(),
+                MB_OK | MB_ICONINFORMATION,
+            );
+            return;
+        }
+        if IsIconic(window) != 0 {
+            ShowWindow(window, SW_RESTORE);
+        }
+        SetForegroundWindow(window);
+    }
+}
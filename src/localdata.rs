@@ -0,0 +1,250 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+/// NCM stores `localdata` as JSON run through a fixed byte substitution: the low nibble moves up,
+/// the new low nibble is both nibbles xor'ed, and the result is xor'ed with this key.
+const KEY: u8 = 0x3b;
+
+fn encode_byte(byte: u8) -> u8 {
+    let (high, low) = (byte >> 4, byte & 0x0f);
+    ((low << 4) | (low ^ high)) ^ KEY
+}
+
+fn decode_byte(byte: u8) -> u8 {
+    let byte = byte ^ KEY;
+    let low = byte >> 4;
+    let high = (byte & 0x0f) ^ low;
+    (high << 4) | low
+}
+
+pub fn decode(data: &[u8]) -> Vec<u8> {
+    data.iter().copied().map(decode_byte).collect()
+}
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    data.iter().copied().map(encode_byte).collect()
+}
+
+fn is_proxy_key(key: &str) -> bool {
+    key.to_lowercase().contains("proxy")
+}
+
+/// Walks JSON text that is known to be valid and records the byte ranges to cut so that every
+/// member with a proxy key disappears, leaving all other text as it was.
+struct ProxyScanner<'a> {
+    text: &'a str,
+    pos: usize,
+    cuts: Vec<(usize, usize)>,
+    removed: Vec<String>,
+}
+
+impl ProxyScanner<'_> {
+    fn peek(&self) -> u8 {
+        self.text.as_bytes()[self.pos]
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let start = self.pos;
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                b'\\' => self.pos += 2,
+                b'"' => break,
+                _ => self.pos += 1,
+            }
+        }
+        self.pos += 1;
+        Ok(serde_json::from_str(&self.text[start..self.pos])?)
+    }
+
+    fn value(&mut self, path: &str) -> Result<()> {
+        self.skip_whitespace();
+        match self.peek() {
+            b'{' => self.object(path)?,
+            b'[' => {
+                self.pos += 1;
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() == b']' {
+                        break;
+                    }
+                    self.value(path)?;
+                    self.skip_whitespace();
+                    if self.peek() == b',' {
+                        self.pos += 1;
+                    }
+                }
+                self.pos += 1;
+            }
+            b'"' => {
+                self.string()?;
+            }
+            _ => {
+                while !matches!(self.peek(), b',' | b'}' | b']')
+                    && !self.peek().is_ascii_whitespace()
+                {
+                    self.pos += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn object(&mut self, path: &str) -> Result<()> {
+        let open = self.pos;
+        self.pos += 1;
+        // (start, end, removed) of every member
+        let mut members = vec![];
+        loop {
+            self.skip_whitespace();
+            if self.peek() == b'}' {
+                break;
+            }
+            let start = self.pos;
+            let key = self.string()?;
+            self.skip_whitespace();
+            // The colon
+            self.pos += 1;
+            let proxy = is_proxy_key(&key);
+            if proxy {
+                // Not descended into, the whole member goes
+                let mut skipped = ProxyScanner {
+                    text: self.text,
+                    pos: self.pos,
+                    cuts: vec![],
+                    removed: vec![],
+                };
+                skipped.value("")?;
+                self.pos = skipped.pos;
+                self.removed.push(format!("{path}{key}"));
+            } else {
+                self.value(&format!("{path}{key}."))?;
+            }
+            members.push((start, self.pos, proxy));
+            self.skip_whitespace();
+            if self.peek() == b',' {
+                self.pos += 1;
+            }
+        }
+        let close = self.pos;
+        self.pos += 1;
+
+        let Some(first_kept) = members.iter().position(|&(_, _, proxy)| !proxy) else {
+            if !members.is_empty() {
+                self.cuts.push((open + 1, close));
+            }
+            return Ok(());
+        };
+        for (index, &(start, end, proxy)) in members.iter().enumerate() {
+            if !proxy {
+                continue;
+            }
+            if index < first_kept {
+                // Up to the next member, taking the comma after this one
+                self.cuts.push((start, members[index + 1].0));
+            } else {
+                // From the end of the previous member, taking the comma before this one
+                self.cuts.push((members[index - 1].1, end));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes every proxy setting from an encoded `localdata` file and returns the new contents
+/// together with the removed keys. Only the proxy members are cut out of the text, so all other
+/// settings keep their order and formatting.
+pub fn remove_proxy(data: &[u8]) -> Result<(Vec<u8>, Vec<String>)> {
+    let decoded = decode(data);
+    let value: Value =
+        serde_json::from_slice(&decoded).context("localdata is not in the expected format")?;
+    if !value.is_object() {
+        return Ok((data.to_vec(), vec![]));
+    }
+    let text = std::str::from_utf8(&decoded)?;
+    let mut scanner = ProxyScanner {
+        text,
+        pos: 0,
+        cuts: vec![],
+        removed: vec![],
+    };
+    scanner.value("")?;
+    let mut json = text.to_string();
+    scanner.cuts.sort_unstable();
+    for &(start, end) in scanner.cuts.iter().rev() {
+        json.replace_range(start..end, "");
+    }
+    Ok((encode(json.as_bytes()), scanner.removed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_PROXY: &[u8] = include_bytes!("localdata/localdata_noproxy");
+
+    #[test]
+    fn encoding_round_trips() {
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode(&encode(&all)), all);
+        assert!(serde_json::from_slice::<Value>(&decode(NO_PROXY)).is_ok());
+    }
+
+    #[test]
+    fn keeps_settings_without_a_proxy() {
+        let (contents, removed) = remove_proxy(NO_PROXY).unwrap();
+        assert!(removed.is_empty());
+        assert_eq!(contents, NO_PROXY);
+    }
+
+    #[test]
+    fn removes_only_proxy_keys() {
+        let settings = serde_json::json!({
+            "Proxy": { "Type": "http", "Http": { "Host": "127.0.0.1" } },
+            "network": { "ProxyMode": 1, "timeout": 30 },
+            "Audio": { "RenderType": 0 }
+        });
+        let (contents, mut removed) =
+            remove_proxy(&encode(settings.to_string().as_bytes())).unwrap();
+        removed.sort();
+        assert_eq!(removed, ["Proxy", "network.ProxyMode"]);
+        let after: Value = serde_json::from_slice(&decode(&contents)).unwrap();
+        assert_eq!(
+            after,
+            serde_json::json!({ "network": { "timeout": 30 }, "Audio": { "RenderType": 0 } })
+        );
+    }
+
+    #[test]
+    fn keeps_the_rest_of_the_text() {
+        let patch = |before: &str| {
+            let (contents, _) = remove_proxy(&encode(before.as_bytes())).unwrap();
+            String::from_utf8(decode(&contents)).unwrap()
+        };
+        assert_eq!(
+            patch(
+                "{\n   \"b\": [1, {\"x\": \"}\"}],\n   \"proxy\": {\"Type\": 1},\n   \"a\": 0\n}"
+            ),
+            "{\n   \"b\": [1, {\"x\": \"}\"}],\n   \"a\": 0\n}"
+        );
+        assert_eq!(
+            patch("{\"Proxy\": \"\\\"\", \"HttpProxy\": null, \"z\": true}"),
+            "{\"z\": true}"
+        );
+        assert_eq!(
+            patch("{\"z\": {\"proxyHost\": \"h\", \"proxyPort\": 8080}}"),
+            "{\"z\": {}}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(remove_proxy(b"not localdata").is_err());
+    }
+}
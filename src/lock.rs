@@ -0,0 +1,212 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::windows::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use winapi::shared::winerror::ERROR_SHARING_VIOLATION;
+use winapi::um::winnt::FILE_SHARE_READ;
+
+use crate::i18n::{fill, msgs};
+use crate::settings::installer_data_dir;
+
+/// A holder still alive after this long is assumed to be a reused PID rather than an installer.
+const MAX_LOCK_AGE_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LockInfo {
+    pid: u32,
+    operation: String,
+    /// Unix time in seconds.
+    started_at: u64,
+}
+
+/// Held while a plan changes NCM or the profile, so only one installer process does at a time.
+///
+/// The lock file lives in the profile and is held open: nobody else may write it while it is
+/// open, and Windows closes it when the holder exits. A holder that exits normally empties the
+/// file, so what is left in it was written by one that did not, and counts until its PID is gone.
+#[derive(Debug)]
+pub struct OperationLock {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+fn lock_path() -> PathBuf {
+    installer_data_dir().join("operation.lock")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+fn is_pid_alive(pid: u32) -> bool {
+    use winapi::shared::minwindef::{DWORD, FALSE};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::minwinbase::STILL_ACTIVE;
+    use winapi::um::processthreadsapi::{GetExitCodeProcess, OpenProcess};
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let mut code: DWORD = 0;
+        let alive = GetExitCodeProcess(handle, &mut code) != 0 && code == STILL_ACTIVE;
+        CloseHandle(handle);
+        alive
+    }
+}
+
+impl LockInfo {
+    fn is_stale(&self) -> bool {
+        !is_pid_alive(self.pid) || now().saturating_sub(self.started_at) > MAX_LOCK_AGE_SECS
+    }
+}
+
+fn locked_by(holder: &LockInfo) -> anyhow::Error {
+    anyhow::anyhow!(fill(
+        msgs().operation_locked,
+        &[("pid", &holder.pid), ("operation", &holder.operation)],
+    ))
+}
+
+impl OperationLock {
+    pub fn acquire(operation: &str) -> Result<OperationLock> {
+        fs::create_dir_all(installer_data_dir())?;
+        OperationLock::acquire_at(&lock_path(), operation)
+    }
+
+    fn acquire_at(path: &Path, operation: &str) -> Result<OperationLock> {
+        // Others may still read who holds the lock, but not open it for writing
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .share_mode(FILE_SHARE_READ)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION as i32) => {
+                let content = fs::read_to_string(path).unwrap_or_default();
+                match serde_json::from_str::<LockInfo>(&content) {
+                    Ok(holder) if !holder.is_stale() => return Err(locked_by(&holder)),
+                    // The holder has opened the file and not written it yet
+                    _ => bail!(msgs().operation_busy),
+                }
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to open '{}'", path.display()))
+            }
+        };
+        let mut left = String::new();
+        let _ = file.read_to_string(&mut left);
+        if let Ok(holder) = serde_json::from_str::<LockInfo>(&left) {
+            if !holder.is_stale() {
+                return Err(locked_by(&holder));
+            }
+            tracing::warn!(
+                pid = holder.pid,
+                operation = %holder.operation,
+                "taking over stale operation lock"
+            );
+        }
+        let info = LockInfo {
+            pid: process::id(),
+            operation: operation.to_string(),
+            started_at: now(),
+        };
+        let content = serde_json::to_string(&info)?;
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .and_then(|()| file.write_all(content.as_bytes()))
+            .with_context(|| format!("Failed to write '{}'", path.display()))?;
+        tracing::info!(path = %path.display(), operation, "acquired operation lock");
+        Ok(OperationLock {
+            path: path.to_path_buf(),
+            file: Some(file),
+        })
+    }
+}
+
+impl Drop for OperationLock {
+    fn drop(&mut self) {
+        // Emptied first, so whoever opens the file before it is removed does not see a holder
+        if let Some(file) = self.file.take() {
+            let _ = file.set_len(0);
+        }
+        // Fails harmlessly when another process opened the file right after it was closed
+        if let Err(err) = fs::remove_file(&self.path) {
+            tracing::debug!(
+                error = ?err,
+                path = %self.path.display(),
+                "did not remove operation lock"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn only_one_holder_at_a_time() {
+        let dir = TempDir::new("lock");
+        let path = dir.join("operation.lock");
+        let lock = OperationLock::acquire_at(&path, "install").unwrap();
+        let err = OperationLock::acquire_at(&path, "uninstall").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            fill(
+                msgs().operation_locked,
+                &[("pid", &process::id()), ("operation", &"install")]
+            )
+        );
+        drop(lock);
+        assert!(!path.exists());
+        drop(OperationLock::acquire_at(&path, "uninstall").unwrap());
+    }
+
+    #[test]
+    fn takes_over_stale_locks_left_behind() {
+        let dir = TempDir::new("lock-left");
+        let path = dir.join("operation.lock");
+        // A PID that is gone, and one that is alive but too old to still be the installer
+        for (pid, started_at) in [(u32::MAX, now()), (process::id(), 0)] {
+            let left = LockInfo {
+                pid,
+                operation: "a much longer operation name".into(),
+                started_at,
+            };
+            fs::write(&path, serde_json::to_string(&left).unwrap()).unwrap();
+            let lock = OperationLock::acquire_at(&path, "update").unwrap();
+            let info: LockInfo = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(info.operation, "update");
+            drop(lock);
+        }
+    }
+
+    #[test]
+    fn respects_a_live_holder_left_behind() {
+        let dir = TempDir::new("lock-live");
+        let path = dir.join("operation.lock");
+        let left = LockInfo {
+            pid: process::id(),
+            operation: "install".into(),
+            started_at: now(),
+        };
+        fs::write(&path, serde_json::to_string(&left).unwrap()).unwrap();
+        assert!(OperationLock::acquire_at(&path, "update").is_err());
+        assert_eq!(
+            serde_json::from_str::<LockInfo>(&fs::read_to_string(&path).unwrap()).unwrap(),
+            left
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Result;
+use tracing_appender::rolling::{Builder, Rotation};
+
+use crate::settings::installer_data_dir;
+
+const LOG_FILE_PREFIX: &str = "installer";
+const LOG_FILE_SUFFIX: &str = "log";
+const MAX_LOG_FILES: usize = 7;
+
+pub fn log_dir() -> PathBuf {
+    installer_data_dir().join("logs")
+}
+
+/// Starts writing structured logs to a daily rotated file in the profile directory.
+///
+/// Every event is written to the file before the logging call returns, so nothing is lost when
+/// the process exits or aborts on a panic.
+pub fn init() {
+    let Ok(appender) = Builder::new()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix(LOG_FILE_SUFFIX)
+        .max_log_files(MAX_LOG_FILES)
+        .build(log_dir())
+    else {
+        return;
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(appender)
+        .with_ansi(false)
+        .with_target(false)
+        .with_thread_ids(true)
+        .try_init();
+    if subscriber.is_err() {
+        return;
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        tracing::error!(panic = %info, "installer panicked");
+        default_hook(info);
+    }));
+
+    tracing::info!(
+        version = env!("CARGO_PKG_VERSION"),
+        args = ?std::env::args().collect::<Vec<_>>(),
+        "installer started"
+    );
+}
+
+fn is_log_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(LOG_FILE_PREFIX) && name.ends_with(LOG_FILE_SUFFIX))
+}
+
+pub fn log_files() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(log_dir())
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| is_log_file(path))
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+pub fn open_log_dir() -> Result<()> {
+    fs::create_dir_all(log_dir())?;
+    Command::new("explorer.exe").arg(log_dir()).spawn()?;
+    Ok(())
+}
+
+pub fn export_logs(dest_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut exported = vec![];
+    for file in log_files() {
+        if let Some(name) = file.file_name() {
+            let dest = dest_dir.join(name);
+            fs::copy(&file, &dest)?;
+            exported.push(dest);
+        }
+    }
+    tracing::info!(dest = %dest_dir.display(), count = exported.len(), "exported logs");
+    Ok(exported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_rotated_log_files() {
+        assert!(is_log_file(Path::new(r"C:\logs\installer.2024-05-01.log")));
+        assert!(is_log_file(Path::new("installer.log")));
+        assert!(!is_log_file(Path::new("installer.2024-05-01.log.tmp")));
+        assert!(!is_log_file(Path::new("settings.json")));
+        assert!(!is_log_file(Path::new(r"C:\logs\")));
+    }
+}
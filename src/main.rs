@@ -4,148 +4,372 @@
 )]
 #![feature(fs_try_exists)]
 #![feature(rustc_attrs)]
+mod bundle;
+mod cli;
+mod compat;
+mod conflicts;
+mod deploy;
+mod diagnostics;
+mod download;
+mod i18n;
+mod instance;
+mod localdata;
+mod lock;
+mod logging;
+mod manifest;
 #[rustc_box]
 mod ncm_utils;
+mod network;
+mod operations;
+mod pages;
+mod payload;
+mod policy;
+mod privilege;
+mod receipt;
+mod release_notes;
+mod self_update;
+mod settings;
+mod status;
+#[cfg(test)]
+mod test_util;
+mod theme;
+mod vc_runtime;
+mod verify;
+use std::env;
 use std::fs;
-use std::path::Path;
-use std::path::PathBuf;
+use std::ops::Deref;
 use std::process;
-use std::process::Command;
-use std::time::Duration;
-use std::{env, os::windows::process::CommandExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
-use anyhow::Context;
 use anyhow::Result;
+use conflicts::Conflict;
 use druid::commands::CLOSE_ALL_WINDOWS;
-use druid::widget::Checkbox;
-use druid::widget::{Flex, Label, ProgressBar};
+use druid::widget::{Either, EnvScope, Flex, Label, LineBreaking, Scroll};
 use druid::Color;
 use druid::ExtEventSink;
+use druid::Target;
 use druid::{
     AppLauncher, Data, FontDescriptor, FontWeight, Lens, Widget, WidgetExt as _, WindowDesc,
 };
+use i18n::Lang;
+use manifest::{Channel, InstallerRelease, ManifestEntry};
 use ncm_utils::Ncm;
-use ncm_utils::{is_vc_redist_14_x64_installed, is_vc_redist_14_x86_installed};
+use operations::{Operation, Plan, Reporter};
+use receipt::CheckOutcome;
 use semver::Version;
-use winreg::enums::HKEY_CURRENT_USER;
-use winreg::enums::HKEY_LOCAL_MACHINE;
-use winreg::RegKey;
+use theme::ThemeSetting;
 
 use scl_gui_widgets::{
     widget_ext::WidgetExt,
-    widgets::{Button, WindowWidget, QUERY_CLOSE_WINDOW},
+    widgets::{
+        Button, ProgressOverlay, WindowWidget, BACK_PAGE_CLICKED, ENABLE_BACK_PAGE, HIDE_PROGRESS,
+        NEW_PROGRESS, ON_PAGE, QUERY_CLOSE_WINDOW, QUERY_POP_PAGE, REMOVE_PROGRESS,
+        SET_MAX_PROGRESS, SET_MESSAGE, SET_PROGRESS,
+    },
 };
 
-use crate::ncm_utils::get_ncm_install_path;
+use crate::download::DownloadProgress;
+use crate::i18n::{fill, msgs, text};
+use crate::ncm_utils::{get_betterncm_profile_path, get_file_version, get_ncm_install_path};
+use crate::settings::Settings;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum AdaptedVersionResult {
     Version(Version),
     NoAdaptedVersion,
+    FetchFailed,
 }
 
 #[derive(Debug, Clone, Data, Lens)]
 struct AppData {
-    progress: f64,
     prerelease: bool,
     #[data(eq)]
     latest_version: Option<AdaptedVersionResult>,
     old_version: bool,
     new_version: bool,
+    /// Version of the `msimg32.dll` next to NCM.
+    #[data(eq)]
+    installed_version: Option<Version>,
     #[data(eq)]
     installer_version: Version,
 
     #[data(eq)]
     tips_string: String,
+    /// Manifest entry of the adapted version, which the install plan is made from.
     #[data(eq)]
-    latest_download_url: Option<String>,
+    adapted_entry: Option<ManifestEntry>,
     #[data(eq)]
     ncm: Option<Ncm>,
-}
-
-fn config_path() -> String {
-    String::from(
-        dirs::home_dir()
-            .unwrap()
-            .as_os_str()
-            .to_str()
-            .expect("Covert error"),
-    ) + "\\betterncm\\"
+    #[data(eq)]
+    lang: Lang,
+    #[data(eq)]
+    theme: ThemeSetting,
+    /// Whether Windows currently asks apps for the dark theme.
+    system_dark: bool,
+    #[data(eq)]
+    installer_update: Option<InstallerRelease>,
+    manifest_unsupported: bool,
+    /// Age in seconds of the cached manifest in use while the server cannot be reached.
+    manifest_stale: Option<u64>,
+    /// Warning shown while the installed BetterNCM build is one the manifest has yanked.
+    yanked: Option<String>,
+    /// Explains which NCM versions the channel supports when none fits the installed one.
+    unadapted: Option<String>,
+    /// Release notes from the installed BetterNCM up to the adapted one.
+    release_notes: Option<String>,
+    #[data(eq)]
+    pending_plan: Option<Plan>,
+    #[data(eq)]
+    conflicts: Vec<Conflict>,
+    #[data(eq)]
+    plugins: Vec<String>,
 }
 
 fn get_adapted_betterncm_version(
     ncm: Option<Ncm>,
     event_sink: ExtEventSink,
-    channel: String,
-) -> anyhow::Result<(), Box<dyn std::error::Error>> {
-    if let Some(ncm) = ncm {
-        use serde_json::Value;
-        let releases = tinyget::get(
-            "https://gitcode.net/qq_21551787/bncm-data-pack2/-/raw/master/betterncm/betterncm3.json",
-        )
-        .with_header("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/115.0.0.0 Safari/537.36")
-        .send()?;
-
-        let releases = releases.as_str()?;
-
-        let releases: Value = serde_json::from_str(releases)?;
-
-        let adapted_versions = releases[channel]
-            .as_object()
-            .context("Invalid JSON")?
-            .clone();
-        for (version_req, val) in adapted_versions.iter() {
-            if semver::VersionReq::parse(version_req)
-                .context("Failed to parse version req")?
-                .matches(&ncm.version)
-            {
-                let latest_version = Some(AdaptedVersionResult::Version(
-                    Version::parse(val["version"].to_owned().as_str().unwrap()).unwrap(),
-                ));
-                let latest_url = Some(if ncm.ncm_type == ncm_utils::NcmType::X86 {
-                    val["url_x86"].to_owned().as_str().unwrap().to_string()
-                } else {
-                    val["url_x64"].to_owned().as_str().unwrap().to_string()
-                });
+    channel: Channel,
+) -> Result<()> {
+    let fetched = manifest::fetch_with_source()?;
+    let manifest = fetched.manifest;
+    let manifest_stale = fetched.stale_age();
+    let _ = fs::create_dir_all(settings::installer_data_dir());
+    let _ = fs::write(diagnostics::last_manifest_path(), fetched.raw);
+
+    let installer_version = manifest::installer_version();
+    let installer_update = manifest.newer_installer(&installer_version).cloned();
+    if let Some(release) = &installer_update {
+        tracing::warn!(current = %installer_version, latest = %release.version, "installer is outdated");
+    }
+    let manifest_supported = manifest.is_supported_by(&installer_version);
+    if !manifest_supported {
+        tracing::warn!(
+            current = %installer_version,
+            required = ?manifest.min_installer_version,
+            "manifest requires a newer installer"
+        );
+    }
 
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.latest_version = latest_version;
-                    data.latest_download_url = latest_url;
-                });
-                return Ok(());
+    let yanked = ncm
+        .as_ref()
+        .and_then(|ncm| get_file_version(&ncm.path.join("msimg32.dll")).ok())
+        .and_then(|version| {
+            let entry = manifest.yanked(&version)?;
+            tracing::warn!(
+                version = %version,
+                advisory = ?entry.advisory,
+                "installed BetterNCM is yanked"
+            );
+            Some(verify::yanked_text(&version, entry.advisory.as_deref()))
+        });
+
+    let mut adapted = None;
+    let mut unadapted = None;
+    if let (Some(ncm), true) = (&ncm, manifest_supported) {
+        let pinned = policy::active().and_then(|policy| policy.version.as_ref());
+        match manifest.resolve(channel, ncm, pinned)? {
+            Some((version_req, entry)) => {
+                tracing::info!(
+                    version_req = %version_req,
+                    version = %entry.version,
+                    url = %entry.url_for(&ncm.ncm_type),
+                    mirrors = ?entry.mirrors_for(&ncm.ncm_type),
+                    "chose adapted BetterNCM version"
+                );
+                adapted = Some(entry.clone());
+            }
+            None => {
+                // A pinned version is the policy's choice, so the ranges would not help there
+                if pinned.is_none() {
+                    let explanation = compat::explain(&manifest, channel, &ncm.version)?;
+                    tracing::warn!(explanation = ?explanation, "no adapted BetterNCM version");
+                    unadapted = Some(explanation.to_string());
+                } else {
+                    tracing::warn!(
+                        ncm_version = %ncm.version,
+                        channel = channel.key(),
+                        "pinned BetterNCM version is not adapted"
+                    );
+                }
             }
         }
     }
 
+    let notes_range = adapted.as_ref().map(|entry| {
+        let installed = ncm
+            .as_ref()
+            .and_then(|ncm| get_file_version(&ncm.path.join("msimg32.dll")).ok());
+        (installed, entry.version.clone())
+    });
+
     event_sink.add_idle_callback(move |data: &mut AppData| {
-        data.latest_version = Some(AdaptedVersionResult::NoAdaptedVersion);
+        data.installer_update = installer_update;
+        data.manifest_unsupported = !manifest_supported;
+        data.manifest_stale = manifest_stale;
+        data.yanked = yanked;
+        data.unadapted = unadapted;
+        match adapted {
+            Some(entry) => {
+                data.latest_version = Some(AdaptedVersionResult::Version(entry.version.clone()));
+                data.adapted_entry = Some(entry);
+            }
+            None => {
+                data.latest_version = Some(AdaptedVersionResult::NoAdaptedVersion);
+                data.adapted_entry = None;
+            }
+        }
+        data.release_notes = None;
     });
 
+    // Notes may have to be downloaded, so they follow once the version is already shown
+    if let Some((installed, target)) = notes_range {
+        let notes = release_notes::collect(&manifest, installed.as_ref(), &target);
+        if !notes.is_empty() {
+            let notes = release_notes::render(&notes);
+            event_sink.add_idle_callback(move |data: &mut AppData| {
+                data.release_notes = Some(notes);
+            });
+        }
+    }
+
     Ok(())
 }
 
+fn fetch_adapted_version_in_background(
+    ncm: Option<Ncm>,
+    event_sink: ExtEventSink,
+    channel: Channel,
+) {
+    std::thread::spawn(move || {
+        if let Err(err) = get_adapted_betterncm_version(ncm, event_sink.clone(), channel) {
+            tracing::error!(error = ?err, channel = channel.key(), "failed to fetch manifest");
+            event_sink.add_idle_callback(|data: &mut AppData| {
+                data.latest_version = Some(AdaptedVersionResult::FetchFailed);
+                data.adapted_entry = None;
+            });
+        }
+    });
+}
+
+/// NCM updates may replace its install directory, so compare it with the last install on startup.
+fn check_installation_in_background(event_sink: ExtEventSink) {
+    std::thread::spawn(move || {
+        let outcome = manifest::fetch().and_then(|(manifest, _)| receipt::check(&manifest));
+        let (tip, plan) = match outcome {
+            Ok(CheckOutcome::Unchanged) => return,
+            Ok(CheckOutcome::Reapply { drift, plan }) => (
+                fill(msgs().reapply_prompt, &[("reason", &drift)]),
+                Some(plan),
+            ),
+            Ok(CheckOutcome::NoAdaptedVersion { drift, ncm_version }) => (
+                fill(
+                    msgs().reapply_not_adapted,
+                    &[("reason", &drift), ("version", &ncm_version)],
+                ),
+                None,
+            ),
+            Err(err) => {
+                tracing::error!(error = ?err, "failed to check the installation");
+                return;
+            }
+        };
+        event_sink.add_idle_callback(move |data: &mut AppData| {
+            data.tips_string = tip;
+            if data.pending_plan.is_none() {
+                data.pending_plan = plan;
+            }
+        });
+    });
+}
+
 fn main() -> Result<()> {
+    let args = cli::parse_args(env::args().skip(1));
+    let settings = Settings::load();
+    i18n::set_lang(
+        args.as_ref()
+            .ok()
+            .and_then(|args| args.lang)
+            .or(settings.lang)
+            .unwrap_or_else(Lang::system),
+    );
+    logging::init();
+    self_update::cleanup_old_executable();
+
+    let offline_bundle = args
+        .as_ref()
+        .ok()
+        .and_then(|args| args.offline.clone())
+        .or(settings.offline_bundle)
+        .or_else(bundle::find_default_bundle);
+    if let Some(path) = offline_bundle {
+        if let Err(err) = bundle::activate(&path) {
+            tracing::error!(error = ?err, path = %path.display(), "failed to open offline bundle");
+            if args.as_ref().is_ok_and(|args| args.command.is_some()) {
+                cli::attach_console();
+                eprintln!("{err:?}");
+                process::exit(1);
+            }
+        }
+    }
+
+    match policy::machine_policy() {
+        Ok(Some(policy)) => {
+            tracing::info!(policy = ?policy, "loaded machine policy");
+            policy::activate(policy);
+        }
+        Ok(None) => {}
+        Err(err) => tracing::error!(error = ?err, "failed to load machine policy"),
+    }
+
+    let mut network_settings = policy::active()
+        .and_then(|policy| policy.network.clone())
+        .unwrap_or(settings.network);
+    if let Some(proxy) = args.as_ref().ok().and_then(|args| args.proxy.clone()) {
+        network_settings.proxy = Some(proxy);
+    }
+    network::configure(network_settings);
+
+    match args {
+        Ok(cli::CliArgs {
+            command: Some(command),
+            ..
+        }) => {
+            cli::attach_console();
+            let code = cli::run(command).unwrap_or_else(|err| {
+                tracing::error!(error = ?err, "command failed");
+                eprintln!("{err:?}");
+                1
+            });
+            process::exit(code);
+        }
+        Err(err) => {
+            cli::attach_console();
+            eprintln!("{err}");
+            process::exit(2);
+        }
+        Ok(_) => {}
+    }
+
+    if !instance::claim() {
+        tracing::info!("another installer window is open");
+        instance::focus_existing("BetterNCM Installer");
+        return Ok(());
+    }
+
     let main_window = WindowDesc::new(ui_builder())
-        .window_size((400., 310.))
+        .window_size((400., 375.))
         .resizable(false)
         .show_titlebar(false)
         .title("BetterNCM Installer");
 
     let mut data = AppData {
-        prerelease: false,
-        progress: 0.,
+        prerelease: policy::active().is_some_and(|policy| policy.channel() == Channel::Test),
         latest_version: None,
-        old_version: if let Ok(path) = get_ncm_install_path() {
-            path.join("cloudmusicn.exe").exists()
-        } else {
-            false
-        },
-        new_version: if let Ok(path) = get_ncm_install_path() {
-            path.join("msimg32.dll").exists()
-        } else {
-            false
-        },
-        latest_download_url: None,
+        old_version: false,
+        new_version: false,
+        installed_version: None,
+        adapted_entry: None,
         installer_version: Version::parse(env!("CARGO_PKG_VERSION"))?,
         ncm: get_ncm_install_path()
             .and_then(|path| Ncm::get_ncm_by_path(path))
@@ -157,509 +381,333 @@ fn main() -> Result<()> {
         // },
         // ncm_version: get_ncm_version().ok(),
         tips_string: String::new(),
+        lang: i18n::lang(),
+        theme: settings.theme,
+        system_dark: theme::system_is_dark(),
+        installer_update: None,
+        manifest_unsupported: false,
+        manifest_stale: None,
+        yanked: None,
+        unadapted: None,
+        release_notes: None,
+        pending_plan: None,
+        conflicts: vec![],
+        plugins: vec![],
     };
+    refresh_install_state(&mut data);
+    tracing::info!(
+        ncm = ?data.ncm,
+        old_version = data.old_version,
+        new_version = data.new_version,
+        "detected NCM"
+    );
     if let Some(ncm) = &data.ncm {
         if &ncm.version < &Version::new(2, 10, 2) {
-            data.tips_string = "您的网易云版本太低，请更新".to_string();
+            data.tips_string = msgs().ncm_too_old.to_string();
         }
     }
     let launcher = AppLauncher::with_window(main_window);
 
     let event_sink = launcher.get_external_handle();
 
-    let ncm_version_ = data.ncm.clone();
-
-    std::thread::spawn(move || {
-        let _ = get_adapted_betterncm_version(ncm_version_, event_sink, "versions".to_string());
-    });
+    fetch_adapted_version_in_background(
+        data.ncm.clone(),
+        event_sink.clone(),
+        Channel::from_prerelease(data.prerelease),
+    );
+    check_installation_in_background(event_sink.clone());
+    theme::watch_system_theme(event_sink);
 
+    let initial_theme = data.theme.resolve(data.system_dark);
     launcher
-        .configure_env(|env, _| {
-            scl_gui_widgets::theme::color::set_color_to_env(
-                env,
-                scl_gui_widgets::theme::color::Theme::Dark,
-            );
+        .configure_env(move |env, _| {
+            scl_gui_widgets::theme::color::set_color_to_env(env, initial_theme);
         })
         .launch(data)?;
     Ok(())
 }
 
-fn get_ncm_localdata_path() -> String {
-    let appdata = env::var("APPDATA").unwrap();
-    let ncmdata = Path::new(&appdata);
-    ncmdata
-        .parent()
-        .unwrap()
-        .join("Local")
-        .join("Netease")
-        .join("CloudMusic")
-        .to_str()
-        .unwrap()
-        .to_string()
-}
-
-fn set_noproxy_localdata() -> anyhow::Result<()> {
-    fs::write(
-        get_ncm_localdata_path() + "/localdata",
-        include_bytes!("localdata/localdata_noproxy"),
-    )?;
-    Ok(())
-}
-
 fn ui_builder() -> impl Widget<AppData> {
-    let title = Label::new("BetterNCM Installer".to_string()).with_font(
-        FontDescriptor::default()
-            .with_size(20.)
-            .with_weight(FontWeight::BOLD),
-    );
-
-    let installer_version_label = Flex::row()
-        .with_child(Label::new("BetterNCM Installer 版本: ").with_text_color(Color::grey(0.7)))
-        .with_child(
-            Label::new(|data: &AppData, _env: &_| -> String { data.installer_version.to_string() })
-                .with_font(
-                    FontDescriptor::default()
-                        .with_size(17.)
-                        .with_weight(FontWeight::SEMI_BOLD),
-                ),
-        );
-
-    let latest_version_label = Flex::row()
-        .with_child(Label::new("适配 BetterNCM 版本: ").with_text_color(Color::grey(0.7)))
-        .with_child(
-            Label::new(|data: &AppData, _env: &_| -> String {
-                match &data.latest_version {
-                    Some(AdaptedVersionResult::Version(version)) => version.to_string(),
-                    Some(AdaptedVersionResult::NoAdaptedVersion) => "未适配".to_string(),
-                    None => String::from("获取中..."),
-                }
-            })
-            .with_font(
-                FontDescriptor::default()
-                    .with_size(17.)
-                    .with_weight(FontWeight::SEMI_BOLD),
-            ),
-        );
+    let pages = pages::page_switcher().on_notify(ON_PAGE, |ctx, page, _data| {
+        ctx.submit_command(ENABLE_BACK_PAGE.with(*page != pages::PAGE_OVERVIEW));
+    });
 
-    let local_version_label = Flex::row().with_child(
-        Label::new(|data: &AppData, _env: &_| -> String {
-            match data.old_version {
-                true => String::from("检测到老版本BetterNCM 请先卸载"),
-                false => String::from(""),
-            }
-        })
-        .with_font(
-            FontDescriptor::default()
-                .with_size(17.)
-                .with_weight(FontWeight::SEMI_BOLD),
-        ),
-    );
+    let tips = Label::new(|data: &AppData, _env: &_| -> String { data.tips_string.clone() })
+        .with_line_break_mode(LineBreaking::WordWrap)
+        .padding((10., 0., 10., 10.))
+        .show_if(|data: &AppData, _env| {
+            data.pending_plan.is_none() && !data.tips_string.is_empty()
+        });
 
-    let install_path_label = Flex::row()
-        .with_child(Label::new("网易云版本: ").with_text_color(Color::grey(0.7)))
-        .with_child(
-            Label::new(|data: &AppData, _env: &_| -> String {
-                match &data.ncm {
-                    Some(ncm) => format!("{} ({:#?})", ncm.version, ncm.ncm_type).to_lowercase(),
-                    None => "未安装".to_string(),
-                }
-            })
-            .with_font(
-                FontDescriptor::default()
-                    .with_size(17.)
-                    .with_weight(FontWeight::SEMI_BOLD),
+    let content = Flex::column()
+        .with_flex_child(
+            Either::new(
+                |data: &AppData, _env| data.pending_plan.is_some(),
+                plan_view(),
+                pages,
             ),
-        );
+            1.,
+        )
+        .with_child(tips)
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start);
 
-    let checker_prerelease = Checkbox::new("测试通道")
-        .on_change(|ctx, _old, new, _env| {
-            let sink = ctx.get_external_handle();
-            let channel = if *new { "test" } else { "versions" };
-            ctx.get_external_handle()
-                .add_idle_callback(move |data: &mut AppData| {
-                    data.latest_version = None;
-                    data.tips_string = "".into();
-                    let ncm = data.ncm.clone();
-                    std::thread::spawn(move || {
-                        let _ = get_adapted_betterncm_version(ncm, sink, channel.to_string());
-                    });
-                });
-        })
-        .lens(AppData::prerelease);
-
-    let button_install = Button::new("安装")
-        .disabled_if(|data: &AppData, _env: &_| {
-            data.latest_version.is_none()
-                || data.latest_version == Some(AdaptedVersionResult::NoAdaptedVersion)
-                || data.old_version
-                || data.new_version
+    let window = WindowWidget::new("BetterNCM Installer", ProgressOverlay::new(content))
+        .on_notify(QUERY_CLOSE_WINDOW, |ctx, _, _| {
+            ctx.submit_command(CLOSE_ALL_WINDOWS);
         })
-        .on_click(|ctx, data, _env| {
-            let event_sink = ctx.get_external_handle();
-            let url: String = data.latest_download_url.as_ref().unwrap().clone();
-            std::thread::spawn(move || {
-                fn add_exclude_from_wd() -> anyhow::Result<()> {
-                    // Command::new("powershell.exe")
-                    //     .arg("-Command")
-                    //     .arg(format!(
-                    //         "Add-MpPreference -ExclusionPath \"{}\"",
-                    //         get_ncm_install_path()?
-                    //             .to_str()
-                    //             .context("Failed to get ncm install path")?
-                    //     ))
-                    //     .spawn()?
-                    //     .wait()?;
-
-                    Ok(())
-                }
-
-                let _ = add_exclude_from_wd();
-
-                let _ = std::fs::remove_file("betterncm.dll");
-
-                download_file(&url, "betterncm.dll", event_sink.to_owned());
-
-                install_vc_redist_14(event_sink.to_owned());
-
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.tips_string = "正在安装 BetterNCM…".into();
-                });
-
-                Command::new("taskkill.exe")
-                    .args(["/f", "/im", "cloudmusic.exe"])
-                    .creation_flags(0x08000000)
-                    .spawn()?
-                    .wait()?;
-
-                std::thread::sleep(Duration::from_millis(300));
-
-                std::fs::copy("betterncm.dll", get_ncm_install_path()?.join("msimg32.dll"))
-                    .unwrap();
-
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.tips_string = "安装成功！".into();
-                    data.new_version = if let Ok(path) = get_ncm_install_path() {
-                        path.join("msimg32.dll").exists()
-                    } else {
-                        false
-                    };
-                });
-
-                Command::new(get_ncm_install_path()?.join("cloudmusic.exe"))
-                    .current_dir(get_ncm_install_path()?)
-                    .spawn()?;
-                anyhow::Ok(())
-            });
+        .on_notify(BACK_PAGE_CLICKED, |ctx, _, data: &mut AppData| {
+            // The plan replaces the current page, so going back first dismisses it
+            if data.pending_plan.is_some() {
+                data.pending_plan = None;
+            } else {
+                ctx.submit_command(QUERY_POP_PAGE.with(""));
+            }
         });
 
-    let button_reinstall = Button::new("重装/更新")
-        .disabled_if(|data: &AppData, _env: &_| {
-            data.latest_version.is_none()
-                || data.latest_version == Some(AdaptedVersionResult::NoAdaptedVersion)
-                || data.old_version
-                || !data.new_version
-        })
-        .on_click(|ctx, data, _env| {
-            let event_sink = ctx.get_external_handle();
-            let url: String = data.latest_download_url.as_ref().unwrap().clone();
-            std::thread::spawn(move || {
-                let _ = std::fs::remove_file("betterncm.dll");
-
-                download_file(&url, "betterncm.dll", event_sink.to_owned());
-                install_vc_redist_14(event_sink.to_owned());
-
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.tips_string = "正在升级/重新安装 BetterNCM…".into();
-                });
-
-                Command::new("taskkill.exe")
-                    .args(["/f", "/im", "cloudmusic.exe"])
-                    .creation_flags(0x08000000)
-                    .spawn()?
-                    .wait()?;
-
-                std::thread::sleep(Duration::from_millis(300));
+    EnvScope::new(
+        |env, data: &AppData| {
+            scl_gui_widgets::theme::color::set_color_to_env(
+                env,
+                data.theme.resolve(data.system_dark),
+            );
+        },
+        window,
+    )
+}
 
-                std::fs::copy("betterncm.dll", get_ncm_install_path()?.join("msimg32.dll"))
-                    .unwrap();
+fn plan_view() -> impl Widget<AppData> {
+    let title = Label::new(|data: &AppData, _env: &_| -> String {
+        match &data.pending_plan {
+            Some(plan) => fill(msgs().plan_title, &[("operation", &plan.operation.title())]),
+            None => String::new(),
+        }
+    })
+    .with_font(
+        FontDescriptor::default()
+            .with_size(17.)
+            .with_weight(FontWeight::SEMI_BOLD),
+    );
 
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.tips_string = "升级/重新安装成功！".into();
-                    data.new_version = if let Ok(path) = get_ncm_install_path() {
-                        path.join("msimg32.dll").exists()
-                    } else {
-                        false
-                    };
-                });
+    let steps = Label::new(|data: &AppData, _env: &_| -> String {
+        data.pending_plan
+            .as_ref()
+            .map(|plan| plan.to_string())
+            .unwrap_or_default()
+    })
+    .with_line_break_mode(LineBreaking::WordWrap);
 
-                Command::new(get_ncm_install_path()?.join("cloudmusic.exe"))
-                    .current_dir(get_ncm_install_path()?)
-                    .spawn()?;
-                anyhow::Ok(())
-            });
+    let button_cancel =
+        Button::new(text(|m| m.cancel)).on_click(|_ctx, data: &mut AppData, _env| {
+            data.pending_plan = None;
         });
 
-    let button_uninstall = Button::new("卸载")
-        .disabled_if(|data: &AppData, _env: &_| data.old_version || !data.new_version)
-        .on_click(|ctx, _data, _env| {
-            let event_sink = ctx.get_external_handle();
-            std::thread::spawn(move || {
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.tips_string = "正在卸载 BetterNCM…".into();
-                });
-                Command::new("taskkill.exe")
-                    .args(["/f", "/im", "cloudmusic.exe"])
-                    .creation_flags(0x08000000)
-                    .spawn()?
-                    .wait()?;
-                Command::new("taskkill.exe")
-                    .args(["/f", "/im", "cloudmusicn.exe"])
-                    .creation_flags(0x08000000)
-                    .spawn()?
-                    .wait()?;
-                fs::remove_file(get_ncm_install_path()?.join("msimg32.dll"))?;
-
-                event_sink.add_idle_callback(move |data: &mut AppData| {
-                    data.new_version = if let Ok(path) = get_ncm_install_path() {
-                        path.join("msimg32.dll").exists()
-                    } else {
-                        false
-                    };
-                    data.tips_string = "卸载完成！".into();
-                });
-
-                process::Command::new(get_ncm_install_path()?.join("cloudmusic.exe"))
-                    .current_dir(get_ncm_install_path()?)
-                    .spawn()?;
-                anyhow::Ok(())
-            });
+    let button_confirm =
+        Button::new(text(|m| m.confirm)).on_click(|ctx, data: &mut AppData, _env| {
+            if let Some(plan) = data.pending_plan.take() {
+                run_plan(ctx.get_external_handle(), plan);
+            }
         });
 
-    let button_uninstall_old = Button::new("卸载老版本")
-        .disabled_if(|data: &AppData, _env: &_| !data.old_version)
-        .on_click(|_ctx, data, _env| {
-            let mut ins = || {
-                fs::remove_dir_all(config_path())?;
-                Command::new("taskkill.exe")
-                    .args(["/f", "/im", "cloudmusic.exe"])
-                    .creation_flags(0x08000000)
-                    .spawn()?
-                    .wait()?;
-                Command::new("taskkill.exe")
-                    .args(["/f", "/im", "cloudmusicn.exe"])
-                    .creation_flags(0x08000000)
-                    .spawn()?
-                    .wait()?;
-                fs::remove_file(get_ncm_install_path()?.join("cloudmusic.exe"))?;
-
-                fs::rename(
-                    get_ncm_install_path()?.join("cloudmusicn.exe"),
-                    get_ncm_install_path()?.join("cloudmusic.exe"),
-                )?;
-
-                set_noproxy_localdata()?;
-
-                data.old_version = if let Ok(path) = get_ncm_install_path() {
-                    path.join("cloudmusicn.exe").exists()
-                } else {
-                    false
-                };
-
-                process::Command::new(get_ncm_install_path()?.join("cloudmusic.exe"))
-                    .current_dir(get_ncm_install_path()?)
-                    .spawn()?;
-                anyhow::Ok(())
-            };
-            ins().unwrap();
+    let tips = Label::new(|data: &AppData, _env: &_| -> String { data.tips_string.clone() })
+        .with_line_break_mode(LineBreaking::WordWrap);
+
+    let notes = Label::new(|data: &AppData, _env: &_| -> String {
+        data.release_notes.clone().unwrap_or_default()
+    })
+    .with_line_break_mode(LineBreaking::WordWrap);
+    // Shown after the steps in the same scroll area, so a long changelog cannot hide the steps
+    let notes_panel = Flex::column()
+        .with_spacer(10.)
+        .with_child(Label::new(text(|m| m.release_notes)).with_text_color(Color::grey(0.7)))
+        .with_child(notes)
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+        .show_if(|data: &AppData, _env| {
+            data.release_notes.is_some()
+                && data.pending_plan.as_ref().is_some_and(|plan| {
+                    matches!(plan.operation, Operation::Install | Operation::Update)
+                })
         });
 
-    let button_set_path = Button::new("修改数据地址").on_click(|_ctx, _data, _env| {
-        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
-        let (env, _) = hklm
-            .create_subkey("System\\CurrentControlSet\\Control\\Session Manager\\Environment")
-            .unwrap();
-
-        let origin_dir: std::result::Result<String, std::io::Error> =
-            env.get_value("BETTERNCM_PROFILE");
-        let origin_dir = origin_dir.unwrap_or("C:\\betterncm".to_string());
-
-        let folder = rfd::FileDialog::new()
-            .set_directory(origin_dir)
-            .pick_folder();
-        if let Some(path) = folder {
-            env.set_value(
-                "BETTERNCM_PROFILE",
-                &path.to_str().unwrap_or("C:\\betterncm"),
+    Flex::column()
+        .with_child(title)
+        .with_child(tips)
+        .with_spacer(5.)
+        .with_flex_child(
+            Scroll::new(
+                Flex::column()
+                    .with_child(steps)
+                    .with_child(notes_panel)
+                    .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start),
             )
-            .unwrap();
+            .vertical()
+            .expand(),
+            1.,
+        )
+        .with_spacer(5.)
+        .with_child(
+            Flex::row()
+                .with_flex_child(button_cancel.expand_width(), 1.)
+                .with_spacer(5.)
+                .with_flex_child(button_confirm.expand_width(), 1.),
+        )
+        .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+        .padding(10.)
+}
 
-            let hkcu = RegKey::predef(HKEY_CURRENT_USER);
-            let (env, _) = hkcu.create_subkey("Environment").unwrap(); // create_subkey opens with write permissions
-            env.set_value(
-                "BETTERNCM_PROFILE",
-                &path.to_str().unwrap_or("C:\\betterncm"),
-            )
-            .unwrap();
+fn spawn_operation(
+    name: &'static str,
+    event_sink: ExtEventSink,
+    operation: impl FnOnce(&OperationReporter) -> Result<()> + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        tracing::info!(operation = name, "operation started");
+        let reporter = OperationReporter {
+            event_sink,
+            id: NEXT_OPERATION_ID.fetch_add(1, Ordering::Relaxed),
+            download_id: Mutex::new(None),
+        };
+        reporter.submit(NEW_PROGRESS, reporter.id);
+        reporter.submit(SET_MAX_PROGRESS, (reporter.id, 1.));
+        let result = operation(&reporter);
+        reporter.end_download();
+        reporter.submit(REMOVE_PROGRESS, reporter.id);
+        match result {
+            Ok(()) => tracing::info!(operation = name, "operation finished"),
+            Err(err) => {
+                tracing::error!(operation = name, error = ?err, "operation failed");
+                let tip_str = fill(msgs().operation_failed, &[("error", &err)]);
+                reporter.add_idle_callback(move |data: &mut AppData| {
+                    data.tips_string = tip_str;
+                });
+            }
         }
     });
+}
 
-    let button_reset_path = Button::new("重置数据地址")
-        .on_click(|_ctx, _data, _env| {
-            let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
-            let (env, _) = hklm
-                .create_subkey("System\\CurrentControlSet\\Control\\Session Manager\\Environment")
-                .unwrap();
-
-            env.delete_subkey("BETTERNCM_PROFILE");
-
-            let hkcu = RegKey::predef(HKEY_CURRENT_USER);
-            let (env, _) = hkcu.create_subkey("Environment").unwrap(); // create_subkey opens with write permissions
-            env.delete_subkey("BETTERNCM_PROFILE");
-        });
-
-    let button_set_ncm_path =
-        Button::new("手动指定网易云").on_click(|ctx, data: &mut AppData, _env| {
-            let files = rfd::FileDialog::new()
-                .add_filter("NCM Executable", &["exe"])
-                .pick_files();
-
-            if let Some(files) = files {
-                data.ncm = Ncm::get_ncm_by_path(files[0].parent().unwrap().to_path_buf()).ok();
-                let _ = get_adapted_betterncm_version(
-                    data.ncm.clone(),
-                    ctx.get_external_handle(),
-                    if data.prerelease { "test" } else { "versions" }.to_string(),
-                );
+fn run_plan(event_sink: ExtEventSink, plan: Plan) {
+    spawn_operation(plan.operation.name(), event_sink, move |reporter| {
+        let summary = operations::execute(&plan, reporter)?;
+        if plan.operation == Operation::MigrateLegacy {
+            // The migration edits user settings, so spell out everything it did
+            let tip = format!(
+                "{}\n{}\n{}",
+                plan.operation.success_tip(),
+                msgs().changes_made,
+                summary.changes.join("\n")
+            );
+            reporter.add_idle_callback(move |data: &mut AppData| data.tips_string = tip);
+        }
+        let replaces_dll = matches!(
+            plan.operation,
+            Operation::Install | Operation::Update | Operation::Uninstall
+        );
+        reporter.add_idle_callback(move |data: &mut AppData| {
+            if replaces_dll {
+                data.yanked = None;
             }
+            refresh_install_state(data);
         });
+        Ok(())
+    });
+}
 
-    let progress_bar = ProgressBar::new().lens(AppData::progress).expand_width();
-
-    WindowWidget::new(
-        "BetterNCM Installer",
-        Flex::column()
-            .with_child(title)
-            .with_child(installer_version_label)
-            .with_child(latest_version_label)
-            .with_child(install_path_label)
-            .with_child(local_version_label)
-            .with_spacer(5.)
-            .with_child(Label::new(|data: &AppData, _env: &_| -> String {
-                data.tips_string.clone()
-            }))
-            .with_flex_spacer(1.)
-            .with_child(checker_prerelease)
-            .with_spacer(5.)
-            .with_child(
-                Flex::row()
-                    .with_flex_child(button_install.expand_width(), 1.)
-                    .with_spacer(5.)
-                    .with_flex_child(button_reinstall.expand_width(), 1.)
-                    .with_spacer(5.)
-                    .with_flex_child(button_uninstall.expand_width(), 1.)
-                    .with_spacer(5.)
-                    .with_flex_child(button_uninstall_old.expand_width(), 1.),
-            )
-            .with_spacer(5.)
-            .with_child(
-                Flex::row()
-                    .with_flex_child(button_set_path.expand_width(), 1.)
-                    .with_spacer(5.)
-                    .with_flex_child(button_reset_path.expand_width(), 1.)
-                    .with_spacer(5.)
-                    .with_flex_child(button_set_ncm_path.expand_width(), 1.),
-            )
-            .with_spacer(5.)
-            .with_child(progress_bar)
-            .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
-            .padding(10.),
-    )
-    .on_notify(QUERY_CLOSE_WINDOW, |ctx, _, _| {
-        ctx.submit_command(CLOSE_ALL_WINDOWS);
-    })
+fn refresh_install_state(data: &mut AppData) {
+    data.old_version = data
+        .ncm
+        .as_ref()
+        .is_some_and(|ncm| ncm.path.join("cloudmusicn.exe").exists());
+    data.new_version = data
+        .ncm
+        .as_ref()
+        .is_some_and(|ncm| ncm.path.join("msimg32.dll").exists());
+    data.installed_version = data
+        .ncm
+        .as_ref()
+        .and_then(|ncm| get_file_version(&ncm.path.join("msimg32.dll")).ok());
+    data.conflicts = data
+        .ncm
+        .as_ref()
+        .map(|ncm| conflicts::scan(&ncm.path))
+        .unwrap_or_default();
+    data.plugins = status::list_plugins(&get_betterncm_profile_path());
 }
 
-fn download_file(url: &str, path: &str, event_sink: druid::ExtEventSink) {
-    let tip_str = format!("正在下载: {path}");
-    event_sink.add_idle_callback(move |data: &mut AppData| {
-        data.tips_string = tip_str;
-    });
-    use std::fs::File;
-    use std::io::Write;
+static NEXT_OPERATION_ID: AtomicUsize = AtomicUsize::new(0);
 
-    let res = tinyget::get(url)
-        .with_header(
-            "User-Agent",
-            &format!("BetterNCM Installer/{};", env!("CARGO_PKG_VERSION")),
-        )
-        .send_lazy()
-        .unwrap();
+/// Reports an operation both to the tips and to its entry in the [`ProgressOverlay`].
+struct OperationReporter {
+    event_sink: ExtEventSink,
+    id: usize,
+    /// Downloads get an entry of their own so their progress does not jump the step progress.
+    download_id: Mutex<Option<usize>>,
+}
 
-    let file_size = res
-        .headers
-        .get("content-length")
-        .map(|x| x.as_str().parse::<usize>())
-        .unwrap_or(Ok(0))
-        .unwrap_or(0);
+impl OperationReporter {
+    fn submit<T: Send + 'static>(&self, selector: druid::Selector<T>, payload: T) {
+        let _ = self
+            .event_sink
+            .submit_command(selector, payload, Target::Auto);
+    }
 
-    event_sink.add_idle_callback(move |data: &mut AppData| {
-        data.tips_string = "正在下载…".into();
-    });
+    fn download_id(&self) -> usize {
+        let mut download_id = self
+            .download_id
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        *download_id.get_or_insert_with(|| {
+            let id = NEXT_OPERATION_ID.fetch_add(1, Ordering::Relaxed);
+            self.submit(NEW_PROGRESS, id);
+            self.submit(SET_MAX_PROGRESS, (id, 1.));
+            id
+        })
+    }
 
-    let mut file = File::create(path)
-        .or(Err(format!("Failed to create file '{path}'")))
-        .unwrap();
-
-    let mut buf = Vec::with_capacity(file_size);
-    let mut tip_str = "正在下载…".to_string();
-    for data in res {
-        let (byte, length) = data.unwrap();
-        buf.reserve(length);
-        buf.push(byte);
-
-        let progress = buf.len() as f64 / file_size as f64;
-        let percent_progress = ((progress * 100.).floor() as u32).min(100).max(0);
-        let new_tip_str = format!("正在下载：{path}（{percent_progress}%）");
-        if tip_str != new_tip_str {
-            tip_str = new_tip_str.to_owned();
-            event_sink.add_idle_callback(move |data: &mut AppData| {
-                data.tips_string = new_tip_str;
-                data.progress = progress;
-            });
+    fn end_download(&self) {
+        let download_id = self
+            .download_id
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+        if let Some(id) = download_id {
+            self.submit(REMOVE_PROGRESS, id);
         }
     }
+}
 
-    file.write_all(&buf).unwrap();
+impl Deref for OperationReporter {
+    type Target = ExtEventSink;
 
-    event_sink.add_idle_callback(move |data: &mut AppData| {
-        data.tips_string = "".to_string();
-    });
+    fn deref(&self) -> &ExtEventSink {
+        &self.event_sink
+    }
 }
 
-pub fn install_vc_redist_14(event_sink: druid::ExtEventSink) {
-    if is_vc_redist_14_x86_installed() && is_vc_redist_14_x64_installed() {
-        return;
+impl Reporter for OperationReporter {
+    fn tip(&self, tip: String) {
+        self.submit(SET_MESSAGE, (self.id, tip.clone()));
+        self.add_idle_callback(move |data: &mut AppData| {
+            data.tips_string = tip;
+        });
     }
-    // https://aka.ms/vs/17/release/VC_redist.x86.exe
-    // Install: /install /passive /norestart
-    // SilentInstall: /install /quiet /norestart
 
-    let install_url = |url: &str| {
-        download_file(url, "VC_redist.exe", event_sink.to_owned());
+    fn progress(&self, progress: f64) {
+        self.submit(SET_PROGRESS, (self.id, progress));
+    }
 
-        event_sink.add_idle_callback(move |data: &mut AppData| {
-            data.tips_string = "正在安装 VC 运行时…".into();
-            data.progress = 1.;
+    fn download(&self, progress: &DownloadProgress) {
+        let tip = progress.to_string();
+        if progress.finished {
+            self.end_download();
+        } else {
+            let id = self.download_id();
+            self.submit(SET_MESSAGE, (id, tip.clone()));
+            match progress.fraction() {
+                Some(fraction) => self.submit(SET_PROGRESS, (id, fraction)),
+                None => self.submit(HIDE_PROGRESS, id),
+            }
+        }
+        self.add_idle_callback(move |data: &mut AppData| {
+            data.tips_string = tip;
         });
-
-        let _ = Command::new("VC_redist.exe")
-            .args(["/install", "/quiet", "/norestart"])
-            .creation_flags(0x08000000)
-            .status()
-            .unwrap()
-            .success();
-    };
-
-    install_url("https://aka.ms/vs/17/release/VC_redist.x86.exe");
-    install_url("https://aka.ms/vs/17/release/VC_redist.x64.exe");
+    }
 }
@@ -0,0 +1,658 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use crate::bundle::{self, OfflineBundle};
+use crate::ncm_utils::{Ncm, NcmType};
+use crate::network;
+use crate::payload::{PayloadFile, PayloadRoot};
+use crate::settings::installer_data_dir;
+use crate::vc_runtime;
+
+pub const MANIFEST_URL: &str =
+    "https://gitcode.net/qq_21551787/bncm-data-pack2/-/raw/master/betterncm/betterncm3.json";
+
+/// Replaces [`MANIFEST_URL`] when a policy points the installer at a mirror.
+static MANIFEST_MIRROR: RwLock<Option<String>> = RwLock::new(None);
+
+pub fn set_mirror(url: Option<String>) {
+    *MANIFEST_MIRROR
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = url;
+}
+
+pub fn manifest_url() -> String {
+    MANIFEST_MIRROR
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone()
+        .unwrap_or_else(|| MANIFEST_URL.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Stable,
+    Test,
+}
+
+impl Channel {
+    pub fn other(self) -> Channel {
+        match self {
+            Channel::Stable => Channel::Test,
+            Channel::Test => Channel::Stable,
+        }
+    }
+
+    pub fn from_prerelease(prerelease: bool) -> Channel {
+        if prerelease {
+            Channel::Test
+        } else {
+            Channel::Stable
+        }
+    }
+
+    pub fn key(self) -> &'static str {
+        match self {
+            Channel::Stable => "versions",
+            Channel::Test => "test",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub version: Version,
+    pub url_x86: String,
+    pub url_x64: String,
+    #[serde(default)]
+    pub sha256_x86: Option<String>,
+    #[serde(default)]
+    pub sha256_x64: Option<String>,
+    /// More places serving the same file as `url_x86`, checked against the same hash.
+    #[serde(default)]
+    pub mirrors_x86: Vec<String>,
+    #[serde(default)]
+    pub mirrors_x64: Vec<String>,
+    /// Withdrawn builds stay listed so installed copies can be recognised, but are never chosen.
+    #[serde(default)]
+    pub yanked: bool,
+    /// Why the build was withdrawn or what users of it should know.
+    #[serde(default)]
+    pub advisory: Option<String>,
+    /// Release notes in Markdown.
+    #[serde(default)]
+    pub changelog: Option<String>,
+    /// Where to fetch the release notes from when they are not inlined in `changelog`.
+    #[serde(default)]
+    pub changelog_url: Option<String>,
+    /// Set when `url_x86` and `url_x64` are zip archives instead of the DLL, mapping their
+    /// entries to the NCM directory or the profile. `sha256_*` then hash the archives.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<PayloadFile>,
+}
+
+impl ManifestEntry {
+    pub fn url_for(&self, ncm_type: &NcmType) -> &str {
+        match ncm_type {
+            NcmType::X86 => &self.url_x86,
+            NcmType::X64 => &self.url_x64,
+        }
+    }
+
+    /// Mirrors are only as trustworthy as the hash they are checked against, so they are left
+    /// out for an architecture without `sha256_*`.
+    pub fn mirrors_for(&self, ncm_type: &NcmType) -> &[String] {
+        if self.sha256_for(ncm_type).is_none() {
+            return &[];
+        }
+        match ncm_type {
+            NcmType::X86 => &self.mirrors_x86,
+            NcmType::X64 => &self.mirrors_x64,
+        }
+    }
+
+    pub fn sha256_for(&self, ncm_type: &NcmType) -> Option<&str> {
+        match ncm_type {
+            NcmType::X86 => self.sha256_x86.as_deref(),
+            NcmType::X64 => self.sha256_x64.as_deref(),
+        }
+    }
+
+    pub fn is_archive(&self) -> bool {
+        !self.files.is_empty()
+    }
+
+    /// The expected hash of the installed `msimg32.dll`, if the manifest has one.
+    pub fn dll_sha256(&self, ncm_type: &NcmType) -> Option<&str> {
+        if !self.is_archive() {
+            return self.sha256_for(ncm_type);
+        }
+        self.files
+            .iter()
+            .find(|file| file.to == PayloadRoot::Ncm && file.path == Path::new("msimg32.dll"))
+            .and_then(|file| file.sha256.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstallerRelease {
+    pub version: Version,
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Manifests that rely on fields older installers would silently ignore set this,
+    /// so outdated installers refuse them instead of installing the wrong thing.
+    #[serde(default)]
+    pub min_installer_version: Option<Version>,
+    #[serde(default)]
+    pub installer: Option<InstallerRelease>,
+    #[serde(default)]
+    pub versions: BTreeMap<String, ManifestEntry>,
+    #[serde(default)]
+    pub test: BTreeMap<String, ManifestEntry>,
+    /// Extra places to download the VC runtime from besides the `aka.ms` links.
+    #[serde(default)]
+    pub vc_redist_mirrors: VcRedistMirrors,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VcRedistMirrors {
+    #[serde(default)]
+    pub x86: Vec<String>,
+    #[serde(default)]
+    pub x64: Vec<String>,
+    /// The build the mirrors serve. Mirrors of an architecture without a hash are not used.
+    #[serde(default)]
+    pub sha256_x86: Option<String>,
+    #[serde(default)]
+    pub sha256_x64: Option<String>,
+}
+
+impl Manifest {
+    pub fn parse(content: &str) -> Result<Manifest> {
+        serde_json::from_str(content).context("Invalid manifest")
+    }
+
+    pub fn channel(&self, channel: Channel) -> &BTreeMap<String, ManifestEntry> {
+        match channel {
+            Channel::Stable => &self.versions,
+            Channel::Test => &self.test,
+        }
+    }
+
+    pub fn is_supported_by(&self, installer_version: &Version) -> bool {
+        self.min_installer_version
+            .as_ref()
+            .is_none_or(|min| installer_version >= min)
+    }
+
+    pub fn newer_installer(&self, installer_version: &Version) -> Option<&InstallerRelease> {
+        self.installer
+            .as_ref()
+            .filter(|release| &release.version > installer_version)
+    }
+
+    /// Finds the entry of an exact BetterNCM version in either channel.
+    pub fn find_version(&self, version: &Version) -> Option<&ManifestEntry> {
+        self.versions
+            .values()
+            .chain(self.test.values())
+            .find(|entry| &entry.version == version)
+    }
+
+    /// Entries newer than `installed` up to and including `target`, newest first, one per version.
+    /// Without an installed version only `target` itself is returned.
+    pub fn versions_between(
+        &self,
+        installed: Option<&Version>,
+        target: &Version,
+    ) -> Vec<&ManifestEntry> {
+        let mut entries: Vec<&ManifestEntry> = self
+            .versions
+            .values()
+            .chain(self.test.values())
+            .filter(|entry| match installed {
+                Some(installed) => &entry.version > installed && &entry.version <= target,
+                None => &entry.version == target,
+            })
+            .collect();
+        entries.sort_by(|a, b| b.version.cmp(&a.version));
+        entries.dedup_by(|a, b| a.version == b.version);
+        entries
+    }
+
+    /// Returns the entry of `version` if that build has been yanked.
+    pub fn yanked(&self, version: &Version) -> Option<&ManifestEntry> {
+        self.find_version(version).filter(|entry| entry.yanked)
+    }
+
+    pub fn find_adapted(
+        &self,
+        channel: Channel,
+        ncm: &Ncm,
+    ) -> Result<Option<(&str, &ManifestEntry)>> {
+        self.find_adapted_to(channel, &ncm.version)
+    }
+
+    /// Finds the first non-yanked entry whose range covers `ncm_version`.
+    pub fn find_adapted_to(
+        &self,
+        channel: Channel,
+        ncm_version: &Version,
+    ) -> Result<Option<(&str, &ManifestEntry)>> {
+        for (version_req, entry) in self.channel(channel) {
+            if entry.yanked {
+                continue;
+            }
+            if VersionReq::parse(version_req)
+                .context("Failed to parse version req")?
+                .matches(ncm_version)
+            {
+                return Ok(Some((version_req, entry)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like [`Manifest::find_adapted`], but only accepts `pinned` when it is given.
+    /// Yanked builds are skipped either way.
+    pub fn resolve(
+        &self,
+        channel: Channel,
+        ncm: &Ncm,
+        pinned: Option<&Version>,
+    ) -> Result<Option<(&str, &ManifestEntry)>> {
+        let pinned = match pinned {
+            Some(pinned) => pinned,
+            None => return self.find_adapted(channel, ncm),
+        };
+        for (version_req, entry) in self.channel(channel) {
+            if &entry.version == pinned
+                && !entry.yanked
+                && VersionReq::parse(version_req)
+                    .context("Failed to parse version req")?
+                    .matches(&ncm.version)
+            {
+                return Ok(Some((version_req, entry)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub fn installer_version() -> Version {
+    Version::parse(env!("CARGO_PKG_VERSION")).expect("Invalid package version")
+}
+
+/// Applies to the whole request, so a dead mirror cannot leave the window on "获取中..." forever.
+pub const FETCH_TIMEOUT_SECS: u64 = 15;
+
+/// The last good manifest, kept so the installer still works offline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestCache {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Seconds since the Unix epoch.
+    fetched_at: u64,
+    content: String,
+}
+
+fn cache_path() -> PathBuf {
+    installer_data_dir().join("manifest_cache.json")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl ManifestCache {
+    fn load(url: &str) -> Option<ManifestCache> {
+        let content = fs::read_to_string(cache_path()).ok()?;
+        serde_json::from_str::<ManifestCache>(&content)
+            .ok()
+            .filter(|cache| cache.url == url)
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(installer_data_dir())?;
+        fs::write(cache_path(), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestSource {
+    Network,
+    /// The server answered 304, so the cached copy is current.
+    NotModified,
+    /// The server could not be reached and this is the copy cached at `fetched_at`.
+    Stale {
+        fetched_at: u64,
+    },
+    Bundle,
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchedManifest {
+    pub manifest: Manifest,
+    pub raw: String,
+    pub source: ManifestSource,
+}
+
+impl FetchedManifest {
+    /// How old the manifest is in seconds when it had to be taken from the cache.
+    pub fn stale_age(&self) -> Option<u64> {
+        match self.source {
+            ManifestSource::Stale { fetched_at } => Some(now().saturating_sub(fetched_at)),
+            _ => None,
+        }
+    }
+}
+
+/// Downloads the manifest, or returns `None` when the cached copy is still current.
+fn download(url: &str, cache: Option<&ManifestCache>) -> Result<Option<ManifestCache>> {
+    let response = network::get(url, |mut request| {
+        request = request.timeout(network::timeout_or(FETCH_TIMEOUT_SECS));
+        // Some mirrors turn away unknown clients, so look like a browser unless told otherwise
+        if network::user_agent().is_none() {
+            request = request.set("User-Agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/115.0.0.0 Safari/537.36");
+        }
+        if let Some(cache) = cache {
+            if let Some(etag) = &cache.etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &cache.last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+        request
+    })
+    .context("Failed to fetch the manifest")?;
+    let status = response.status();
+    let etag = response.header("etag").map(str::to_string);
+    let last_modified = response.header("last-modified").map(str::to_string);
+    let content = response.into_string()?;
+    tracing::info!(status, size = content.len(), "fetched manifest");
+    if status == 304 && cache.is_some() {
+        return Ok(None);
+    }
+    // Only a manifest that parses may replace the cached one
+    Manifest::parse(&content)?;
+    Ok(Some(ManifestCache {
+        url: url.to_string(),
+        etag,
+        last_modified,
+        fetched_at: now(),
+        content,
+    }))
+}
+
+pub fn load_from_bundle(bundle: &OfflineBundle) -> Result<FetchedManifest> {
+    tracing::info!(root = %bundle.root.display(), "reading manifest from offline bundle");
+    let content = bundle.manifest()?;
+    Ok(FetchedManifest {
+        manifest: Manifest::parse(&content)?,
+        raw: content,
+        source: ManifestSource::Bundle,
+    })
+}
+
+/// Picks the manifest to use from the outcome of `download` and what was cached before.
+fn settle(
+    downloaded: Result<Option<ManifestCache>>,
+    cache: Option<ManifestCache>,
+) -> Result<(ManifestCache, ManifestSource)> {
+    match (downloaded, cache) {
+        (Ok(Some(fresh)), _) => Ok((fresh, ManifestSource::Network)),
+        (Ok(None), Some(mut cache)) => {
+            cache.fetched_at = now();
+            Ok((cache, ManifestSource::NotModified))
+        }
+        (Err(err), Some(cache)) => {
+            tracing::warn!(
+                error = ?err,
+                fetched_at = cache.fetched_at,
+                "failed to fetch manifest, using the cached copy"
+            );
+            let fetched_at = cache.fetched_at;
+            Ok((cache, ManifestSource::Stale { fetched_at }))
+        }
+        (Err(err), None) => Err(err),
+        (Ok(None), None) => unreachable!("304 is only accepted with a cached manifest"),
+    }
+}
+
+pub fn fetch_with_source() -> Result<FetchedManifest> {
+    let fetched = load()?;
+    // VC runtime downloads are planned where the manifest is not at hand
+    vc_runtime::set_mirrors(fetched.manifest.vc_redist_mirrors.clone());
+    Ok(fetched)
+}
+
+fn load() -> Result<FetchedManifest> {
+    if let Some(bundle) = bundle::active() {
+        return load_from_bundle(bundle);
+    }
+    let url = manifest_url();
+    let cache = ManifestCache::load(&url);
+    tracing::info!(url = %url, cached = cache.is_some(), "fetching manifest");
+    let (cache, source) = settle(download(&url, cache.as_ref()), cache)?;
+    if !matches!(source, ManifestSource::Stale { .. }) {
+        if let Err(err) = cache.save() {
+            tracing::warn!(error = ?err, "failed to cache the manifest");
+        }
+    }
+    Ok(FetchedManifest {
+        manifest: Manifest::parse(&cache.content)?,
+        raw: cache.content,
+        source,
+    })
+}
+
+pub fn fetch() -> Result<(Manifest, String)> {
+    let fetched = fetch_with_source()?;
+    Ok((fetched.manifest, fetched.raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    fn manifest(json: serde_json::Value) -> Manifest {
+        Manifest::parse(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn offers_only_newer_installers() {
+        let manifest = manifest(serde_json::json!({
+            "installer": { "version": "1.2.0", "url": "https://example.com/i.exe", "sha256": "ab" }
+        }));
+        assert_eq!(
+            manifest
+                .newer_installer(&version("1.1.4"))
+                .map(|release| &release.version),
+            Some(&version("1.2.0"))
+        );
+        assert!(manifest.newer_installer(&version("1.2.0")).is_none());
+        assert!(manifest.newer_installer(&version("1.3.0")).is_none());
+        assert!(Manifest::default()
+            .newer_installer(&version("0.1.0"))
+            .is_none());
+    }
+
+    #[test]
+    fn checks_the_minimum_installer_version() {
+        let manifest = manifest(serde_json::json!({ "min_installer_version": "1.2.0" }));
+        assert!(!manifest.is_supported_by(&version("1.1.9")));
+        assert!(manifest.is_supported_by(&version("1.2.0")));
+        assert!(Manifest::default().is_supported_by(&version("0.0.1")));
+    }
+
+    fn entry(version: &str, yanked: bool) -> serde_json::Value {
+        serde_json::json!({
+            "version": version,
+            "url_x86": format!("https://example.com/{version}/x86.dll"),
+            "url_x64": format!("https://example.com/{version}/x64.dll"),
+            "yanked": yanked
+        })
+    }
+
+    fn ncm(version: &str) -> Ncm {
+        Ncm {
+            path: PathBuf::from(r"C:\Program Files\Netease\CloudMusic"),
+            version: Version::parse(version).unwrap(),
+            ncm_type: NcmType::X64,
+        }
+    }
+
+    #[test]
+    fn resolves_the_pinned_version_only() {
+        let manifest = manifest(serde_json::json!({
+            "versions": {
+                ">=2.10.0, <3.0.0": entry("1.2.0", false),
+                ">=2.9.0, <2.10.0": entry("1.1.0", false),
+                "*": entry("1.0.0", true)
+            }
+        }));
+        let adapted = |ncm_version: &str, pinned: Option<&str>| {
+            let pinned = pinned.map(version);
+            manifest
+                .resolve(Channel::Stable, &ncm(ncm_version), pinned.as_ref())
+                .unwrap()
+                .map(|(_, entry)| entry.version.to_string())
+        };
+        assert_eq!(adapted("2.10.3", None).as_deref(), Some("1.2.0"));
+        assert_eq!(adapted("2.10.3", Some("1.2.0")).as_deref(), Some("1.2.0"));
+        // A pinned version is never swapped for another one
+        assert_eq!(adapted("2.10.3", Some("1.1.0")), None);
+        assert_eq!(adapted("2.9.5", Some("1.1.0")).as_deref(), Some("1.1.0"));
+        // Yanked builds are skipped even when pinned
+        assert_eq!(adapted("3.1.0", Some("1.0.0")), None);
+        assert_eq!(adapted("3.1.0", None), None);
+    }
+
+    #[test]
+    fn skips_yanked_builds() {
+        let mut yanked = entry("1.0.0", true);
+        yanked["advisory"] = "Crashes NCM on start".into();
+        let manifest = manifest(serde_json::json!({
+            "versions": {
+                "*": yanked,
+                ">=2.10.0": entry("1.2.0", false)
+            },
+            "test": { "*": entry("1.3.0-beta", true) }
+        }));
+        let (_, entry) = manifest
+            .find_adapted(Channel::Stable, &ncm("2.10.3"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.version, version("1.2.0"));
+        assert!(manifest
+            .find_adapted(Channel::Stable, &ncm("2.9.0"))
+            .unwrap()
+            .is_none());
+        assert!(manifest
+            .find_adapted(Channel::Test, &ncm("2.10.3"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn finds_yanked_builds_in_either_channel() {
+        let manifest = manifest(serde_json::json!({
+            "versions": { "*": entry("1.2.0", false), "<2.0.0": entry("1.0.0", true) },
+            "test": { "*": entry("1.3.0-beta", true) }
+        }));
+        assert!(manifest.yanked(&version("1.2.0")).is_none());
+        assert!(manifest.yanked(&version("1.0.0")).is_some());
+        assert!(manifest.yanked(&version("1.3.0-beta")).is_some());
+        // Versions the manifest does not know are not reported as yanked
+        assert!(manifest.yanked(&version("0.9.0")).is_none());
+    }
+
+    #[test]
+    fn uses_mirrors_only_with_a_hash() {
+        let mut json = entry("1.2.0", false);
+        json["mirrors_x86"] = serde_json::json!(["https://mirror.example/x86.dll"]);
+        json["mirrors_x64"] = serde_json::json!(["https://mirror.example/x64.dll"]);
+        json["sha256_x64"] = "ab".into();
+        let entry: ManifestEntry = serde_json::from_value(json).unwrap();
+        assert!(entry.mirrors_for(&NcmType::X86).is_empty());
+        assert_eq!(
+            entry.mirrors_for(&NcmType::X64),
+            ["https://mirror.example/x64.dll"]
+        );
+    }
+
+    fn cached(content: &str, fetched_at: u64) -> ManifestCache {
+        ManifestCache {
+            url: MANIFEST_URL.to_string(),
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+            fetched_at,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn prefers_a_fresh_manifest() {
+        let (cache, source) = settle(
+            Ok(Some(cached("{\"new\":1}", now()))),
+            Some(cached("{}", 1)),
+        )
+        .unwrap();
+        assert_eq!(source, ManifestSource::Network);
+        assert_eq!(cache.content, "{\"new\":1}");
+    }
+
+    #[test]
+    fn keeps_the_cache_when_not_modified() {
+        let (cache, source) = settle(Ok(None), Some(cached("{}", 1))).unwrap();
+        assert_eq!(source, ManifestSource::NotModified);
+        assert_eq!(cache.content, "{}");
+        // Revalidated just now, so it is not reported as stale later
+        assert!(cache.fetched_at > 1);
+    }
+
+    #[test]
+    fn falls_back_to_the_cache_when_offline() {
+        let offline = || Err(anyhow::anyhow!("offline"));
+        let (cache, source) = settle(offline(), Some(cached("{}", 1))).unwrap();
+        assert_eq!(source, ManifestSource::Stale { fetched_at: 1 });
+        let fetched = FetchedManifest {
+            manifest: Manifest::parse(&cache.content).unwrap(),
+            raw: cache.content,
+            source,
+        };
+        assert!(fetched.stale_age().unwrap() > 0);
+        assert!(settle(offline(), None).is_err());
+    }
+
+    #[test]
+    fn cache_files_round_trip() {
+        let cache = cached("{}", 42);
+        let json = serde_json::to_string(&cache).unwrap();
+        let loaded: ManifestCache = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.etag, cache.etag);
+        assert_eq!(loaded.fetched_at, 42);
+        assert_eq!(loaded.content, "{}");
+    }
+}
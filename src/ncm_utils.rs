@@ -6,16 +6,18 @@ use anyhow::*;
 use pelite::pe64::Pe;
 use pelite::resources::version_info::VersionInfo;
 use semver::{BuildMetadata, Prerelease, Version};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use winreg::enums::*;
 use winreg::RegKey;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NcmType {
     X86,
     X64,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Ncm {
     // Install dir
     pub path: PathBuf,
@@ -37,16 +39,27 @@ pub fn get_ncm_install_path() -> Result<PathBuf> {
     }
 }
 
-pub fn is_vc_redist_14_x86_installed() -> bool {
-    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
-    hklm.open_subkey("SOFTWARE\\WOW6432Node\\Microsoft\\VisualStudio\\14.0\\VC\\Runtimes\\X86")
-        .is_ok()
-}
+pub fn get_betterncm_profile_path() -> PathBuf {
+    // The process environment is stale after the data path is changed, so read the registry first
+    let from_registry = || -> Result<String> {
+        let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+        if let std::result::Result::Ok(path) = hkcu
+            .open_subkey("Environment")
+            .and_then(|env| env.get_value("BETTERNCM_PROFILE"))
+        {
+            return Ok(path);
+        }
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        Ok(hklm
+            .open_subkey("System\\CurrentControlSet\\Control\\Session Manager\\Environment")?
+            .get_value("BETTERNCM_PROFILE")?)
+    };
 
-pub fn is_vc_redist_14_x64_installed() -> bool {
-    let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
-    hklm.open_subkey(r"SOFTWARE\Microsoft\VisualStudio\14.0\VC\Runtimes\x64")
-        .is_ok()
+    from_registry()
+        .ok()
+        .or_else(|| std::env::var("BETTERNCM_PROFILE").ok())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("C:\\betterncm"))
 }
 
 impl Ncm {
@@ -89,3 +102,36 @@ impl Ncm {
         }
     }
 }
+
+pub fn get_file_version(path: &Path) -> Result<Version> {
+    use pelite::pe::Pe;
+    use pelite::pe32::PeFile as PeFile32;
+    use pelite::pe64::PeFile as PeFile64;
+    use pelite::FileMap;
+
+    let map = FileMap::open(path)?;
+    let version_info = match PeFile32::from_bytes(&map) {
+        std::result::Result::Ok(file) => file.resources()?.version_info()?,
+        Err(_) => PeFile64::from_bytes(&map)?.resources()?.version_info()?,
+    };
+    let fixed = version_info
+        .file_info()
+        .fixed
+        .context("Empty file version")?;
+    Ok(Version::new(
+        fixed.dwFileVersion.Major as u64,
+        fixed.dwFileVersion.Minor as u64,
+        fixed.dwFileVersion.Patch as u64,
+    ))
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn sha256_bytes(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
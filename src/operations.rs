@@ -0,0 +1,1327 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::iter;
+use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE};
+use winreg::RegKey;
+
+use crate::download::{download_from_sources, DownloadProgress};
+use crate::i18n::{fill, msgs};
+use crate::localdata;
+use crate::lock::OperationLock;
+use crate::manifest::{Channel, ManifestEntry};
+use crate::ncm_utils::{get_betterncm_profile_path, sha256_file, Ncm, NcmType};
+use crate::payload::{self, PayloadFile, PayloadRoot};
+use crate::privilege::{self, InstallScope};
+use crate::receipt::{receipt_path, InstallReceipt};
+use crate::settings::installer_data_dir;
+use crate::vc_runtime::{self, VcInstallResult, VcRuntimeStatus};
+
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+/// Receives user-facing progress while a plan runs, either the GUI or the console.
+pub trait Reporter {
+    fn tip(&self, tip: String);
+    fn progress(&self, progress: f64);
+
+    fn download(&self, progress: &DownloadProgress) {
+        self.tip(progress.to_string());
+        if let Some(fraction) = progress.fraction() {
+            self.progress(fraction);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Install,
+    Update,
+    Uninstall,
+    MigrateLegacy,
+    RestoreLegacy,
+    SetProfile,
+    ResetProfile,
+    Deploy,
+    Repair,
+    Quarantine,
+}
+
+impl Operation {
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Install => "install",
+            Operation::Update => "update",
+            Operation::Uninstall => "uninstall",
+            Operation::MigrateLegacy => "migrate_legacy",
+            Operation::RestoreLegacy => "restore_legacy",
+            Operation::SetProfile => "set_profile",
+            Operation::ResetProfile => "reset_profile",
+            Operation::Deploy => "deploy",
+            Operation::Repair => "repair",
+            Operation::Quarantine => "quarantine",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Operation::Install => msgs().install,
+            Operation::Update => msgs().reinstall,
+            Operation::Uninstall => msgs().uninstall,
+            Operation::MigrateLegacy => msgs().uninstall_old,
+            Operation::RestoreLegacy => msgs().restore_legacy,
+            Operation::SetProfile => msgs().set_data_path,
+            Operation::ResetProfile => msgs().reset_data_path,
+            Operation::Deploy => msgs().deploy,
+            Operation::Repair => msgs().repair,
+            Operation::Quarantine => msgs().quarantine,
+        }
+    }
+
+    pub fn running_tip(self) -> &'static str {
+        match self {
+            Operation::Install => msgs().installing,
+            Operation::Update => msgs().reinstalling,
+            Operation::Uninstall => msgs().uninstalling,
+            Operation::MigrateLegacy => msgs().migrating_legacy,
+            Operation::RestoreLegacy => msgs().restoring_legacy,
+            Operation::SetProfile | Operation::ResetProfile => msgs().updating_profile,
+            Operation::Deploy => msgs().deploying,
+            Operation::Repair => msgs().repairing,
+            Operation::Quarantine => msgs().quarantining,
+        }
+    }
+
+    pub fn success_tip(self) -> &'static str {
+        match self {
+            Operation::Install => msgs().install_success,
+            Operation::Update => msgs().reinstall_success,
+            Operation::Uninstall => msgs().uninstall_success,
+            Operation::MigrateLegacy => msgs().legacy_migrated,
+            Operation::RestoreLegacy => msgs().legacy_restored,
+            Operation::SetProfile | Operation::ResetProfile => msgs().profile_updated,
+            Operation::Deploy => msgs().deploy_success,
+            Operation::Repair => msgs().repair_success,
+            Operation::Quarantine => msgs().quarantine_success,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegScope {
+    Machine,
+    User,
+}
+
+impl RegScope {
+    fn environment_key(self) -> &'static str {
+        match self {
+            RegScope::Machine => "System\\CurrentControlSet\\Control\\Session Manager\\Environment",
+            RegScope::User => "Environment",
+        }
+    }
+
+    fn root(self) -> RegKey {
+        match self {
+            RegScope::Machine => RegKey::predef(HKEY_LOCAL_MACHINE),
+            RegScope::User => RegKey::predef(HKEY_CURRENT_USER),
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            RegScope::Machine => "HKLM",
+            RegScope::User => "HKCU",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Step {
+    KillProcess {
+        name: String,
+    },
+    Download {
+        url: String,
+        /// Other places serving the same file; the fastest source is used.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mirrors: Vec<String>,
+        dest: PathBuf,
+    },
+    VerifyFile {
+        path: PathBuf,
+        sha256: String,
+    },
+    InstallVcRuntime {
+        arch: NcmType,
+        installer: PathBuf,
+    },
+    CopyFile {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    CopyDir {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Installs the mapped entries of a zip payload all at once.
+    ExtractArchive {
+        archive: PathBuf,
+        files: Vec<PayloadFile>,
+        ncm_dir: PathBuf,
+        profile_dir: PathBuf,
+    },
+    RenameFile {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    DeleteFile {
+        path: PathBuf,
+    },
+    DeleteDir {
+        path: PathBuf,
+    },
+    CreateDir {
+        path: PathBuf,
+    },
+    /// Removes only the proxy settings from NCM's `localdata`.
+    ResetNcmProxy {
+        path: PathBuf,
+    },
+    SetEnvironmentValue {
+        scope: RegScope,
+        name: String,
+        value: String,
+    },
+    DeleteEnvironmentValue {
+        scope: RegScope,
+        name: String,
+    },
+    LaunchNcm {
+        path: PathBuf,
+    },
+    RecordInstall {
+        receipt: InstallReceipt,
+    },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = msgs();
+        let text = match self {
+            Step::KillProcess { name } => fill(m.step_kill_process, &[("name", name)]),
+            Step::Download { url, mirrors, dest } if !mirrors.is_empty() => fill(
+                m.step_download_mirrors,
+                &[
+                    ("url", url),
+                    ("count", &(mirrors.len() + 1)),
+                    ("path", &dest.display()),
+                ],
+            ),
+            Step::Download { url, dest, .. } => {
+                fill(m.step_download, &[("url", url), ("path", &dest.display())])
+            }
+            Step::VerifyFile { path, .. } => fill(m.step_verify_file, &[("path", &path.display())]),
+            Step::InstallVcRuntime { arch, installer } => fill(
+                m.step_install_vc_runtime,
+                &[
+                    ("arch", &format!("{arch:?}").to_lowercase()),
+                    ("path", &installer.display()),
+                ],
+            ),
+            Step::CopyFile { from, to } => fill(
+                m.step_copy_file,
+                &[("from", &from.display()), ("to", &to.display())],
+            ),
+            Step::CopyDir { from, to } => fill(
+                m.step_copy_dir,
+                &[("from", &from.display()), ("to", &to.display())],
+            ),
+            Step::ExtractArchive { archive, files, .. } => fill(
+                m.step_extract_archive,
+                &[("path", &archive.display()), ("count", &files.len())],
+            ),
+            Step::RenameFile { from, to } => fill(
+                m.step_rename_file,
+                &[("from", &from.display()), ("to", &to.display())],
+            ),
+            Step::DeleteFile { path } => fill(m.step_delete_file, &[("path", &path.display())]),
+            Step::DeleteDir { path } => fill(m.step_delete_dir, &[("path", &path.display())]),
+            Step::CreateDir { path } => fill(m.step_create_dir, &[("path", &path.display())]),
+            Step::ResetNcmProxy { path } => fill(m.step_reset_proxy, &[("path", &path.display())]),
+            Step::SetEnvironmentValue { scope, name, value } => fill(
+                m.step_set_registry,
+                &[
+                    (
+                        "key",
+                        &format!("{}\\{}", scope.prefix(), scope.environment_key()),
+                    ),
+                    ("name", name),
+                    ("value", value),
+                ],
+            ),
+            Step::DeleteEnvironmentValue { scope, name } => fill(
+                m.step_delete_registry,
+                &[
+                    (
+                        "key",
+                        &format!("{}\\{}", scope.prefix(), scope.environment_key()),
+                    ),
+                    ("name", name),
+                ],
+            ),
+            Step::LaunchNcm { path } => fill(m.step_launch_ncm, &[("path", &path.display())]),
+            Step::RecordInstall { receipt } => fill(
+                m.step_record_install,
+                &[
+                    ("version", &receipt.betterncm_version),
+                    ("ncm_version", &receipt.ncm_version),
+                ],
+            ),
+        };
+        f.write_str(&text)
+    }
+}
+
+impl Step {
+    /// Whether the step fails without administrator rights.
+    fn needs_elevation(&self) -> bool {
+        match self {
+            Step::InstallVcRuntime { .. } => true,
+            Step::CopyFile { to, .. } | Step::CopyDir { to, .. } => !privilege::path_writable(to),
+            Step::ExtractArchive {
+                files,
+                ncm_dir,
+                profile_dir,
+                ..
+            } => files.iter().any(|file| match file.to {
+                PayloadRoot::Ncm => !privilege::dir_writable(ncm_dir),
+                PayloadRoot::Profile => !privilege::dir_writable(profile_dir),
+            }),
+            Step::RenameFile { from, to } => {
+                !privilege::path_writable(from) || !privilege::path_writable(to)
+            }
+            Step::DeleteFile { path }
+            | Step::DeleteDir { path }
+            | Step::CreateDir { path }
+            | Step::ResetNcmProxy { path } => !privilege::path_writable(path),
+            Step::SetEnvironmentValue { scope, .. }
+            | Step::DeleteEnvironmentValue { scope, .. } => {
+                *scope == RegScope::Machine && !privilege::machine_env_writable()
+            }
+            _ => false,
+        }
+    }
+
+    /// Steps that have to stay in the user's own process even next to elevated ones.
+    ///
+    /// An administrator approving the prompt may be a different account, whose HKCU, profile
+    /// and temporary files are not the user's. Downloads stay here to keep showing progress.
+    fn stays_unelevated(&self) -> bool {
+        matches!(
+            self,
+            Step::Download { .. }
+                | Step::SetEnvironmentValue {
+                    scope: RegScope::User,
+                    ..
+                }
+                | Step::DeleteEnvironmentValue {
+                    scope: RegScope::User,
+                    ..
+                }
+                | Step::LaunchNcm { .. }
+                | Step::RecordInstall { .. }
+        )
+    }
+
+    /// A file the step reads and runs or installs from.
+    fn input(&self) -> Option<&Path> {
+        match self {
+            Step::InstallVcRuntime { installer, .. } => Some(installer),
+            Step::CopyFile { from, .. } => Some(from),
+            Step::ExtractArchive { archive, .. } => Some(archive),
+            _ => None,
+        }
+    }
+
+    /// The step reading `input` instead of its own [`Step::input`].
+    fn with_input(&self, input: PathBuf) -> Step {
+        let mut step = self.clone();
+        match &mut step {
+            Step::InstallVcRuntime { installer, .. } => *installer = input,
+            Step::CopyFile { from, .. } => *from = input,
+            Step::ExtractArchive { archive, .. } => *archive = input,
+            _ => {}
+        }
+        step
+    }
+
+    fn run(&self, reporter: &dyn Reporter, summary: &mut ExecutionSummary) -> Result<()> {
+        match self {
+            Step::KillProcess { name } => {
+                let status = Command::new("taskkill.exe")
+                    .args(["/f", "/im", name])
+                    .creation_flags(CREATE_NO_WINDOW)
+                    .spawn()?
+                    .wait()?;
+                tracing::info!(process = %name, exit_code = ?status.code(), "killed process");
+                // Give NCM a moment to release its files
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            Step::Download { url, mirrors, dest } => {
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let _ = fs::remove_file(dest);
+                let urls: Vec<String> = iter::once(url).chain(mirrors).cloned().collect();
+                download_from_sources(&urls, dest, reporter)?;
+            }
+            Step::VerifyFile { path, sha256 } => {
+                let actual = sha256_file(path)?;
+                if !actual.eq_ignore_ascii_case(sha256) {
+                    bail!(
+                        "Checksum mismatch for {}: expected {sha256}, got {actual}",
+                        path.display()
+                    );
+                }
+                tracing::info!(path = %path.display(), sha256 = %actual, "verified file");
+            }
+            Step::InstallVcRuntime { arch, installer } => {
+                // Install: /install /passive /norestart
+                // SilentInstall: /install /quiet /norestart
+                let status = Command::new(installer)
+                    .args(["/install", "/quiet", "/norestart"])
+                    .creation_flags(CREATE_NO_WINDOW)
+                    .status()
+                    .with_context(|| format!("Failed to run {}", installer.display()))?;
+                let result = vc_runtime::interpret_exit_code(status.code());
+                tracing::info!(
+                    arch = ?arch,
+                    exit_code = ?status.code(),
+                    result = ?result,
+                    detected = ?vc_runtime::detect(arch),
+                    "ran VC runtime installer"
+                );
+                if result? == VcInstallResult::RebootRequired {
+                    summary.reboot_required = true;
+                }
+            }
+            Step::CopyFile { from, to } => {
+                if let Some(parent) = to.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let size = fs::copy(from, to).with_context(|| {
+                    format!("Failed to copy {} to {}", from.display(), to.display())
+                })?;
+                tracing::info!(from = %from.display(), to = %to.display(), size, "copied file");
+            }
+            Step::CopyDir { from, to } => {
+                copy_dir(from, to).with_context(|| {
+                    format!("Failed to copy {} to {}", from.display(), to.display())
+                })?;
+                tracing::info!(from = %from.display(), to = %to.display(), "copied directory");
+            }
+            Step::ExtractArchive {
+                archive,
+                files,
+                ncm_dir,
+                profile_dir,
+            } => {
+                let installed = payload::extract(archive, files, ncm_dir, profile_dir)?;
+                tracing::info!(
+                    archive = %archive.display(),
+                    files = ?installed,
+                    "extracted archive"
+                );
+            }
+            Step::RenameFile { from, to } => {
+                fs::rename(from, to).with_context(|| {
+                    format!("Failed to rename {} to {}", from.display(), to.display())
+                })?;
+                tracing::info!(from = %from.display(), to = %to.display(), "renamed file");
+            }
+            Step::DeleteFile { path } => {
+                fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                tracing::info!(path = %path.display(), "removed file");
+            }
+            Step::DeleteDir { path } => {
+                fs::remove_dir_all(path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                tracing::info!(path = %path.display(), "removed directory");
+            }
+            Step::CreateDir { path } => {
+                fs::create_dir_all(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                tracing::info!(path = %path.display(), "created directory");
+            }
+            Step::ResetNcmProxy { path } => {
+                let contents =
+                    fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+                let change = match localdata::remove_proxy(&contents) {
+                    Ok((_, removed)) if removed.is_empty() => {
+                        fill(msgs().proxy_not_found, &[("path", &path.display())])
+                    }
+                    Ok((contents, removed)) => {
+                        fs::write(path, contents)
+                            .with_context(|| format!("Failed to write {}", path.display()))?;
+                        fill(
+                            msgs().proxy_removed,
+                            &[("path", &path.display()), ("keys", &removed.join(", "))],
+                        )
+                    }
+                    // Overwriting the file would lose every other NCM setting, and stopping here
+                    // would leave the migration half done
+                    Err(err) => {
+                        tracing::warn!(error = ?err, path = %path.display(), "bad localdata");
+                        fill(msgs().proxy_left_unchanged, &[("path", &path.display())])
+                    }
+                };
+                tracing::info!(path = %path.display(), change = %change, "reset NCM proxy");
+                summary.changes.push(change);
+            }
+            Step::SetEnvironmentValue { scope, name, value } => {
+                // create_subkey opens with write permissions
+                let (env, _) = scope.root().create_subkey(scope.environment_key())?;
+                env.set_value(name, value)?;
+                tracing::info!(scope = scope.prefix(), name = %name, value = %value, "wrote registry value");
+            }
+            Step::DeleteEnvironmentValue { scope, name } => {
+                let (env, _) = scope.root().create_subkey(scope.environment_key())?;
+                match env.delete_value(name) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+                tracing::info!(scope = scope.prefix(), name = %name, "removed registry value");
+            }
+            Step::LaunchNcm { path } => {
+                tracing::info!(path = %path.display(), "launching NCM");
+                Command::new(path.join("cloudmusic.exe"))
+                    .current_dir(path)
+                    .spawn()?;
+            }
+            Step::RecordInstall { receipt } => {
+                receipt.save()?;
+                tracing::info!(receipt = ?receipt, "recorded install receipt");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Plan {
+    pub operation: Operation,
+    pub steps: Vec<Step>,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{}. {step}", index + 1)?;
+        }
+        Ok(())
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn is_process_running(name: &str) -> bool {
+    Command::new("tasklist.exe")
+        .args(["/fi", &format!("imagename eq {name}"), "/fo", "csv", "/nh"])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .to_lowercase()
+                .contains(&format!("\"{}\"", name.to_lowercase()))
+        })
+        .unwrap_or(false)
+}
+
+pub fn config_path() -> PathBuf {
+    dirs::home_dir().unwrap_or_default().join("betterncm")
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        // Windows paths are case-insensitive
+        _ => {
+            let trim = |path: &Path| {
+                path.to_string_lossy()
+                    .trim_end_matches(['\\', '/'])
+                    .to_lowercase()
+            };
+            trim(a) == trim(b)
+        }
+    }
+}
+
+/// Whether a v1 configuration directory is left over, and not the profile BetterNCM now uses.
+pub fn has_legacy_config() -> bool {
+    let config = config_path();
+    config.exists() && !same_dir(&config, &get_betterncm_profile_path())
+}
+
+fn get_ncm_localdata_path() -> PathBuf {
+    dirs::data_local_dir()
+        .unwrap_or_default()
+        .join("Netease")
+        .join("CloudMusic")
+}
+
+/// BetterNCM is loaded into NCM, so only the runtime matching NCM's architecture is needed.
+fn vc_redist_steps(status: &VcRuntimeStatus) -> Vec<Step> {
+    if status.is_sufficient() {
+        return vec![];
+    }
+    tracing::info!(status = ?status, "VC runtime is missing or outdated");
+    let arch = &status.arch;
+    let installer = env::temp_dir().join(format!("VC_redist.{arch:?}.exe").to_lowercase());
+    let source = vc_runtime::redist_source(arch);
+    let mut steps = vec![Step::Download {
+        url: source.url,
+        mirrors: source.mirrors,
+        dest: installer.clone(),
+    }];
+    if let Some(sha256) = source.sha256 {
+        steps.push(Step::VerifyFile {
+            path: installer.clone(),
+            sha256,
+        });
+    }
+    steps.push(Step::InstallVcRuntime {
+        arch: arch.clone(),
+        installer,
+    });
+    steps
+}
+
+/// Plans an install or an update, which only differ in how they are presented.
+///
+/// Payload files that belong in the profile go to `profile_dir`, which is the profile the
+/// install is for rather than the one in effect while planning.
+pub fn plan_install(
+    operation: Operation,
+    ncm: &Ncm,
+    channel: Channel,
+    entry: &ManifestEntry,
+    profile_dir: &Path,
+) -> Plan {
+    let vc_runtime = vc_runtime::detect(&ncm.ncm_type);
+    plan_install_for(operation, ncm, channel, entry, profile_dir, &vc_runtime)
+}
+
+fn plan_install_for(
+    operation: Operation,
+    ncm: &Ncm,
+    channel: Channel,
+    entry: &ManifestEntry,
+    profile_dir: &Path,
+    vc_runtime: &VcRuntimeStatus,
+) -> Plan {
+    let artifact = env::temp_dir().join(if entry.is_archive() {
+        "betterncm.zip"
+    } else {
+        "betterncm.dll"
+    });
+    let mut steps = vec![Step::Download {
+        url: entry.url_for(&ncm.ncm_type).to_string(),
+        mirrors: entry.mirrors_for(&ncm.ncm_type).to_vec(),
+        dest: artifact.clone(),
+    }];
+    if let Some(sha256) = entry.sha256_for(&ncm.ncm_type) {
+        steps.push(Step::VerifyFile {
+            path: artifact.clone(),
+            sha256: sha256.to_string(),
+        });
+    }
+    steps.extend(vc_redist_steps(vc_runtime));
+    steps.push(Step::KillProcess {
+        name: "cloudmusic.exe".into(),
+    });
+    let mut receipt = InstallReceipt::new(ncm, channel, &entry.version);
+    if entry.is_archive() {
+        let previous = InstallReceipt::load()
+            .filter(|previous| previous.ncm_path == ncm.path)
+            .map(|previous| previous.files)
+            .unwrap_or_default();
+        receipt.files = payload_files(&entry.files, &ncm.path, profile_dir, &previous);
+        steps.push(Step::ExtractArchive {
+            archive: artifact,
+            files: entry.files.clone(),
+            ncm_dir: ncm.path.clone(),
+            profile_dir: profile_dir.to_path_buf(),
+        });
+        // Files an older version installed that this one no longer ships
+        steps.extend(
+            previous
+                .into_iter()
+                .filter(|path| !receipt.files.contains(path) && path.is_file())
+                .map(|path| Step::DeleteFile { path }),
+        );
+    } else {
+        steps.push(Step::CopyFile {
+            from: artifact,
+            to: ncm.path.join("msimg32.dll"),
+        });
+    }
+    steps.extend([
+        Step::RecordInstall { receipt },
+        Step::LaunchNcm {
+            path: ncm.path.clone(),
+        },
+    ]);
+    Plan { operation, steps }
+}
+
+/// The files an archive install owns afterwards, so uninstalling removes exactly those.
+///
+/// Files that are never overwritten and were there before belong to the user, not to us.
+fn payload_files(
+    files: &[PayloadFile],
+    ncm_dir: &Path,
+    profile_dir: &Path,
+    previous: &[PathBuf],
+) -> Vec<PathBuf> {
+    files
+        .iter()
+        .filter_map(|file| {
+            let target = file.target(ncm_dir, profile_dir).ok()?;
+            let owned = file.overwrite || !target.exists() || previous.contains(&target);
+            owned.then_some(target)
+        })
+        .collect()
+}
+
+pub fn plan_uninstall(ncm: &Ncm) -> Plan {
+    let mut steps = vec![
+        Step::KillProcess {
+            name: "cloudmusic.exe".into(),
+        },
+        Step::KillProcess {
+            name: "cloudmusicn.exe".into(),
+        },
+        Step::DeleteFile {
+            path: ncm.path.join("msimg32.dll"),
+        },
+    ];
+    if let Some(receipt) = InstallReceipt::load().filter(|receipt| receipt.ncm_path == ncm.path) {
+        steps.extend(
+            receipt
+                .files
+                .into_iter()
+                .filter(|path| *path != ncm.path.join("msimg32.dll") && path.is_file())
+                .map(|path| Step::DeleteFile { path }),
+        );
+    }
+    // Without the receipt, the startup check won't bring BetterNCM back
+    if receipt_path().exists() {
+        steps.push(Step::DeleteFile {
+            path: receipt_path(),
+        });
+    }
+    steps.push(Step::LaunchNcm {
+        path: ncm.path.clone(),
+    });
+    Plan {
+        operation: Operation::Uninstall,
+        steps,
+    }
+}
+
+fn legacy_backups_dir() -> PathBuf {
+    installer_data_dir().join("backups")
+}
+
+/// Finds the newest backup a legacy migration left behind.
+pub fn latest_legacy_backup() -> Option<PathBuf> {
+    fs::read_dir(legacy_backups_dir())
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("legacy-"))
+        })
+        .max()
+}
+
+fn new_legacy_backup_dir() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    legacy_backups_dir().join(format!("legacy-{timestamp}"))
+}
+
+/// Backs the v1 configuration up like a migration does, then removes it.
+pub fn plan_remove_legacy_config() -> Vec<Step> {
+    let backup = new_legacy_backup_dir();
+    vec![
+        Step::CreateDir {
+            path: backup.clone(),
+        },
+        Step::CopyDir {
+            from: config_path(),
+            to: backup.join("betterncm"),
+        },
+        Step::DeleteDir {
+            path: config_path(),
+        },
+    ]
+}
+
+/// Backs up everything the migration touches first, so `plan_restore_legacy` can undo it.
+pub fn plan_migrate_legacy(ncm: &Ncm) -> Plan {
+    let backup = new_legacy_backup_dir();
+    let localdata = get_ncm_localdata_path().join("localdata");
+    let config = config_path();
+    // `BETTERNCM_PROFILE` may point at the old directory, which then holds the current plugins
+    let has_legacy_config = has_legacy_config();
+
+    let mut steps = vec![Step::CreateDir {
+        path: backup.clone(),
+    }];
+    if localdata.exists() {
+        steps.push(Step::CopyFile {
+            from: localdata.clone(),
+            to: backup.join("localdata"),
+        });
+    }
+    if has_legacy_config {
+        steps.push(Step::CopyDir {
+            from: config.clone(),
+            to: backup.join("betterncm"),
+        });
+        // Packed plugins still load in the new version, unpacked v1 plugins do not
+        let plugins = get_betterncm_profile_path().join("plugins");
+        for entry in fs::read_dir(config.join("plugins"))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let path = entry.path();
+            let is_packed = path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("plugin"));
+            if is_packed && !plugins.join(entry.file_name()).exists() {
+                steps.push(Step::CopyFile {
+                    to: plugins.join(entry.file_name()),
+                    from: path,
+                });
+            } else if !is_packed {
+                tracing::info!(path = %path.display(), "skipping legacy plugin");
+            }
+        }
+    }
+    steps.extend([
+        Step::KillProcess {
+            name: "cloudmusic.exe".into(),
+        },
+        Step::KillProcess {
+            name: "cloudmusicn.exe".into(),
+        },
+        Step::CopyFile {
+            from: ncm.path.join("cloudmusic.exe"),
+            to: backup.join("cloudmusic.exe"),
+        },
+        Step::DeleteFile {
+            path: ncm.path.join("cloudmusic.exe"),
+        },
+        Step::RenameFile {
+            from: ncm.path.join("cloudmusicn.exe"),
+            to: ncm.path.join("cloudmusic.exe"),
+        },
+    ]);
+    if localdata.exists() {
+        steps.push(Step::ResetNcmProxy { path: localdata });
+    }
+    if has_legacy_config {
+        steps.push(Step::DeleteDir { path: config });
+    }
+    steps.push(Step::LaunchNcm {
+        path: ncm.path.clone(),
+    });
+    Plan {
+        operation: Operation::MigrateLegacy,
+        steps,
+    }
+}
+
+/// Puts back what `plan_migrate_legacy` saved in `backup`.
+pub fn plan_restore_legacy(ncm: &Ncm, backup: &Path) -> Plan {
+    let mut steps = vec![
+        Step::KillProcess {
+            name: "cloudmusic.exe".into(),
+        },
+        Step::KillProcess {
+            name: "cloudmusicn.exe".into(),
+        },
+    ];
+    if backup.join("cloudmusic.exe").exists() {
+        steps.extend([
+            Step::RenameFile {
+                from: ncm.path.join("cloudmusic.exe"),
+                to: ncm.path.join("cloudmusicn.exe"),
+            },
+            Step::CopyFile {
+                from: backup.join("cloudmusic.exe"),
+                to: ncm.path.join("cloudmusic.exe"),
+            },
+        ]);
+    }
+    if backup.join("localdata").exists() {
+        steps.push(Step::CopyFile {
+            from: backup.join("localdata"),
+            to: get_ncm_localdata_path().join("localdata"),
+        });
+    }
+    if backup.join("betterncm").exists() {
+        steps.push(Step::CopyDir {
+            from: backup.join("betterncm"),
+            to: config_path(),
+        });
+    }
+    steps.push(Step::LaunchNcm {
+        path: ncm.path.clone(),
+    });
+    Plan {
+        operation: Operation::RestoreLegacy,
+        steps,
+    }
+}
+
+/// A per-user install keeps the data path in HKCU only, which needs no administrator.
+fn profile_scopes() -> Vec<RegScope> {
+    match privilege::install_scope() {
+        InstallScope::Machine => vec![RegScope::Machine, RegScope::User],
+        InstallScope::PerUser => vec![RegScope::User],
+    }
+}
+
+pub fn plan_set_profile(path: &Path) -> Plan {
+    let value = path.to_string_lossy().to_string();
+    Plan {
+        operation: Operation::SetProfile,
+        steps: profile_scopes()
+            .into_iter()
+            .map(|scope| Step::SetEnvironmentValue {
+                scope,
+                name: "BETTERNCM_PROFILE".into(),
+                value: value.clone(),
+            })
+            .collect(),
+    }
+}
+
+pub fn plan_reset_profile() -> Plan {
+    let mut scopes = profile_scopes();
+    // A value left in HKLM by an earlier install would still apply after the reset
+    if !scopes.contains(&RegScope::Machine) && privilege::has_profile_value(RegScope::Machine) {
+        scopes.insert(0, RegScope::Machine);
+    }
+    Plan {
+        operation: Operation::ResetProfile,
+        steps: scopes
+            .into_iter()
+            .map(|scope| Step::DeleteEnvironmentValue {
+                scope,
+                name: "BETTERNCM_PROFILE".into(),
+            })
+            .collect(),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExecutionSummary {
+    pub reboot_required: bool,
+    /// What each executed step changed, in order.
+    pub changes: Vec<String>,
+}
+
+/// The checks among `done` of files that `segment` reads.
+///
+/// The elevated helper runs them again on its own copies of the files, since the originals sit
+/// where the unelevated user could swap them after they were verified.
+fn rechecks(done: &[Step], segment: &[Step]) -> Vec<Step> {
+    let mut checks = vec![];
+    for input in segment.iter().filter_map(Step::input) {
+        let check = done
+            .iter()
+            .rev()
+            .find(|step| matches!(step, Step::VerifyFile { path, .. } if path.as_path() == input));
+        if let Some(check) = check {
+            if !checks.contains(check) {
+                checks.push(check.clone());
+            }
+        }
+    }
+    checks
+}
+
+/// The end of the run of steps from `start` that goes to one elevated helper, if it needs one.
+///
+/// Steps that work either way are taken along while more elevated steps follow, so a plan
+/// shows a single UAC prompt where possible.
+fn elevated_segment(steps: &[Step], start: usize) -> Option<usize> {
+    if !steps[start].needs_elevation() {
+        return None;
+    }
+    let mut end = start + 1;
+    for (index, step) in steps.iter().enumerate().skip(start + 1) {
+        if step.stays_unelevated() {
+            break;
+        }
+        if step.needs_elevation() {
+            end = index + 1;
+        }
+    }
+    Some(end)
+}
+
+fn run_step(step: &Step, reporter: &dyn Reporter, summary: &mut ExecutionSummary) -> Result<()> {
+    run_step_as(step, step, reporter, summary)
+}
+
+/// Runs `step`, describing it as `shown`, the step it was staged from.
+fn run_step_as(
+    shown: &Step,
+    step: &Step,
+    reporter: &dyn Reporter,
+    summary: &mut ExecutionSummary,
+) -> Result<()> {
+    if !matches!(step, Step::Download { .. }) {
+        reporter.tip(shown.to_string());
+    }
+    let recorded = summary.changes.len();
+    step.run(reporter, summary)?;
+    // Steps that know more about their effect than their description record it themselves
+    if summary.changes.len() == recorded {
+        summary.changes.push(shown.to_string());
+    }
+    Ok(())
+}
+
+/// Copies `input` into `staging` under a name no other copy has.
+fn stage(input: &Path, staging: &Path, count: &mut usize) -> Result<PathBuf> {
+    let name = input
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let copy = staging.join(format!("{count}-{name}"));
+    *count += 1;
+    fs::copy(input, &copy)
+        .with_context(|| format!("Failed to copy {} to {}", input.display(), copy.display()))?;
+    Ok(copy)
+}
+
+/// Runs steps for the elevated helper, each on a copy of the file it reads.
+///
+/// The copies go to `staging`, which only administrators can write. Files that `checks` cover
+/// are copied and verified before any step runs, so a swapped file fails the plan before it
+/// changes anything, and can't be swapped again once its copy was verified.
+pub fn run_staged(
+    steps: &[Step],
+    checks: &[Step],
+    staging: &Path,
+    reporter: &dyn Reporter,
+) -> Result<ExecutionSummary> {
+    let mut count = 0;
+    let mut verified = vec![];
+    for check in checks {
+        if let Step::VerifyFile { path, sha256 } = check {
+            let copy = stage(path, staging, &mut count)?;
+            run_steps(
+                &[Step::VerifyFile {
+                    path: copy.clone(),
+                    sha256: sha256.clone(),
+                }],
+                reporter,
+            )?;
+            verified.push((path.as_path(), copy));
+        }
+    }
+    let mut summary = ExecutionSummary::default();
+    for step in steps {
+        let Some(input) = step.input() else {
+            run_step(step, reporter, &mut summary)?;
+            continue;
+        };
+        let copy = match verified.iter().find(|(path, _)| *path == input) {
+            Some((_, copy)) => copy.clone(),
+            // Written by an earlier step of this helper, so copied when it is used
+            None => stage(input, staging, &mut count)?,
+        };
+        run_step_as(step, &step.with_input(copy), reporter, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+/// Runs steps in this process as they are.
+pub fn run_steps(steps: &[Step], reporter: &dyn Reporter) -> Result<ExecutionSummary> {
+    let mut summary = ExecutionSummary::default();
+    for step in steps {
+        run_step(step, reporter, &mut summary)?;
+    }
+    Ok(summary)
+}
+
+pub fn execute(plan: &Plan, reporter: &dyn Reporter) -> Result<ExecutionSummary> {
+    tracing::info!(operation = plan.operation.name(), plan = ?plan, "executing plan");
+    let _lock = OperationLock::acquire(plan.operation.name())?;
+    reporter.tip(plan.operation.running_tip().to_string());
+    let elevated = privilege::is_elevated();
+    let mut summary = ExecutionSummary::default();
+    let total = plan.steps.len().max(1) as f64;
+    let mut index = 0;
+    while index < plan.steps.len() {
+        let segment = if elevated {
+            None
+        } else {
+            elevated_segment(&plan.steps, index)
+        };
+        if let Some(end) = segment {
+            reporter.tip(msgs().waiting_for_elevation.to_string());
+            let segment = &plan.steps[index..end];
+            let checks = rechecks(&plan.steps[..index], segment);
+            let helper = privilege::run_elevated(segment, &checks)?;
+            summary.reboot_required |= helper.reboot_required;
+            summary.changes.extend(helper.changes);
+            index = end;
+        } else {
+            run_step(&plan.steps[index], reporter, &mut summary)?;
+            index += 1;
+        }
+        reporter.progress(index as f64 / total);
+    }
+    let mut tip = plan.operation.success_tip().to_string();
+    if summary.reboot_required {
+        tip = format!("{tip} {}", msgs().reboot_required);
+    }
+    reporter.tip(tip);
+    tracing::info!(operation = plan.operation.name(), changes = ?summary.changes, "executed plan");
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use semver::Version;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn plans_list_numbered_steps() {
+        let plan = Plan {
+            operation: Operation::Uninstall,
+            steps: vec![
+                Step::DeleteFile {
+                    path: PathBuf::from("msimg32.dll"),
+                },
+                Step::DeleteDir {
+                    path: PathBuf::from("betterncm"),
+                },
+            ],
+        };
+        let text = plan.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                format!("1. {}", plan.steps[0]),
+                format!("2. {}", plan.steps[1])
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_serialize_with_their_type() {
+        let step = Step::KillProcess {
+            name: "cloudmusic.exe".into(),
+        };
+        let json = serde_json::to_value(&step).unwrap();
+        assert_eq!(json["type"], "kill_process");
+        assert_eq!(json["name"], "cloudmusic.exe");
+    }
+
+    #[test]
+    fn compares_directories_like_windows() {
+        assert!(same_dir(
+            Path::new(r"C:\Users\Al\betterncm"),
+            Path::new(r"c:\users\al\BetterNCM\")
+        ));
+        assert!(!same_dir(
+            Path::new(r"C:\Users\Al\betterncm"),
+            Path::new(r"C:\Users\Al\betterncm-old")
+        ));
+        let temp = env::temp_dir();
+        assert!(same_dir(&temp, &temp.join(".")));
+    }
+
+    struct NullReporter;
+
+    impl Reporter for NullReporter {
+        fn tip(&self, _tip: String) {}
+
+        fn progress(&self, _progress: f64) {}
+    }
+
+    #[test]
+    fn keeps_localdata_it_cannot_read() {
+        let dir = TempDir::new("localdata");
+        let path = dir.join("localdata");
+        fs::write(&path, b"not localdata").unwrap();
+
+        let steps = [Step::ResetNcmProxy { path: path.clone() }];
+        let summary = run_steps(&steps, &NullReporter).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"not localdata");
+        assert_eq!(
+            summary.changes,
+            [fill(
+                msgs().proxy_left_unchanged,
+                &[("path", &path.display())]
+            )]
+        );
+    }
+
+    #[test]
+    fn backs_up_the_legacy_config_before_removing_it() {
+        let steps = plan_remove_legacy_config();
+        let Some(Step::CopyDir { from, to }) = steps.get(1) else {
+            panic!("{steps:?}");
+        };
+        assert_eq!(from, &config_path());
+        assert!(to.starts_with(legacy_backups_dir()));
+        assert_eq!(
+            steps.last(),
+            Some(&Step::DeleteDir {
+                path: config_path()
+            })
+        );
+    }
+
+    #[test]
+    fn installs_only_the_runtime_ncm_needs() {
+        let entry: ManifestEntry = serde_json::from_value(serde_json::json!({
+            "version": "1.0.0",
+            "url_x86": "https://example.com/x86.dll",
+            "url_x64": "https://example.com/x64.dll"
+        }))
+        .unwrap();
+        let ncm = Ncm {
+            path: PathBuf::from("CloudMusic"),
+            version: Version::new(2, 10, 3),
+            ncm_type: NcmType::X86,
+        };
+        let runtime_installs = |installed_version| {
+            let vc_runtime = VcRuntimeStatus {
+                arch: NcmType::X86,
+                installed_version,
+            };
+            plan_install_for(
+                Operation::Install,
+                &ncm,
+                Channel::Stable,
+                &entry,
+                Path::new("profile"),
+                &vc_runtime,
+            )
+            .steps
+            .into_iter()
+            .filter_map(|step| match step {
+                Step::InstallVcRuntime { arch, .. } => Some(arch),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(runtime_installs(None), [NcmType::X86]);
+        assert!(runtime_installs(Some(Version::new(14, 38, 33135))).is_empty());
+    }
+
+    #[test]
+    fn rechecks_what_elevated_steps_read() {
+        let verify = |path: &str, sha256: &str| Step::VerifyFile {
+            path: PathBuf::from(path),
+            sha256: sha256.into(),
+        };
+        let done = [
+            verify("vc_redist.x64.exe", "old"),
+            verify("msimg32.dll", "dll"),
+            verify("vc_redist.x64.exe", "new"),
+            verify("unused.dll", "unused"),
+        ];
+        let segment = [
+            Step::InstallVcRuntime {
+                arch: NcmType::X64,
+                installer: PathBuf::from("vc_redist.x64.exe"),
+            },
+            Step::CopyFile {
+                from: PathBuf::from("msimg32.dll"),
+                to: PathBuf::from(r"C:\Program Files\Netease\CloudMusic\msimg32.dll"),
+            },
+            Step::CopyFile {
+                from: PathBuf::from("msimg32.dll"),
+                to: PathBuf::from(r"C:\Program Files\Netease\CloudMusic\msimg32.dll.bak"),
+            },
+        ];
+        assert_eq!(
+            rechecks(&done, &segment),
+            [
+                verify("vc_redist.x64.exe", "new"),
+                verify("msimg32.dll", "dll")
+            ]
+        );
+        assert!(rechecks(&done, &segment[..0]).is_empty());
+    }
+
+    #[test]
+    fn runs_elevated_steps_on_verified_copies() {
+        let (dir, staging) = (TempDir::new("staged"), TempDir::new("staging"));
+        let (input, output) = (dir.join("msimg32.dll"), dir.join("installed.dll"));
+        fs::write(&input, b"loader").unwrap();
+        let steps = [Step::CopyFile {
+            from: input.clone(),
+            to: output.clone(),
+        }];
+        let checks = [Step::VerifyFile {
+            path: input.clone(),
+            sha256: sha256_file(&input).unwrap(),
+        }];
+
+        let summary = run_staged(&steps, &checks, &staging, &NullReporter).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), b"loader");
+        assert_eq!(fs::read(staging.join("0-msimg32.dll")).unwrap(), b"loader");
+        assert_eq!(summary.changes, [steps[0].to_string()]);
+
+        // Swapped after the unelevated check, so nothing runs
+        fs::write(&input, b"swapped").unwrap();
+        fs::remove_file(&output).unwrap();
+        let err = run_staged(&steps, &checks, &staging, &NullReporter).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{err:#}");
+        assert!(!output.exists());
+    }
+}
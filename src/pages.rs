@@ -0,0 +1,592 @@
+use druid::widget::{Flex, Label, LineBreaking, Scroll, ViewSwitcher};
+use druid::{Color, Env, FontDescriptor, FontWeight, LensExt, Widget, WidgetExt as _};
+use scl_gui_widgets::{
+    widget_ext::WidgetExt,
+    widgets::{Button, ListItem, NavigationControl, PageSwitcher, ToggleSwitch, PUSH_PAGE},
+};
+
+use crate::i18n::{self, fill, msgs, text, Messages, ALL_LANGS};
+use crate::manifest::{self, Channel};
+use crate::ncm_utils::{get_betterncm_profile_path, Ncm};
+use crate::operations::{self, Operation, Reporter};
+use crate::settings::Settings;
+use crate::theme::{ThemeSetting, ALL_THEMES};
+use crate::{
+    bundle, conflicts, diagnostics, download, logging, policy, self_update, status, verify,
+};
+use crate::{
+    fetch_adapted_version_in_background, refresh_install_state, spawn_operation,
+    AdaptedVersionResult, AppData,
+};
+
+pub const PAGE_OVERVIEW: &str = "overview";
+pub const PAGE_INSTALL: &str = "install";
+pub const PAGE_PLUGINS: &str = "plugins";
+pub const PAGE_PROFILE: &str = "profile";
+pub const PAGE_DIAGNOSTICS: &str = "diagnostics";
+pub const PAGE_SETTINGS: &str = "settings";
+
+pub fn page_switcher() -> PageSwitcher<AppData> {
+    PageSwitcher::new()
+        .with_page(PAGE_OVERVIEW, Box::new(|| Box::new(overview_page())))
+        .with_page(PAGE_INSTALL, Box::new(|| Box::new(install_page())))
+        .with_page(PAGE_PLUGINS, Box::new(|| Box::new(plugins_page())))
+        .with_page(PAGE_PROFILE, Box::new(|| Box::new(profile_page())))
+        .with_page(PAGE_DIAGNOSTICS, Box::new(|| Box::new(diagnostics_page())))
+        .with_page(PAGE_SETTINGS, Box::new(|| Box::new(settings_page())))
+}
+
+fn value_font() -> FontDescriptor {
+    FontDescriptor::default()
+        .with_size(17.)
+        .with_weight(FontWeight::SEMI_BOLD)
+}
+
+fn page_title(key: fn(&'static Messages) -> &'static str) -> impl Widget<AppData> {
+    Label::new(text(key)).with_font(
+        FontDescriptor::default()
+            .with_size(20.)
+            .with_weight(FontWeight::BOLD),
+    )
+}
+
+/// Lays a page out as a scrollable column, since some pages grow with warnings and lists.
+fn page(content: Flex<AppData>) -> impl Widget<AppData> {
+    Scroll::new(
+        content
+            .cross_axis_alignment(druid::widget::CrossAxisAlignment::Start)
+            .padding(10.),
+    )
+    .vertical()
+    .expand()
+}
+
+fn nav_item(
+    key: fn(&'static Messages) -> &'static str,
+    page: &'static str,
+) -> impl Widget<AppData> {
+    ListItem::new(text(key))
+        .on_click(move |ctx, _data: &mut AppData, _env| {
+            ctx.submit_command(PUSH_PAGE.with(page));
+        })
+        .expand_width()
+}
+
+fn pick_install(data: &mut AppData, operation: Operation) {
+    if let (Some(ncm), Some(entry)) = (&data.ncm, &data.adapted_entry) {
+        if !data.conflicts.is_empty() {
+            data.tips_string = conflicts::describe(&data.conflicts);
+        }
+        data.pending_plan = Some(operations::plan_install(
+            operation,
+            ncm,
+            Channel::from_prerelease(data.prerelease),
+            entry,
+            &get_betterncm_profile_path(),
+        ));
+    }
+}
+
+fn overview_page() -> impl Widget<AppData> {
+    let installer_version_label = Flex::row()
+        .with_child(Label::new(text(|m| m.installer_version)).with_text_color(Color::grey(0.7)))
+        .with_child(
+            Label::new(|data: &AppData, _env: &_| -> String { data.installer_version.to_string() })
+                .with_font(value_font()),
+        );
+
+    let installer_update_row = Flex::row()
+        .with_child(
+            Label::new(|data: &AppData, _env: &_| -> String {
+                match &data.installer_update {
+                    Some(release) => {
+                        fill(msgs().installer_outdated, &[("version", &release.version)])
+                    }
+                    None => String::new(),
+                }
+            })
+            .with_text_color(Color::rgb8(0xff, 0xc0, 0x40)),
+        )
+        .with_spacer(5.)
+        .with_child(Button::new(text(|m| m.update_installer)).on_click(
+            |ctx, data: &mut AppData, _env| {
+                if let Some(release) = data.installer_update.clone() {
+                    spawn_operation("self_update", ctx.get_external_handle(), move |reporter| {
+                        let path = self_update::download_path()?;
+                        download::download_file(&release.url, &path, reporter)?;
+                        self_update::replace_and_restart(&release)
+                    });
+                }
+            },
+        ))
+        .show_if(|data: &AppData, _env| data.installer_update.is_some());
+
+    let manifest_unsupported_label = Label::new(text(|m| m.manifest_unsupported))
+        .with_text_color(Color::rgb8(0xff, 0x60, 0x60))
+        .show_if(|data: &AppData, _env| data.manifest_unsupported);
+
+    let manifest_stale_label = Label::new(|data: &AppData, _env: &_| -> String {
+        match data.manifest_stale {
+            Some(age) => fill(msgs().manifest_stale, &[("age", &i18n::format_age(age))]),
+            None => String::new(),
+        }
+    })
+    .with_text_color(Color::rgb8(0xff, 0xb0, 0x40))
+    .show_if(|data: &AppData, _env| data.manifest_stale.is_some());
+
+    let offline_label = Label::new(|_data: &AppData, _env: &_| -> String {
+        match bundle::active() {
+            Some(bundle) => fill(msgs().offline_mode, &[("path", &bundle.root.display())]),
+            None => String::new(),
+        }
+    })
+    .with_text_color(Color::grey(0.7))
+    .show_if(|_data: &AppData, _env| bundle::active().is_some());
+
+    let settings_locked_label = Label::new(text(|m| m.settings_locked))
+        .with_text_color(Color::grey(0.7))
+        .show_if(|_data: &AppData, _env| policy::settings_locked());
+
+    let install_path_label = Flex::row()
+        .with_child(Label::new(text(|m| m.ncm_version)).with_text_color(Color::grey(0.7)))
+        .with_child(
+            Label::new(|data: &AppData, _env: &_| -> String {
+                match &data.ncm {
+                    Some(ncm) => format!("{} ({:#?})", ncm.version, ncm.ncm_type).to_lowercase(),
+                    None => msgs().ncm_not_installed.to_string(),
+                }
+            })
+            .with_font(value_font()),
+        );
+
+    let latest_version_label = Flex::row()
+        .with_child(Label::new(text(|m| m.adapted_version)).with_text_color(Color::grey(0.7)))
+        .with_child(
+            Label::new(|data: &AppData, _env: &_| -> String {
+                match &data.latest_version {
+                    Some(AdaptedVersionResult::Version(version)) => version.to_string(),
+                    Some(AdaptedVersionResult::NoAdaptedVersion) => msgs().not_adapted.to_string(),
+                    Some(AdaptedVersionResult::FetchFailed) => msgs().fetch_failed.to_string(),
+                    None => msgs().fetching.to_string(),
+                }
+            })
+            .with_font(value_font()),
+        );
+
+    let unadapted_label = Label::new(|data: &AppData, _env: &_| -> String {
+        data.unadapted.clone().unwrap_or_default()
+    })
+    .with_line_break_mode(LineBreaking::WordWrap)
+    .with_text_color(Color::grey(0.7))
+    .show_if(|data: &AppData, _env| {
+        data.unadapted.is_some()
+            && data.latest_version == Some(AdaptedVersionResult::NoAdaptedVersion)
+    });
+
+    let local_version_label = Label::new(|data: &AppData, _env: &_| -> String {
+        match data.old_version {
+            true => msgs().old_version_detected.to_string(),
+            false => String::from(""),
+        }
+    })
+    .with_font(value_font())
+    .show_if(|data: &AppData, _env| data.old_version);
+
+    let yanked_row = Flex::row()
+        .with_flex_child(
+            Label::new(|data: &AppData, _env: &_| -> String {
+                data.yanked.clone().unwrap_or_default()
+            })
+            .with_line_break_mode(LineBreaking::WordWrap)
+            .with_text_color(Color::rgb8(0xff, 0x60, 0x60)),
+            1.,
+        )
+        .with_spacer(5.)
+        .with_child(
+            Button::new(text(|m| m.update_to_recommended))
+                .disabled_if(|data: &AppData, _env: &_| {
+                    !matches!(data.latest_version, Some(AdaptedVersionResult::Version(_)))
+                })
+                .on_click(|_ctx, data: &mut AppData, _env| pick_install(data, Operation::Update)),
+        )
+        .show_if(|data: &AppData, _env| data.yanked.is_some());
+
+    let conflicts_row = Flex::row()
+        .with_flex_child(
+            Label::new(|data: &AppData, _env: &_| -> String {
+                conflicts::describe(&data.conflicts)
+            })
+            .with_line_break_mode(LineBreaking::WordWrap)
+            .with_text_color(Color::rgb8(0xff, 0xc0, 0x40)),
+            1.,
+        )
+        .with_spacer(5.)
+        .with_child(Button::new(text(|m| m.quarantine)).on_click(
+            |_ctx, data: &mut AppData, _env| {
+                if let Some(ncm) = &data.ncm {
+                    data.pending_plan = Some(conflicts::plan_quarantine(ncm, &data.conflicts));
+                }
+            },
+        ))
+        .show_if(|data: &AppData, _env| !data.conflicts.is_empty());
+
+    page(
+        Flex::column()
+            .with_child(
+                Label::new("BetterNCM Installer".to_string()).with_font(
+                    FontDescriptor::default()
+                        .with_size(20.)
+                        .with_weight(FontWeight::BOLD),
+                ),
+            )
+            .with_child(installer_version_label)
+            .with_child(installer_update_row)
+            .with_child(manifest_unsupported_label)
+            .with_child(manifest_stale_label)
+            .with_child(offline_label)
+            .with_child(settings_locked_label)
+            .with_child(install_path_label)
+            .with_child(latest_version_label)
+            .with_child(unadapted_label)
+            .with_child(local_version_label)
+            .with_child(yanked_row)
+            .with_child(conflicts_row)
+            .with_spacer(10.)
+            .with_child(nav_item(|m| m.page_install, PAGE_INSTALL))
+            .with_child(nav_item(|m| m.page_plugins, PAGE_PLUGINS))
+            .with_child(nav_item(|m| m.page_profile, PAGE_PROFILE))
+            .with_child(nav_item(|m| m.page_diagnostics, PAGE_DIAGNOSTICS))
+            .with_child(nav_item(|m| m.page_settings, PAGE_SETTINGS)),
+    )
+}
+
+fn install_page() -> impl Widget<AppData> {
+    let installed_label = Flex::row()
+        .with_child(Label::new(text(|m| m.installed_version)).with_text_color(Color::grey(0.7)))
+        .with_child(
+            Label::new(|data: &AppData, _env: &_| -> String {
+                data.installed_version
+                    .as_ref()
+                    .map_or_else(|| msgs().ncm_not_installed.to_string(), |v| v.to_string())
+            })
+            .with_font(value_font()),
+        );
+
+    let channel_switch = ToggleSwitch::new()
+        .disabled_if(|_data: &bool, _env: &Env| policy::settings_locked())
+        .on_change(|ctx, _old, new, _env| {
+            let sink = ctx.get_external_handle();
+            let channel = Channel::from_prerelease(*new);
+            ctx.get_external_handle()
+                .add_idle_callback(move |data: &mut AppData| {
+                    data.latest_version = None;
+                    data.adapted_entry = None;
+                    data.tips_string = "".into();
+                    fetch_adapted_version_in_background(data.ncm.clone(), sink, channel);
+                });
+        })
+        .lens(AppData::prerelease);
+
+    // The switch only sees `prerelease`, so its text lives in a label that follows the language
+    let channel_row = Flex::row()
+        .with_child(Label::new(text(|m| m.test_channel)))
+        .with_flex_spacer(1.)
+        .with_child(channel_switch)
+        .expand_width();
+
+    let button_install = Button::new(text(|m| m.install))
+        .disabled_if(|data: &AppData, _env: &_| {
+            !matches!(data.latest_version, Some(AdaptedVersionResult::Version(_)))
+                || data.old_version
+                || data.new_version
+        })
+        .on_click(|_ctx, data: &mut AppData, _env| pick_install(data, Operation::Install));
+
+    let button_reinstall = Button::new(text(|m| m.reinstall))
+        .disabled_if(|data: &AppData, _env: &_| {
+            !matches!(data.latest_version, Some(AdaptedVersionResult::Version(_)))
+                || data.old_version
+                || !data.new_version
+        })
+        .on_click(|_ctx, data: &mut AppData, _env| pick_install(data, Operation::Update));
+
+    let button_uninstall = Button::new(text(|m| m.uninstall))
+        .disabled_if(|data: &AppData, _env: &_| data.old_version || !data.new_version)
+        .on_click(|_ctx, data: &mut AppData, _env| {
+            if let Some(ncm) = &data.ncm {
+                data.pending_plan = Some(operations::plan_uninstall(ncm));
+            }
+        });
+
+    let button_uninstall_old = Button::new(text(|m| m.uninstall_old))
+        .disabled_if(|data: &AppData, _env: &_| !data.old_version)
+        .on_click(|_ctx, data: &mut AppData, _env| {
+            if let Some(ncm) = &data.ncm {
+                data.pending_plan = Some(operations::plan_migrate_legacy(ncm));
+            }
+        });
+
+    let button_set_ncm_path =
+        Button::new(text(|m| m.select_ncm)).on_click(|ctx, data: &mut AppData, _env| {
+            let files = rfd::FileDialog::new()
+                .add_filter(msgs().ncm_executable_filter, &["exe"])
+                .pick_files();
+
+            if let Some(files) = files {
+                let ncm = Ncm::get_ncm_by_path(files[0].parent().unwrap().to_path_buf());
+                tracing::info!(path = %files[0].display(), ncm = ?ncm, "manually selected NCM");
+                data.ncm = ncm.ok();
+                data.latest_version = None;
+                data.adapted_entry = None;
+                refresh_install_state(data);
+                fetch_adapted_version_in_background(
+                    data.ncm.clone(),
+                    ctx.get_external_handle(),
+                    Channel::from_prerelease(data.prerelease),
+                );
+            }
+        });
+
+    page(
+        Flex::column()
+            .with_child(page_title(|m| m.page_install))
+            .with_spacer(5.)
+            .with_child(installed_label)
+            .with_spacer(5.)
+            .with_child(channel_row)
+            .with_spacer(10.)
+            .with_child(
+                Flex::row()
+                    .with_flex_child(button_install.expand_width(), 1.)
+                    .with_spacer(5.)
+                    .with_flex_child(button_reinstall.expand_width(), 1.),
+            )
+            .with_spacer(5.)
+            .with_child(
+                Flex::row()
+                    .with_flex_child(button_uninstall.expand_width(), 1.)
+                    .with_spacer(5.)
+                    .with_flex_child(button_uninstall_old.expand_width(), 1.),
+            )
+            .with_spacer(5.)
+            .with_child(button_set_ncm_path.expand_width()),
+    )
+}
+
+fn plugins_page() -> impl Widget<AppData> {
+    let plugins = Label::new(|data: &AppData, _env: &_| -> String {
+        if data.plugins.is_empty() {
+            msgs().no_plugins.to_string()
+        } else {
+            data.plugins.join("\n")
+        }
+    })
+    .with_line_break_mode(LineBreaking::WordWrap);
+
+    let button_open_plugins =
+        Button::new(text(|m| m.open_plugins_dir)).on_click(|_ctx, data: &mut AppData, _env| {
+            let dir = get_betterncm_profile_path().join("plugins");
+            let result = std::fs::create_dir_all(&dir)
+                .and_then(|_| std::process::Command::new("explorer.exe").arg(&dir).spawn());
+            if let Err(err) = result {
+                tracing::error!(error = ?err, "failed to open plugins directory");
+                data.tips_string = fill(msgs().operation_failed, &[("error", &err)]);
+            }
+        });
+
+    let button_refresh =
+        Button::new(text(|m| m.refresh)).on_click(|_ctx, data: &mut AppData, _env| {
+            data.plugins = status::list_plugins(&get_betterncm_profile_path());
+        });
+
+    page(
+        Flex::column()
+            .with_child(page_title(|m| m.page_plugins))
+            .with_spacer(5.)
+            .with_child(plugins)
+            .with_spacer(10.)
+            .with_child(
+                Flex::row()
+                    .with_flex_child(button_open_plugins.expand_width(), 1.)
+                    .with_spacer(5.)
+                    .with_flex_child(button_refresh.expand_width(), 1.),
+            ),
+    )
+}
+
+fn profile_page() -> impl Widget<AppData> {
+    let profile_label = Label::new(|_data: &AppData, _env: &_| -> String {
+        get_betterncm_profile_path().display().to_string()
+    })
+    .with_line_break_mode(LineBreaking::WordWrap)
+    .with_font(value_font());
+
+    let button_set_path = Button::new(text(|m| m.set_data_path))
+        .disabled_if(|_data: &AppData, _env: &_| policy::settings_locked())
+        .on_click(|_ctx, data: &mut AppData, _env| {
+            let folder = rfd::FileDialog::new()
+                .set_directory(get_betterncm_profile_path())
+                .pick_folder();
+            if let Some(path) = folder {
+                data.pending_plan = Some(operations::plan_set_profile(&path));
+            }
+        });
+
+    let button_reset_path = Button::new(text(|m| m.reset_data_path))
+        .disabled_if(|_data: &AppData, _env: &_| policy::settings_locked())
+        .on_click(|_ctx, data: &mut AppData, _env| {
+            data.pending_plan = Some(operations::plan_reset_profile());
+        });
+
+    page(
+        Flex::column()
+            .with_child(page_title(|m| m.page_profile))
+            .with_spacer(5.)
+            .with_child(Label::new(text(|m| m.data_path)).with_text_color(Color::grey(0.7)))
+            .with_child(profile_label)
+            .with_spacer(10.)
+            .with_child(
+                Flex::row()
+                    .with_flex_child(button_set_path.expand_width(), 1.)
+                    .with_spacer(5.)
+                    .with_flex_child(button_reset_path.expand_width(), 1.),
+            ),
+    )
+}
+
+fn diagnostics_page() -> impl Widget<AppData> {
+    let button_repair = Button::new(text(|m| m.repair))
+        .disabled_if(|data: &AppData, _env: &_| data.ncm.is_none())
+        .on_click(|ctx, data: &mut AppData, _env| {
+            if let Some(ncm) = data.ncm.clone() {
+                spawn_operation("verify", ctx.get_external_handle(), move |reporter| {
+                    reporter.tip(msgs().verifying.to_string());
+                    let (manifest, _) = manifest::fetch()?;
+                    let verification = verify::verify(&ncm, &manifest);
+                    reporter.add_idle_callback(move |data: &mut AppData| {
+                        data.tips_string = verification.to_string().trim_end().to_string();
+                        if !verification.plan.steps.is_empty() {
+                            data.pending_plan = Some(verification.plan);
+                        }
+                    });
+                    Ok(())
+                });
+            }
+        });
+
+    let button_export_diagnostics =
+        Button::new(text(|m| m.export_diagnostics)).on_click(|_ctx, data: &mut AppData, _env| {
+            let dest = rfd::FileDialog::new()
+                .add_filter("zip", &["zip"])
+                .set_file_name(&diagnostics::default_bundle_name())
+                .save_file();
+            if let Some(dest) = dest {
+                data.tips_string = match diagnostics::export_bundle(&dest, data.ncm.clone()) {
+                    Ok(()) => msgs().diagnostics_exported.to_string(),
+                    Err(err) => {
+                        tracing::error!(error = ?err, "failed to export diagnostics bundle");
+                        fill(msgs().operation_failed, &[("error", &err)])
+                    }
+                };
+            }
+        });
+
+    let button_open_log = Button::new(text(|m| m.open_log)).on_click(|_ctx, data, _env| {
+        if let Err(err) = logging::open_log_dir() {
+            tracing::error!(error = ?err, "failed to open log directory");
+            data.tips_string = fill(msgs().operation_failed, &[("error", &err)]);
+        }
+    });
+
+    let button_export_logs = Button::new(text(|m| m.export_logs)).on_click(|_ctx, data, _env| {
+        if let Some(dest) = rfd::FileDialog::new().pick_folder() {
+            data.tips_string = match logging::export_logs(&dest) {
+                Ok(files) => fill(msgs().logs_exported, &[("count", &files.len())]),
+                Err(err) => {
+                    tracing::error!(error = ?err, "failed to export logs");
+                    fill(msgs().operation_failed, &[("error", &err)])
+                }
+            };
+        }
+    });
+
+    page(
+        Flex::column()
+            .with_child(page_title(|m| m.page_diagnostics))
+            .with_spacer(5.)
+            .with_child(button_repair.expand_width())
+            .with_spacer(5.)
+            .with_child(button_export_diagnostics.expand_width())
+            .with_spacer(5.)
+            .with_child(
+                Flex::row()
+                    .with_flex_child(button_open_log.expand_width(), 1.)
+                    .with_spacer(5.)
+                    .with_flex_child(button_export_logs.expand_width(), 1.),
+            ),
+    )
+}
+
+fn settings_page() -> impl Widget<AppData> {
+    let languages = ALL_LANGS
+        .iter()
+        .fold(NavigationControl::new(), |control, lang| {
+            control.with_page(lang.msgs().lang_name.to_string())
+        })
+        .lens(AppData::lang.map(
+            |lang| ALL_LANGS.iter().position(|l| l == lang).unwrap_or(0),
+            |lang, index| *lang = ALL_LANGS[index],
+        ))
+        .on_change(|_ctx, old, data: &mut AppData, _env| {
+            if old.lang != data.lang {
+                i18n::set_lang(data.lang);
+                let mut settings = Settings::load();
+                settings.lang = Some(data.lang);
+                let _ = settings.save();
+            }
+        });
+
+    // The theme names are laid out once, so the control is rebuilt for each language
+    let themes = ViewSwitcher::new(
+        |data: &AppData, _env| data.lang.tag(),
+        |_lang, _data, _env| Box::new(theme_control()),
+    );
+
+    page(
+        Flex::column()
+            .with_child(page_title(|m| m.page_settings))
+            .with_spacer(5.)
+            .with_child(Label::new(text(|m| m.language)).with_text_color(Color::grey(0.7)))
+            .with_child(languages)
+            .with_spacer(5.)
+            .with_child(Label::new(text(|m| m.theme)).with_text_color(Color::grey(0.7)))
+            .with_child(themes),
+    )
+}
+
+fn theme_control() -> impl Widget<AppData> {
+    let m = msgs();
+    ALL_THEMES
+        .iter()
+        .fold(NavigationControl::new(), |control, theme| {
+            control.with_page(
+                match theme {
+                    ThemeSetting::Light => m.theme_light,
+                    ThemeSetting::Dark => m.theme_dark,
+                    ThemeSetting::System => m.theme_system,
+                }
+                .to_string(),
+            )
+        })
+        .lens(AppData::theme.map(
+            |theme| ALL_THEMES.iter().position(|t| t == theme).unwrap_or(0),
+            |theme, index| *theme = ALL_THEMES[index],
+        ))
+        .on_change(|_ctx, old, data: &mut AppData, _env| {
+            if old.theme != data.theme {
+                let mut settings = Settings::load();
+                settings.theme = data.theme;
+                let _ = settings.save();
+            }
+        })
+}
@@ -0,0 +1,294 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+
+use crate::ncm_utils::sha256_file;
+
+/// Which directory a payload file is installed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadRoot {
+    Ncm,
+    Profile,
+}
+
+/// One entry of a zip artifact and where it goes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayloadFile {
+    /// Name of the entry inside the archive.
+    pub entry: String,
+    pub to: PayloadRoot,
+    /// Destination relative to `to`.
+    pub path: PathBuf,
+    /// Checked after extraction, and by `verify` for files already installed.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// `false` keeps a file the user may have edited, like a config template, once it exists.
+    #[serde(default = "default_overwrite")]
+    pub overwrite: bool,
+}
+
+fn default_overwrite() -> bool {
+    true
+}
+
+impl PayloadFile {
+    /// The absolute destination, refusing paths that would leave the target directory.
+    pub fn target(&self, ncm_dir: &Path, profile_dir: &Path) -> Result<PathBuf> {
+        if self.path.as_os_str().is_empty()
+            || !self
+                .path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!("Invalid payload path '{}'", self.path.display());
+        }
+        Ok(match self.to {
+            PayloadRoot::Ncm => ncm_dir.join(&self.path),
+            PayloadRoot::Profile => profile_dir.join(&self.path),
+        })
+    }
+}
+
+/// A file moved into place, and what it replaced.
+struct Placed {
+    target: PathBuf,
+    backup: Option<PathBuf>,
+}
+
+fn staging_dir(root: &Path) -> PathBuf {
+    root.join(format!(".betterncm-staging-{}", process::id()))
+}
+
+/// Puts every placed file back the way it was, newest first.
+fn roll_back(placed: &[Placed]) {
+    for placed in placed.iter().rev() {
+        let _ = fs::remove_file(&placed.target);
+        if let Some(backup) = &placed.backup {
+            if let Err(err) = fs::rename(backup, &placed.target) {
+                tracing::error!(
+                    error = ?err,
+                    target = %placed.target.display(),
+                    "failed to restore file during rollback"
+                );
+            }
+        }
+    }
+}
+
+/// Extracts the mapped files of `archive` so that either all of them are installed or none is.
+///
+/// Everything is unpacked into staging directories next to the targets first. Only then are
+/// the files renamed into place, with replaced files kept aside until the last one succeeded.
+/// Returns the files that were written.
+pub fn extract(
+    archive: &Path,
+    files: &[PayloadFile],
+    ncm_dir: &Path,
+    profile_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut zip = ZipArchive::new(
+        File::open(archive).with_context(|| format!("Failed to open {}", archive.display()))?,
+    )
+    .with_context(|| format!("{} is not a zip archive", archive.display()))?;
+
+    let staging = [staging_dir(ncm_dir), staging_dir(profile_dir)];
+    let result = (|| -> Result<Vec<PathBuf>> {
+        let mut staged = vec![];
+        for (index, file) in files.iter().enumerate() {
+            let target = file.target(ncm_dir, profile_dir)?;
+            if !file.overwrite && target.exists() {
+                tracing::info!(target = %target.display(), "keeping existing payload file");
+                continue;
+            }
+            let dir = match file.to {
+                PayloadRoot::Ncm => &staging[0],
+                PayloadRoot::Profile => &staging[1],
+            };
+            fs::create_dir_all(dir)?;
+            // Flat names keep the staging directory free of the archive's layout
+            let staged_path = dir.join(index.to_string());
+            let mut entry = zip
+                .by_name(&file.entry)
+                .with_context(|| format!("{} is missing from the archive", file.entry))?;
+            io::copy(&mut entry, &mut File::create(&staged_path)?)
+                .with_context(|| format!("Failed to extract {}", file.entry))?;
+            if let Some(expected) = &file.sha256 {
+                let actual = sha256_file(&staged_path)?;
+                if !actual.eq_ignore_ascii_case(expected) {
+                    bail!(
+                        "Checksum mismatch for {}: expected {expected}, got {actual}",
+                        file.entry
+                    );
+                }
+            }
+            staged.push((staged_path, target));
+        }
+
+        let mut placed: Vec<Placed> = vec![];
+        for (staged_path, target) in &staged {
+            let commit = || -> Result<Placed> {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let backup = if target.exists() {
+                    let mut backup = target.clone().into_os_string();
+                    backup.push(".betterncm-old");
+                    let backup = PathBuf::from(backup);
+                    let _ = fs::remove_file(&backup);
+                    fs::rename(target, &backup)?;
+                    Some(backup)
+                } else {
+                    None
+                };
+                if let Err(err) = fs::rename(staged_path, target) {
+                    if let Some(backup) = &backup {
+                        let _ = fs::rename(backup, target);
+                    }
+                    return Err(err.into());
+                }
+                Ok(Placed {
+                    target: target.clone(),
+                    backup,
+                })
+            };
+            match commit() {
+                Ok(done) => placed.push(done),
+                Err(err) => {
+                    roll_back(&placed);
+                    return Err(err)
+                        .with_context(|| format!("Failed to install {}", target.display()));
+                }
+            }
+        }
+
+        for backup in placed.iter().filter_map(|placed| placed.backup.as_ref()) {
+            let _ = fs::remove_file(backup);
+        }
+        Ok(placed.into_iter().map(|placed| placed.target).collect())
+    })();
+
+    for dir in &staging {
+        let _ = fs::remove_dir_all(dir);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Write;
+
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    use super::*;
+    use crate::ncm_utils::sha256_bytes;
+    use crate::test_util::TempDir;
+
+    fn write_archive(path: &Path, entries: &[(&str, &str)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, content) in entries {
+            zip.start_file(
+                *name,
+                FileOptions::default().compression_method(CompressionMethod::Stored),
+            )
+            .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn payload(entry: &str, to: PayloadRoot, path: &str) -> PayloadFile {
+        PayloadFile {
+            entry: entry.into(),
+            to,
+            path: path.into(),
+            sha256: None,
+            overwrite: true,
+        }
+    }
+
+    #[test]
+    fn keeps_targets_inside_their_root() {
+        let (ncm, profile) = (Path::new("ncm"), Path::new("profile"));
+        assert_eq!(
+            payload("a", PayloadRoot::Profile, "plugins/a.txt")
+                .target(ncm, profile)
+                .unwrap(),
+            profile.join("plugins/a.txt")
+        );
+        for path in ["", "../a.txt", "plugins/../../a.txt", "./a.txt"] {
+            assert!(payload("a", PayloadRoot::Ncm, path)
+                .target(ncm, profile)
+                .is_err());
+        }
+        let absolute = env::temp_dir().join("a.txt");
+        assert!(payload("a", PayloadRoot::Ncm, absolute.to_str().unwrap())
+            .target(ncm, profile)
+            .is_err());
+    }
+
+    #[test]
+    fn places_files_into_both_roots() {
+        let dir = TempDir::new("payload-extract");
+        let (ncm, profile) = (dir.join("ncm"), dir.join("profile"));
+        let archive = dir.join("payload.zip");
+        write_archive(&archive, &[("dll", "loader"), ("cfg", "config")]);
+        let files = [
+            payload("dll", PayloadRoot::Ncm, "msimg32.dll"),
+            PayloadFile {
+                sha256: Some(sha256_bytes(b"config")),
+                ..payload("cfg", PayloadRoot::Profile, "config/default.json")
+            },
+        ];
+        let written = extract(&archive, &files, &ncm, &profile).unwrap();
+        assert_eq!(
+            written,
+            [ncm.join("msimg32.dll"), profile.join("config/default.json")]
+        );
+        assert_eq!(fs::read_to_string(&written[0]).unwrap(), "loader");
+        assert_eq!(fs::read_to_string(&written[1]).unwrap(), "config");
+        assert!(!staging_dir(&ncm).exists() && !staging_dir(&profile).exists());
+    }
+
+    #[test]
+    fn keeps_existing_files_that_are_not_overwritten() {
+        let dir = TempDir::new("payload-keep");
+        let archive = dir.join("payload.zip");
+        write_archive(&archive, &[("cfg", "template")]);
+        fs::write(dir.join("config.json"), "edited").unwrap();
+        let files = [PayloadFile {
+            overwrite: false,
+            ..payload("cfg", PayloadRoot::Profile, "config.json")
+        }];
+        assert!(extract(&archive, &files, &dir, &dir).unwrap().is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.join("config.json")).unwrap(),
+            "edited"
+        );
+    }
+
+    #[test]
+    fn installs_nothing_on_a_checksum_mismatch() {
+        let dir = TempDir::new("payload-mismatch");
+        let archive = dir.join("payload.zip");
+        write_archive(&archive, &[("a", "new a"), ("b", "new b")]);
+        fs::write(dir.join("a.txt"), "old a").unwrap();
+        let files = [
+            payload("a", PayloadRoot::Ncm, "a.txt"),
+            PayloadFile {
+                sha256: Some(sha256_bytes(b"something else")),
+                ..payload("b", PayloadRoot::Ncm, "b.txt")
+            },
+        ];
+        assert!(extract(&archive, &files, &dir, &dir).is_err());
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old a");
+        assert!(!dir.join("b.txt").exists());
+    }
+}
@@ -0,0 +1,134 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::{self, Channel};
+use crate::network::NetworkSettings;
+
+/// Administrators deploy this file to preset and lock what the window may change.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub channel: Option<Channel>,
+    /// Install exactly this BetterNCM version instead of the newest adapted one.
+    pub version: Option<Version>,
+    pub manifest_url: Option<String>,
+    pub profile: Option<PathBuf>,
+    /// Plugin URLs downloaded into `<profile>/plugins`.
+    pub plugins: Vec<String>,
+    pub allow_close_ncm: bool,
+    pub relaunch_ncm: bool,
+    pub lock_settings: bool,
+    /// Replaces the network options of `settings.json`.
+    pub network: Option<NetworkSettings>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            channel: None,
+            version: None,
+            manifest_url: None,
+            profile: None,
+            plugins: vec![],
+            allow_close_ncm: true,
+            relaunch_ncm: true,
+            lock_settings: false,
+            network: None,
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Policy> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read policy {}", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid policy {}", path.display()))
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel.unwrap_or(Channel::Stable)
+    }
+}
+
+pub fn machine_policy_path() -> PathBuf {
+    env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("C:\\ProgramData"))
+        .join("BetterNCM")
+        .join("installer-policy.json")
+}
+
+pub fn machine_policy() -> Result<Option<Policy>> {
+    let path = machine_policy_path();
+    if !path.exists() {
+        return Ok(None);
+    }
+    Policy::load(&path).map(Some)
+}
+
+static ACTIVE_POLICY: OnceLock<Policy> = OnceLock::new();
+
+pub fn activate(policy: Policy) {
+    if let Some(url) = &policy.manifest_url {
+        manifest::set_mirror(Some(url.clone()));
+    }
+    let _ = ACTIVE_POLICY.set(policy);
+}
+
+pub fn active() -> Option<&'static Policy> {
+    ACTIVE_POLICY.get()
+}
+
+pub fn settings_locked() -> bool {
+    active().is_some_and(|policy| policy.lock_settings)
+}
+
+pub fn plugin_file_name(url: &str) -> String {
+    url.rsplit('/')
+        .next()
+        .and_then(|name| name.split(['?', '#']).next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("plugin.plugin")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> serde_json::Result<Policy> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn fills_in_defaults() {
+        let policy = parse(r#"{ "version": "1.2.0", "relaunch_ncm": false }"#).unwrap();
+        assert_eq!(policy.version, Some(Version::new(1, 2, 0)));
+        assert_eq!(policy.channel(), Channel::Stable);
+        assert!(policy.allow_close_ncm);
+        assert!(!policy.relaunch_ncm);
+        assert!(!policy.lock_settings);
+        assert_eq!(parse("{}").unwrap(), Policy::default());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        // A typo must not silently deploy something else than intended
+        assert!(parse(r#"{ "verison": "1.2.0" }"#).is_err());
+    }
+
+    #[test]
+    fn names_plugins_after_their_urls() {
+        assert_eq!(
+            plugin_file_name("https://example.com/p/PluginMarket.plugin?v=2"),
+            "PluginMarket.plugin"
+        );
+        assert_eq!(plugin_file_name("https://example.com/"), "plugin.plugin");
+    }
+}
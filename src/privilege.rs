@@ -0,0 +1,378 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::iter;
+use std::mem;
+use std::os::windows::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use winreg::enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, KEY_SET_VALUE};
+use winreg::RegKey;
+
+use crate::download::DownloadProgress;
+use crate::i18n::{self, msgs};
+use crate::ncm_utils::{get_ncm_install_path, sha256_bytes};
+use crate::operations::{self, ExecutionSummary, RegScope, Reporter, Step};
+
+const MACHINE_ENVIRONMENT: &str =
+    "System\\CurrentControlSet\\Control\\Session Manager\\Environment";
+
+/// Revision of the SDDL strings passed to `ConvertStringSecurityDescriptorToSecurityDescriptorW`.
+const SDDL_REVISION_1: u32 = 1;
+
+/// Full control for SYSTEM and administrators only, without inheriting from the parent.
+const ADMIN_ONLY_SDDL: &str = "D:P(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)";
+
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Where the installer keeps `BETTERNCM_PROFILE` and whether NCM needs an administrator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallScope {
+    /// NCM lives somewhere only administrators can write, like `Program Files`.
+    Machine,
+    /// NCM can be changed by the current user, so nothing needs an administrator.
+    PerUser,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Privileges {
+    pub elevated: bool,
+    pub scope: InstallScope,
+    pub machine_env_writable: bool,
+}
+
+pub fn is_elevated() -> bool {
+    use winapi::ctypes::c_void;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+    use winapi::um::securitybaseapi::GetTokenInformation;
+    use winapi::um::winnt::{TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+    unsafe {
+        let mut token = ptr::null_mut();
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return false;
+        }
+        let mut elevation: TOKEN_ELEVATION = mem::zeroed();
+        let mut size = 0;
+        let ok = GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut _ as *mut c_void,
+            mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut size,
+        );
+        CloseHandle(token);
+        ok != 0 && elevation.TokenIsElevated != 0
+    }
+}
+
+/// Whether this process can create files in `path`, or in its closest existing parent.
+pub fn dir_writable(path: &Path) -> bool {
+    let Some(dir) = path.ancestors().find(|dir| dir.is_dir()) else {
+        return false;
+    };
+    let probe = dir.join(format!(".betterncm-write-test-{}", process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(file) => {
+            drop(file);
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        // Left behind by an earlier check that could write but not clean up
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => true,
+        Err(_) => false,
+    }
+}
+
+/// Whether the file at `path` can be replaced or removed by this process.
+pub fn path_writable(path: &Path) -> bool {
+    if path.is_file() {
+        // A file held open by a running NCM is still writable once NCM is closed
+        let file_ok = match OpenOptions::new().write(true).open(path) {
+            Ok(_) => true,
+            Err(err) => err.kind() != ErrorKind::PermissionDenied,
+        };
+        return file_ok && path.parent().is_some_and(dir_writable);
+    }
+    match path.parent() {
+        Some(parent) => dir_writable(parent),
+        None => dir_writable(path),
+    }
+}
+
+pub fn machine_env_writable() -> bool {
+    RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey_with_flags(MACHINE_ENVIRONMENT, KEY_SET_VALUE)
+        .is_ok()
+}
+
+/// Whether `BETTERNCM_PROFILE` is set in the given scope.
+pub fn has_profile_value(scope: RegScope) -> bool {
+    let (root, key) = match scope {
+        RegScope::Machine => (HKEY_LOCAL_MACHINE, MACHINE_ENVIRONMENT),
+        RegScope::User => (HKEY_CURRENT_USER, "Environment"),
+    };
+    RegKey::predef(root)
+        .open_subkey(key)
+        .and_then(|env| env.get_value::<String, _>("BETTERNCM_PROFILE"))
+        .is_ok()
+}
+
+pub fn install_scope() -> InstallScope {
+    match get_ncm_install_path() {
+        // An administrator can write anywhere, so keep the machine-wide layout
+        _ if is_elevated() => InstallScope::Machine,
+        Ok(path) if !dir_writable(&path) => InstallScope::Machine,
+        _ => InstallScope::PerUser,
+    }
+}
+
+pub fn current() -> Privileges {
+    Privileges {
+        elevated: is_elevated(),
+        scope: install_scope(),
+        machine_env_writable: machine_env_writable(),
+    }
+}
+
+fn wide(text: &OsStr) -> Vec<u16> {
+    text.encode_wide().chain(iter::once(0)).collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HelperRequest {
+    steps: Vec<Step>,
+    /// `VerifyFile` steps for the files `steps` read, run again on the helper's copies of them.
+    checks: Vec<Step>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HelperResult {
+    summary: Option<ExecutionSummary>,
+    error: Option<String>,
+}
+
+/// Runs `steps` in an elevated copy of the installer, which shows a UAC prompt first.
+///
+/// The helper copies the files `steps` read to a directory only administrators can write and
+/// runs `checks` on the copies before `steps` use them, so the unelevated user can't swap a file
+/// between its check and its use.
+pub fn run_elevated(steps: &[Step], checks: &[Step]) -> Result<ExecutionSummary> {
+    use winapi::shared::winerror::ERROR_CANCELLED;
+    use winapi::um::errhandlingapi::GetLastError;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::GetExitCodeProcess;
+    use winapi::um::shellapi::{ShellExecuteExW, SEE_MASK_NOCLOSEPROCESS, SHELLEXECUTEINFOW};
+    use winapi::um::synchapi::WaitForSingleObject;
+    use winapi::um::winbase::INFINITE;
+    use winapi::um::winuser::SW_HIDE;
+
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let base = env::temp_dir().join(format!("betterncm-elevated-{}-{id}", process::id()));
+    let request = base.with_extension("request.json");
+    let result = base.with_extension("result.json");
+    let content = serde_json::to_vec(&HelperRequest {
+        steps: steps.to_vec(),
+        checks: checks.to_vec(),
+    })?;
+    fs::write(&request, &content)
+        .with_context(|| format!("Failed to write '{}'", request.display()))?;
+    let _ = fs::remove_file(&result);
+
+    // The temp directory is writable by the user, so the helper only accepts this exact request
+    let exe = env::current_exe()?;
+    let params = format!(
+        "--lang {} elevated-helper \"{}\" \"{}\" {}",
+        i18n::lang().tag(),
+        request.display(),
+        result.display(),
+        sha256_bytes(&content)
+    );
+    tracing::info!(steps = ?steps, checks = ?checks, "starting elevated helper");
+
+    let verb = wide(OsStr::new("runas"));
+    let file = wide(exe.as_os_str());
+    let params = wide(OsStr::new(&params));
+    let exit_code = unsafe {
+        let mut info: SHELLEXECUTEINFOW = mem::zeroed();
+        info.cbSize = mem::size_of::<SHELLEXECUTEINFOW>() as u32;
+        info.fMask = SEE_MASK_NOCLOSEPROCESS;
+        info.lpVerb = verb.as_ptr();
+        info.lpFile = file.as_ptr();
+        info.lpParameters = params.as_ptr();
+        info.nShow = SW_HIDE;
+        if ShellExecuteExW(&mut info) == 0 {
+            let error = GetLastError();
+            let _ = fs::remove_file(&request);
+            if error == ERROR_CANCELLED {
+                bail!(msgs().elevation_cancelled);
+            }
+            bail!(
+                "Failed to start the elevated helper: {}",
+                std::io::Error::from_raw_os_error(error as i32)
+            );
+        }
+        WaitForSingleObject(info.hProcess, INFINITE);
+        let mut code = 0;
+        GetExitCodeProcess(info.hProcess, &mut code);
+        CloseHandle(info.hProcess);
+        code
+    };
+    let _ = fs::remove_file(&request);
+
+    let content = fs::read_to_string(&result);
+    let _ = fs::remove_file(&result);
+    let helper: HelperResult = serde_json::from_str(&content.with_context(|| {
+        format!("The elevated helper exited with code {exit_code} and left no result")
+    })?)?;
+    tracing::info!(exit_code, result = ?helper, "elevated helper finished");
+    match (helper.summary, helper.error) {
+        (_, Some(error)) => bail!(error),
+        (Some(summary), None) => Ok(summary),
+        (None, None) => bail!("The elevated helper exited with code {exit_code}"),
+    }
+}
+
+/// The helper has no window, so its progress only goes to the log.
+struct HelperReporter;
+
+impl Reporter for HelperReporter {
+    fn tip(&self, tip: String) {
+        if !tip.is_empty() {
+            tracing::info!(tip = %tip, "elevated helper progress");
+        }
+    }
+
+    fn progress(&self, _progress: f64) {}
+
+    fn download(&self, _progress: &DownloadProgress) {}
+}
+
+/// Reads the request written by [`run_elevated`], refusing it unless it hashes to `sha256`.
+fn read_request(path: &Path, sha256: &str) -> Result<HelperRequest> {
+    let content = fs::read(path).with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let actual = sha256_bytes(&content);
+    if !actual.eq_ignore_ascii_case(sha256) {
+        bail!(
+            "The request '{}' was changed after it was written: expected {sha256}, got {actual}",
+            path.display()
+        );
+    }
+    Ok(serde_json::from_slice(&content)?)
+}
+
+/// Creates a directory under the system temp directory that only administrators can access.
+///
+/// It fails when something already exists at the path, since that could have been placed there
+/// by the unelevated user.
+fn create_admin_only_dir() -> Result<PathBuf> {
+    use winapi::shared::minwindef::FALSE;
+    use winapi::shared::sddl::ConvertStringSecurityDescriptorToSecurityDescriptorW;
+    use winapi::um::fileapi::CreateDirectoryW;
+    use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+    use winapi::um::winbase::LocalFree;
+
+    let windows = env::var_os("SystemRoot").unwrap_or_else(|| r"C:\Windows".into());
+    let dir = Path::new(&windows)
+        .join("Temp")
+        .join(format!("betterncm-elevated-{}", process::id()));
+    let sddl = wide(OsStr::new(ADMIN_ONLY_SDDL));
+    let path = wide(dir.as_os_str());
+    unsafe {
+        let mut descriptor = ptr::null_mut();
+        if ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            ptr::null_mut(),
+        ) == 0
+        {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to build the staging directory's permissions");
+        }
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor,
+            bInheritHandle: FALSE,
+        };
+        let created = CreateDirectoryW(path.as_ptr(), &mut attributes);
+        let error = std::io::Error::last_os_error();
+        LocalFree(descriptor);
+        if created == 0 {
+            return Err(error).with_context(|| format!("Failed to create '{}'", dir.display()));
+        }
+    }
+    Ok(dir)
+}
+
+/// Entry point of the elevated helper started by [`run_elevated`].
+pub fn run_helper(request: &Path, result: &Path, sha256: &str) -> Result<i32> {
+    let outcome = read_request(request, sha256).and_then(|request| {
+        let staging = create_admin_only_dir()?;
+        tracing::info!(staging = %staging.display(), "staging elevated inputs");
+        let summary =
+            operations::run_staged(&request.steps, &request.checks, &staging, &HelperReporter);
+        let _ = fs::remove_dir_all(&staging);
+        summary
+    });
+    let code = if outcome.is_ok() { 0 } else { 1 };
+    let helper = match outcome {
+        Ok(summary) => HelperResult {
+            summary: Some(summary),
+            error: None,
+        },
+        Err(err) => {
+            tracing::error!(error = ?err, "elevated steps failed");
+            HelperResult {
+                summary: None,
+                error: Some(format!("{err:#}")),
+            }
+        }
+    };
+    let content = serde_json::to_vec(&helper)?;
+    // The result sits in the user's temp directory, so nothing already there is written through
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(result)
+        .and_then(|mut file| file.write_all(&content))
+        .with_context(|| format!("Failed to write '{}'", result.display()))?;
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn refuses_changed_requests() {
+        let dir = TempDir::new("elevated");
+        let path = dir.join("request.json");
+        let request = HelperRequest {
+            steps: vec![Step::DeleteFile {
+                path: PathBuf::from(r"C:\Program Files\Netease\CloudMusic\msimg32.dll"),
+            }],
+            checks: vec![],
+        };
+        let content = serde_json::to_vec(&request).unwrap();
+        let sha256 = sha256_bytes(&content);
+        fs::write(&path, &content).unwrap();
+        assert_eq!(read_request(&path, &sha256).unwrap().steps, request.steps);
+        assert!(read_request(&path, &sha256.to_uppercase()).is_ok());
+
+        let mut changed = request;
+        changed.steps.push(Step::DeleteDir {
+            path: PathBuf::from(r"C:\Windows"),
+        });
+        fs::write(&path, serde_json::to_vec(&changed).unwrap()).unwrap();
+        assert!(read_request(&path, &sha256).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::{fill, msgs};
+use crate::manifest::{self, Channel, Manifest};
+use crate::ncm_utils::{
+    get_betterncm_profile_path, get_file_version, get_ncm_install_path, Ncm, NcmType,
+};
+use crate::operations::{self, Operation, Plan, Step};
+use crate::policy;
+use crate::settings::installer_data_dir;
+
+/// Written after every install so later runs can tell when NCM changed underneath BetterNCM.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstallReceipt {
+    pub ncm_path: PathBuf,
+    pub ncm_version: Version,
+    pub ncm_arch: NcmType,
+    pub channel: Channel,
+    pub betterncm_version: Version,
+    pub installed_at: u64,
+    /// Everything an archive payload put into the NCM directory and the profile.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<PathBuf>,
+}
+
+pub fn receipt_path() -> PathBuf {
+    installer_data_dir().join("receipt.json")
+}
+
+impl InstallReceipt {
+    pub fn new(ncm: &Ncm, channel: Channel, betterncm_version: &Version) -> InstallReceipt {
+        InstallReceipt {
+            ncm_path: ncm.path.clone(),
+            ncm_version: ncm.version.clone(),
+            ncm_arch: ncm.ncm_type.clone(),
+            channel,
+            betterncm_version: betterncm_version.clone(),
+            installed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            files: vec![],
+        }
+    }
+
+    pub fn load() -> Option<InstallReceipt> {
+        fs::read_to_string(receipt_path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(installer_data_dir())?;
+        fs::write(receipt_path(), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drift {
+    DllMissing,
+    NcmUpdated { from: Version, to: Version },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::DllMissing => f.write_str(msgs().dll_missing),
+            Drift::NcmUpdated { from, to } => {
+                f.write_str(&fill(msgs().ncm_updated, &[("from", from), ("to", to)]))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CheckOutcome {
+    /// Nothing was installed by this installer, or everything still matches the receipt.
+    Unchanged,
+    Reapply {
+        drift: Drift,
+        plan: Plan,
+    },
+    NoAdaptedVersion {
+        drift: Drift,
+        ncm_version: Version,
+    },
+}
+
+fn detect_drift(receipt: &InstallReceipt, ncm: &Ncm) -> Option<Drift> {
+    if !ncm.path.join("msimg32.dll").exists() {
+        Some(Drift::DllMissing)
+    } else if ncm.version != receipt.ncm_version {
+        Some(Drift::NcmUpdated {
+            from: receipt.ncm_version.clone(),
+            to: ncm.version.clone(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Compares the NCM install from the last receipt with its current state and plans what brings
+/// BetterNCM back.
+pub fn check(manifest: &Manifest) -> Result<CheckOutcome> {
+    let receipt = match InstallReceipt::load() {
+        Some(receipt) => receipt,
+        None => return Ok(CheckOutcome::Unchanged),
+    };
+    let ncm = match Ncm::get_ncm_by_path(receipt.ncm_path.clone()) {
+        Ok(ncm) => ncm,
+        Err(err) => {
+            // Reinstalling NCM somewhere else leaves it without BetterNCM as well
+            tracing::warn!(error = ?err, receipt = ?receipt, "NCM from the receipt is gone");
+            match get_ncm_install_path().and_then(Ncm::get_ncm_by_path) {
+                Ok(ncm) => ncm,
+                Err(err) => {
+                    tracing::info!(error = ?err, "NCM is not installed anymore");
+                    return Ok(CheckOutcome::Unchanged);
+                }
+            }
+        }
+    };
+
+    let Some(drift) = detect_drift(&receipt, &ncm) else {
+        return Ok(CheckOutcome::Unchanged);
+    };
+    let dll = ncm.path.join("msimg32.dll");
+    tracing::info!(drift = ?drift, receipt = ?receipt, ncm = ?ncm, "installation drifted");
+    // Unattended checks must not get around the refusal of an install or a deploy
+    if !manifest.is_supported_by(&manifest::installer_version()) {
+        bail!("{drift}: {}", msgs().manifest_unsupported);
+    }
+
+    let pinned = policy::active().and_then(|policy| policy.version.as_ref());
+    let entry = match manifest.resolve(receipt.channel, &ncm, pinned)? {
+        Some((_, entry)) => entry,
+        None => {
+            return Ok(CheckOutcome::NoAdaptedVersion {
+                drift,
+                ncm_version: ncm.version,
+            })
+        }
+    };
+
+    let plan = if get_file_version(&dll).ok().as_ref() == Some(&entry.version) {
+        // The installed version is still the adapted one, only the receipt is outdated
+        Plan {
+            operation: Operation::Update,
+            steps: vec![Step::RecordInstall {
+                receipt: InstallReceipt {
+                    files: receipt.files.clone(),
+                    ..InstallReceipt::new(&ncm, receipt.channel, &entry.version)
+                },
+            }],
+        }
+    } else {
+        let operation = if dll.exists() {
+            Operation::Update
+        } else {
+            Operation::Install
+        };
+        let profile_dir = get_betterncm_profile_path();
+        operations::plan_install(operation, &ncm, receipt.channel, entry, &profile_dir)
+    };
+    Ok(CheckOutcome::Reapply { drift, plan })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn ncm(path: PathBuf, version: Version) -> Ncm {
+        Ncm {
+            path,
+            version,
+            ncm_type: NcmType::X64,
+        }
+    }
+
+    #[test]
+    fn detects_what_changed_since_the_install() {
+        let dir = TempDir::new("receipt");
+        let installed = ncm(dir.to_path_buf(), Version::new(2, 10, 3));
+        let receipt = InstallReceipt::new(&installed, Channel::Stable, &Version::new(1, 2, 0));
+
+        assert_eq!(detect_drift(&receipt, &installed), Some(Drift::DllMissing));
+        fs::write(dir.join("msimg32.dll"), b"").unwrap();
+        assert_eq!(detect_drift(&receipt, &installed), None);
+        let updated = ncm(dir.to_path_buf(), Version::new(2, 10, 4));
+        assert_eq!(
+            detect_drift(&receipt, &updated),
+            Some(Drift::NcmUpdated {
+                from: Version::new(2, 10, 3),
+                to: Version::new(2, 10, 4),
+            })
+        );
+        // NCM reinstalled to another directory, which BetterNCM was never copied to
+        let moved = ncm(dir.join("moved"), Version::new(2, 10, 3));
+        assert_eq!(detect_drift(&receipt, &moved), Some(Drift::DllMissing));
+    }
+
+    #[test]
+    fn receipts_round_trip() {
+        let receipt: InstallReceipt = serde_json::from_str(
+            r#"{
+                "ncm_path": "C:\\CloudMusic",
+                "ncm_version": "2.10.3",
+                "ncm_arch": "X64",
+                "channel": "stable",
+                "betterncm_version": "1.2.0",
+                "installed_at": 0
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(receipt.channel, Channel::Stable);
+        assert!(receipt.files.is_empty());
+        let json = serde_json::to_string(&receipt).unwrap();
+        assert_eq!(
+            serde_json::from_str::<InstallReceipt>(&json).unwrap(),
+            receipt
+        );
+    }
+}
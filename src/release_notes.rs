@@ -0,0 +1,143 @@
+use anyhow::Result;
+use semver::Version;
+
+use crate::manifest::{Manifest, ManifestEntry, FETCH_TIMEOUT_SECS};
+use crate::network;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReleaseNote {
+    pub version: Version,
+    /// Markdown; `None` when the notes at `url` could not be fetched.
+    pub text: Option<String>,
+    pub url: Option<String>,
+}
+
+fn fetch_changelog(url: &str) -> Result<String> {
+    let response = network::get(url, |request| {
+        request.timeout(network::timeout_or(FETCH_TIMEOUT_SECS))
+    })?;
+    Ok(response.into_string()?.trim().to_string())
+}
+
+/// Returns `None` for entries that come without any notes.
+fn note_for(entry: &ManifestEntry) -> Option<ReleaseNote> {
+    if entry.changelog.is_none() && entry.changelog_url.is_none() {
+        return None;
+    }
+    let text = entry.changelog.clone().or_else(|| {
+        let url = entry.changelog_url.as_deref()?;
+        fetch_changelog(url)
+            .map_err(|err| tracing::warn!(url, error = ?err, "failed to fetch release notes"))
+            .ok()
+    });
+    Some(ReleaseNote {
+        version: entry.version.clone(),
+        text,
+        url: entry.changelog_url.clone(),
+    })
+}
+
+/// Release notes of every version an update from `installed` to `target` brings, newest first.
+pub fn collect(
+    manifest: &Manifest,
+    installed: Option<&Version>,
+    target: &Version,
+) -> Vec<ReleaseNote> {
+    manifest
+        .versions_between(installed, target)
+        .into_iter()
+        .filter_map(note_for)
+        .collect()
+}
+
+/// Joins the notes into one Markdown document.
+pub fn render(notes: &[ReleaseNote]) -> String {
+    notes
+        .iter()
+        .map(|note| {
+            let body = note.text.as_deref().or(note.url.as_deref()).unwrap_or("-");
+            format!("## {}\n{body}", note.version)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap()
+    }
+
+    fn manifest() -> Manifest {
+        let entry = |version: &str, changelog: Option<&str>| {
+            let mut entry = serde_json::json!({
+                "version": version,
+                "url_x86": "https://example.com/x86.dll",
+                "url_x64": "https://example.com/x64.dll"
+            });
+            // Without any notes the version is left out
+            if let Some(changelog) = changelog {
+                entry["changelog"] = changelog.into();
+                entry["changelog_url"] = format!("https://example.com/{version}.md").into();
+            }
+            entry
+        };
+        let json = serde_json::json!({
+            "versions": {
+                ">=2.10.0": entry("1.2.0", Some("- Faster startup")),
+                "<2.10.0": entry("1.0.0", Some("- First release"))
+            },
+            "test": {
+                ">=2.10.0": entry("1.2.0", Some("- Faster startup")),
+                "<2.10.0": entry("1.1.0", None)
+            }
+        });
+        Manifest::parse(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn lists_each_version_once_newest_first() {
+        let manifest = manifest();
+        let versions = |installed: Option<&str>, target: &str| -> Vec<String> {
+            let installed = installed.map(version);
+            manifest
+                .versions_between(installed.as_ref(), &version(target))
+                .iter()
+                .map(|entry| entry.version.to_string())
+                .collect()
+        };
+        assert_eq!(versions(Some("1.0.0"), "1.2.0"), ["1.2.0", "1.1.0"]);
+        assert_eq!(versions(Some("0.9.0"), "1.1.0"), ["1.1.0", "1.0.0"]);
+        assert_eq!(versions(None, "1.2.0"), ["1.2.0"]);
+        assert!(versions(Some("1.2.0"), "1.2.0").is_empty());
+    }
+
+    #[test]
+    fn collects_only_versions_with_notes() {
+        let notes = collect(&manifest(), Some(&version("0.9.0")), &version("1.2.0"));
+        let versions: Vec<String> = notes.iter().map(|note| note.version.to_string()).collect();
+        assert_eq!(versions, ["1.2.0", "1.0.0"]);
+        assert_eq!(notes[0].text.as_deref(), Some("- Faster startup"));
+    }
+
+    #[test]
+    fn renders_markdown_sections() {
+        let note = |version_text: &str, text: Option<&str>, url: Option<&str>| ReleaseNote {
+            version: version(version_text),
+            text: text.map(str::to_string),
+            url: url.map(str::to_string),
+        };
+        let notes = [
+            note("1.2.0", Some("- Faster startup"), None),
+            note("1.1.0", None, Some("https://example.com/1.1.0.md")),
+            note("1.0.0", None, None),
+        ];
+        assert_eq!(
+            render(&notes),
+            "## 1.2.0\n- Faster startup\n\n## 1.1.0\nhttps://example.com/1.1.0.md\n\n## 1.0.0\n-"
+        );
+        assert_eq!(render(&[]), "");
+    }
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+
+use crate::instance;
+use crate::manifest::InstallerRelease;
+use crate::ncm_utils::sha256_file;
+
+fn sibling_path(suffix: &str) -> Result<PathBuf> {
+    let exe = env::current_exe()?;
+    let name = exe
+        .file_stem()
+        .context("Invalid installer path")?
+        .to_string_lossy()
+        .to_string();
+    Ok(exe.with_file_name(format!("{name}.{suffix}.exe")))
+}
+
+pub fn download_path() -> Result<PathBuf> {
+    sibling_path("new")
+}
+
+/// Removes the executable left behind by a previous self-update.
+pub fn cleanup_old_executable() {
+    if let Ok(old) = sibling_path("old") {
+        if old.exists() && fs::remove_file(&old).is_ok() {
+            tracing::info!(path = %old.display(), "removed old installer executable");
+        }
+    }
+}
+
+/// Verifies the downloaded installer and swaps it in place of the running one, then starts it.
+///
+/// Windows refuses to overwrite a running executable but allows renaming it, so the current
+/// executable is moved aside and removed on the next start.
+pub fn replace_and_restart(release: &InstallerRelease) -> Result<()> {
+    let downloaded = download_path()?;
+    let hash = sha256_file(&downloaded)?;
+    if !hash.eq_ignore_ascii_case(&release.sha256) {
+        let _ = fs::remove_file(&downloaded);
+        tracing::error!(expected = %release.sha256, actual = %hash, "installer hash mismatch");
+        bail!("Hash mismatch for downloaded installer {}", release.version);
+    }
+
+    let current = env::current_exe()?;
+    let old = sibling_path("old")?;
+    let _ = fs::remove_file(&old);
+    fs::rename(&current, &old).context("Failed to move the running installer aside")?;
+    if let Err(err) = fs::rename(&downloaded, &current) {
+        let _ = fs::rename(&old, &current);
+        return Err(err).context("Failed to replace the installer");
+    }
+    tracing::info!(version = %release.version, path = %current.display(), "replaced installer");
+
+    // The new installer would otherwise find this window and exit right away
+    let released = instance::release();
+    if let Err(err) = Command::new(&current).spawn() {
+        if released {
+            instance::claim();
+        }
+        return Err(err).context("Failed to start the new installer");
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,42 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::Lang;
+use crate::ncm_utils::get_betterncm_profile_path;
+use crate::network::NetworkSettings;
+use crate::theme::ThemeSetting;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub lang: Option<Lang>,
+    pub offline_bundle: Option<PathBuf>,
+    pub theme: ThemeSetting,
+    pub network: NetworkSettings,
+}
+
+pub fn installer_data_dir() -> PathBuf {
+    get_betterncm_profile_path().join("installer")
+}
+
+fn settings_path() -> PathBuf {
+    installer_data_dir().join("settings.json")
+}
+
+impl Settings {
+    pub fn load() -> Settings {
+        fs::read_to_string(settings_path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(installer_data_dir())?;
+        fs::write(settings_path(), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
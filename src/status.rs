@@ -0,0 +1,285 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use semver::Version;
+use serde::Serialize;
+
+use crate::conflicts::{self, Conflict};
+use crate::manifest::{Channel, Manifest};
+use crate::ncm_utils::{
+    get_betterncm_profile_path, get_file_version, get_ncm_install_path, sha256_file, Ncm, NcmType,
+};
+use crate::privilege::{self, Privileges};
+use crate::vc_runtime::{self, VcRuntimeStatus};
+
+/// Bumped whenever a field of [`Status`] changes meaning or is removed.
+pub const STATUS_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DllInfo {
+    pub path: PathBuf,
+    pub version: Option<Version>,
+    pub sha256: Option<String>,
+}
+
+impl DllInfo {
+    pub fn read(path: PathBuf) -> Option<DllInfo> {
+        if !path.exists() {
+            return None;
+        }
+        Some(DllInfo {
+            version: get_file_version(&path).ok(),
+            sha256: sha256_file(&path).ok(),
+            path,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AdaptedVersions {
+    pub stable: Option<Version>,
+    pub test: Option<Version>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NcmStatus {
+    pub path: PathBuf,
+    pub version: Version,
+    pub arch: NcmType,
+    pub betterncm: Option<DllInfo>,
+    pub legacy_install: bool,
+    /// Whether the installer can change the NCM directory without elevating.
+    pub writable: bool,
+    pub conflicts: Vec<Conflict>,
+    /// Empty when the manifest could not be read.
+    pub adapted: Option<AdaptedVersions>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileStatus {
+    pub path: PathBuf,
+    pub exists: bool,
+    /// `BETTERNCM_PROFILE` as seen by the installer process.
+    pub env: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub schema_version: u32,
+    pub installer_version: &'static str,
+    pub ncm: Vec<NcmStatus>,
+    pub profile: ProfileStatus,
+    pub plugins: Vec<String>,
+    pub vc_runtime: Vec<VcRuntimeStatus>,
+    pub privileges: Privileges,
+}
+
+pub fn list_plugins(profile: &Path) -> Vec<String> {
+    let mut plugins: Vec<String> = fs::read_dir(profile.join("plugins"))
+        .map(|dir| {
+            dir.filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    plugins.sort();
+    plugins
+}
+
+fn adapted_versions(manifest: &Manifest, ncm: &Ncm) -> AdaptedVersions {
+    let find = |channel| {
+        manifest
+            .find_adapted(channel, ncm)
+            .ok()
+            .flatten()
+            .map(|(_, entry)| entry.version.clone())
+    };
+    AdaptedVersions {
+        stable: find(Channel::Stable),
+        test: find(Channel::Test),
+    }
+}
+
+/// Collects the installation state; `extra_ncm` adds an install picked by hand in the window.
+pub fn collect(extra_ncm: Option<Ncm>, manifest: Option<&Manifest>) -> Status {
+    let mut ncm: Vec<Ncm> = get_ncm_install_path()
+        .and_then(Ncm::get_ncm_by_path)
+        .into_iter()
+        .collect();
+    if let Some(extra_ncm) = extra_ncm {
+        if !ncm.contains(&extra_ncm) {
+            ncm.push(extra_ncm);
+        }
+    }
+
+    let profile = get_betterncm_profile_path();
+    Status {
+        schema_version: STATUS_SCHEMA_VERSION,
+        installer_version: env!("CARGO_PKG_VERSION"),
+        ncm: ncm
+            .into_iter()
+            .map(|ncm| NcmStatus {
+                betterncm: DllInfo::read(ncm.path.join("msimg32.dll")),
+                legacy_install: ncm.path.join("cloudmusicn.exe").exists(),
+                writable: privilege::dir_writable(&ncm.path),
+                conflicts: conflicts::scan(&ncm.path),
+                adapted: manifest.map(|manifest| adapted_versions(manifest, &ncm)),
+                path: ncm.path,
+                version: ncm.version,
+                arch: ncm.ncm_type,
+            })
+            .collect(),
+        plugins: list_plugins(&profile),
+        profile: ProfileStatus {
+            exists: profile.is_dir(),
+            env: std::env::var("BETTERNCM_PROFILE").ok(),
+            path: profile,
+        },
+        vc_runtime: [NcmType::X86, NcmType::X64]
+            .iter()
+            .map(vc_runtime::detect)
+            .collect(),
+        privileges: privilege::current(),
+    }
+}
+
+fn or_none(value: Option<impl fmt::Display>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Installer: {}", self.installer_version)?;
+        if self.ncm.is_empty() {
+            writeln!(f, "NCM: not installed")?;
+        }
+        for ncm in &self.ncm {
+            writeln!(
+                f,
+                "NCM: {} ({:?}) at {}",
+                ncm.version,
+                ncm.arch,
+                ncm.path.display()
+            )?;
+            match &ncm.betterncm {
+                Some(dll) => writeln!(
+                    f,
+                    "  BetterNCM: {} sha256 {}",
+                    or_none(dll.version.as_ref()),
+                    or_none(dll.sha256.as_ref())
+                )?,
+                None => writeln!(f, "  BetterNCM: not installed")?,
+            }
+            writeln!(f, "  Legacy install: {}", ncm.legacy_install)?;
+            writeln!(f, "  Writable without elevation: {}", ncm.writable)?;
+            for conflict in &ncm.conflicts {
+                writeln!(f, "  Conflict: {}", conflict.path.display())?;
+            }
+            if let Some(adapted) = &ncm.adapted {
+                writeln!(
+                    f,
+                    "  Adapted: stable {}, test {}",
+                    or_none(adapted.stable.as_ref()),
+                    or_none(adapted.test.as_ref())
+                )?;
+            }
+        }
+        writeln!(
+            f,
+            "Profile: {}{}",
+            self.profile.path.display(),
+            if self.profile.exists {
+                ""
+            } else {
+                " (missing)"
+            }
+        )?;
+        writeln!(f, "Plugins: {}", self.plugins.join(", "))?;
+        for vc in &self.vc_runtime {
+            writeln!(
+                f,
+                "VC runtime {:?}: {}",
+                vc.arch,
+                or_none(vc.installed_version.as_ref())
+            )?;
+        }
+        writeln!(
+            f,
+            "Privileges: elevated {}, scope {:?}, HKLM environment writable {}",
+            self.privileges.elevated, self.privileges.scope, self.privileges.machine_env_writable
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::privilege::InstallScope;
+    use crate::test_util::TempDir;
+
+    fn status(profile: PathBuf) -> Status {
+        Status {
+            schema_version: STATUS_SCHEMA_VERSION,
+            installer_version: env!("CARGO_PKG_VERSION"),
+            ncm: vec![NcmStatus {
+                path: PathBuf::from(r"C:\CloudMusic"),
+                version: Version::new(2, 10, 3),
+                arch: NcmType::X64,
+                betterncm: None,
+                legacy_install: false,
+                writable: true,
+                conflicts: vec![],
+                adapted: Some(AdaptedVersions {
+                    stable: Some(Version::new(1, 2, 0)),
+                    test: None,
+                }),
+            }],
+            profile: ProfileStatus {
+                path: profile,
+                exists: false,
+                env: None,
+            },
+            plugins: vec![],
+            vc_runtime: vec![],
+            privileges: Privileges {
+                elevated: false,
+                scope: InstallScope::PerUser,
+                machine_env_writable: false,
+            },
+        }
+    }
+
+    #[test]
+    fn json_keeps_its_schema() {
+        let json = serde_json::to_value(status(PathBuf::from(r"C:\profile"))).unwrap();
+        assert_eq!(json["schema_version"], STATUS_SCHEMA_VERSION);
+        let ncm = &json["ncm"][0];
+        assert_eq!(ncm["version"], "2.10.3");
+        assert_eq!(ncm["arch"], "X64");
+        assert_eq!(ncm["betterncm"], serde_json::Value::Null);
+        assert_eq!(ncm["adapted"]["stable"], "1.2.0");
+        assert_eq!(ncm["adapted"]["test"], serde_json::Value::Null);
+        assert_eq!(json["privileges"]["scope"], "per_user");
+    }
+
+    #[test]
+    fn text_output_marks_missing_values() {
+        let text = status(PathBuf::from(r"C:\profile")).to_string();
+        assert!(text.contains("BetterNCM: not installed"), "{text}");
+        assert!(text.contains("Adapted: stable 1.2.0, test -"), "{text}");
+        assert!(text.contains("(missing)"), "{text}");
+    }
+
+    #[test]
+    fn lists_plugins_by_name() {
+        let profile = TempDir::new("status");
+        assert!(list_plugins(&profile).is_empty());
+        fs::create_dir_all(profile.join("plugins")).unwrap();
+        for name in ["b.plugin", "a.plugin"] {
+            fs::write(profile.join("plugins").join(name), b"").unwrap();
+        }
+        assert_eq!(list_plugins(&profile), ["a.plugin", "b.plugin"]);
+    }
+}
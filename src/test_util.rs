@@ -0,0 +1,39 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A fresh directory under the system temp directory for one test, removed again when dropped,
+/// which also happens when the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// `name` keeps the tests that run in parallel apart.
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("betterncm-test-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use druid::{Data, ExtEventSink};
+use scl_gui_widgets::theme::color::Theme;
+use serde::{Deserialize, Serialize};
+use winreg::enums::HKEY_CURRENT_USER;
+use winreg::RegKey;
+
+use crate::AppData;
+
+/// Windows sends no event druid could pass on, so the preference is polled instead.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Data, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeSetting {
+    Light,
+    #[default]
+    Dark,
+    System,
+}
+
+pub const ALL_THEMES: [ThemeSetting; 3] = [
+    ThemeSetting::Light,
+    ThemeSetting::Dark,
+    ThemeSetting::System,
+];
+
+impl ThemeSetting {
+    pub fn resolve(self, system_dark: bool) -> Theme {
+        match self {
+            ThemeSetting::Light => Theme::Light,
+            ThemeSetting::Dark => Theme::Dark,
+            ThemeSetting::System if system_dark => Theme::Dark,
+            ThemeSetting::System => Theme::Light,
+        }
+    }
+}
+
+/// Whether apps should use the dark theme according to the Windows personalization settings.
+pub fn system_is_dark() -> bool {
+    RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey(r"Software\Microsoft\Windows\CurrentVersion\Themes\Personalize")
+        .and_then(|key| key.get_value::<u32, _>("AppsUseLightTheme"))
+        .is_ok_and(|light| light == 0)
+}
+
+/// Keeps `AppData::system_dark` in step with the OS preference.
+pub fn watch_system_theme(event_sink: ExtEventSink) {
+    std::thread::spawn(move || {
+        let mut dark = system_is_dark();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let now_dark = system_is_dark();
+            if now_dark != dark {
+                dark = now_dark;
+                tracing::info!(dark, "system theme changed");
+                event_sink.add_idle_callback(move |data: &mut AppData| {
+                    data.system_dark = dark;
+                });
+            }
+        }
+    });
+}
@@ -0,0 +1,236 @@
+use std::sync::RwLock;
+
+use anyhow::{bail, Result};
+use semver::Version;
+use serde::Serialize;
+use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_READ, KEY_WOW64_32KEY, KEY_WOW64_64KEY};
+use winreg::RegKey;
+
+use crate::i18n::{fill, msgs};
+use crate::manifest::VcRedistMirrors;
+use crate::ncm_utils::NcmType;
+
+/// The oldest runtime BetterNCM is installed with.
+///
+/// A DLL built with a 14.x toolset runs on any 14.x runtime at least as new as that toolset
+/// (<https://learn.microsoft.com/cpp/porting/binary-compat-2015-2017>), so the first Visual
+/// Studio 2022 runtime covers builds from Visual Studio 2015 through 2022.
+pub const MIN_VC_RUNTIME_VERSION: Version = Version::new(14, 30, 0);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VcRuntimeStatus {
+    pub arch: NcmType,
+    pub installed_version: Option<Version>,
+}
+
+impl VcRuntimeStatus {
+    pub fn is_sufficient(&self) -> bool {
+        self.installed_version
+            .as_ref()
+            .is_some_and(|version| version >= &MIN_VC_RUNTIME_VERSION)
+    }
+}
+
+fn arch_key(arch: &NcmType) -> &'static str {
+    match arch {
+        NcmType::X86 => "x86",
+        NcmType::X64 => "x64",
+    }
+}
+
+fn read_version(view: u32, arch: &NcmType) -> Option<Version> {
+    let key = RegKey::predef(HKEY_LOCAL_MACHINE)
+        .open_subkey_with_flags(
+            format!(
+                "SOFTWARE\\Microsoft\\VisualStudio\\14.0\\VC\\Runtimes\\{}",
+                arch_key(arch)
+            ),
+            KEY_READ | view,
+        )
+        .ok()?;
+    let installed: u32 = key.get_value("Installed").ok()?;
+    if installed != 1 {
+        return None;
+    }
+    let major: u32 = key.get_value("Major").ok()?;
+    let minor: u32 = key.get_value("Minor").ok()?;
+    let build: u32 = key.get_value("Bld").ok()?;
+    Some(Version::new(major as u64, minor as u64, build as u64))
+}
+
+/// Reads the installed runtime version for an architecture.
+///
+/// The redist registers itself in the 32-bit registry view, and on some versions in the 64-bit
+/// view as well, so both are checked and the newest version wins.
+pub fn detect(arch: &NcmType) -> VcRuntimeStatus {
+    let installed_version = [KEY_WOW64_32KEY, KEY_WOW64_64KEY]
+        .into_iter()
+        .filter_map(|view| read_version(view, arch))
+        .max();
+    VcRuntimeStatus {
+        arch: arch.clone(),
+        installed_version,
+    }
+}
+
+pub fn redist_url(arch: &NcmType) -> String {
+    format!(
+        "https://aka.ms/vs/17/release/VC_redist.{}.exe",
+        arch_key(arch)
+    )
+}
+
+static REDIST_MIRRORS: RwLock<Option<VcRedistMirrors>> = RwLock::new(None);
+
+/// Sets the mirrors the manifest lists for the VC runtime.
+pub fn set_mirrors(mirrors: VcRedistMirrors) {
+    *REDIST_MIRRORS
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = Some(mirrors);
+}
+
+/// Where to download the redist from, and the hash to check it against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedistSource {
+    pub url: String,
+    pub mirrors: Vec<String>,
+    pub sha256: Option<String>,
+}
+
+/// The `aka.ms` link always serves the newest build, which no hash can be pinned for. So the
+/// mirrors are used on their own when the manifest pins the build they serve, and not at all
+/// otherwise.
+fn source_from(mirrors: Option<&VcRedistMirrors>, arch: &NcmType) -> RedistSource {
+    let pinned = mirrors.and_then(|mirrors| match arch {
+        NcmType::X86 => Some((mirrors.x86.as_slice(), mirrors.sha256_x86.as_ref()?)),
+        NcmType::X64 => Some((mirrors.x64.as_slice(), mirrors.sha256_x64.as_ref()?)),
+    });
+    match pinned {
+        Some(([url, mirrors @ ..], sha256)) => RedistSource {
+            url: url.clone(),
+            mirrors: mirrors.to_vec(),
+            sha256: Some(sha256.clone()),
+        },
+        _ => RedistSource {
+            url: redist_url(arch),
+            mirrors: vec![],
+            sha256: None,
+        },
+    }
+}
+
+pub fn redist_source(arch: &NcmType) -> RedistSource {
+    let mirrors = REDIST_MIRRORS.read().unwrap_or_else(|err| err.into_inner());
+    source_from(mirrors.as_ref(), arch)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcInstallResult {
+    Installed,
+    AlreadyInstalled,
+    RebootRequired,
+}
+
+pub fn interpret_exit_code(code: Option<i32>) -> Result<VcInstallResult> {
+    match code {
+        Some(0) => Ok(VcInstallResult::Installed),
+        // ERROR_PRODUCT_VERSION: the same or a newer runtime is already installed
+        Some(1638) => Ok(VcInstallResult::AlreadyInstalled),
+        // ERROR_SUCCESS_REBOOT_REQUIRED / ERROR_SUCCESS_REBOOT_INITIATED
+        Some(3010) | Some(1641) => Ok(VcInstallResult::RebootRequired),
+        // ERROR_INSTALL_ALREADY_RUNNING
+        Some(1618) => bail!("{}", msgs().vc_install_busy),
+        code => bail!(
+            "{}",
+            fill(
+                msgs().vc_install_failed,
+                &[(
+                    "code",
+                    &code.map_or("-".to_string(), |code| code.to_string())
+                )]
+            )
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(installed_version: Option<Version>) -> VcRuntimeStatus {
+        VcRuntimeStatus {
+            arch: NcmType::X64,
+            installed_version,
+        }
+    }
+
+    #[test]
+    fn requires_the_vs_2022_runtime() {
+        assert!(!status(None).is_sufficient());
+        assert!(!status(Some(Version::new(14, 29, 30133))).is_sufficient());
+        assert!(status(Some(Version::new(14, 30, 0))).is_sufficient());
+        assert!(status(Some(Version::new(14, 38, 33135))).is_sufficient());
+    }
+
+    #[test]
+    fn interprets_redist_exit_codes() {
+        assert_eq!(
+            interpret_exit_code(Some(0)).unwrap(),
+            VcInstallResult::Installed
+        );
+        assert_eq!(
+            interpret_exit_code(Some(1638)).unwrap(),
+            VcInstallResult::AlreadyInstalled
+        );
+        assert_eq!(
+            interpret_exit_code(Some(3010)).unwrap(),
+            VcInstallResult::RebootRequired
+        );
+        assert_eq!(
+            interpret_exit_code(Some(1641)).unwrap(),
+            VcInstallResult::RebootRequired
+        );
+        assert!(interpret_exit_code(Some(1618)).is_err());
+        assert!(interpret_exit_code(Some(1603)).is_err());
+        assert!(interpret_exit_code(None).is_err());
+    }
+
+    #[test]
+    fn uses_mirrors_only_with_a_pinned_hash() {
+        let mirrors = VcRedistMirrors {
+            x86: vec!["https://a.example/x86.exe".into()],
+            x64: vec![
+                "https://a.example/x64.exe".into(),
+                "https://b.example/x64.exe".into(),
+            ],
+            sha256_x86: None,
+            sha256_x64: Some("ab".into()),
+        };
+        assert_eq!(
+            source_from(Some(&mirrors), &NcmType::X64),
+            RedistSource {
+                url: "https://a.example/x64.exe".into(),
+                mirrors: vec!["https://b.example/x64.exe".into()],
+                sha256: Some("ab".into()),
+            }
+        );
+        let official = |arch| RedistSource {
+            url: redist_url(&arch),
+            mirrors: vec![],
+            sha256: None,
+        };
+        assert_eq!(
+            source_from(Some(&mirrors), &NcmType::X86),
+            official(NcmType::X86)
+        );
+        assert_eq!(source_from(None, &NcmType::X64), official(NcmType::X64));
+        let no_mirrors = VcRedistMirrors {
+            sha256_x64: Some("ab".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            source_from(Some(&no_mirrors), &NcmType::X64),
+            official(NcmType::X64)
+        );
+    }
+}
@@ -0,0 +1,453 @@
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+
+use semver::Version;
+use serde::Serialize;
+
+use crate::i18n::{fill, msgs};
+use crate::manifest::{Channel, Manifest};
+use crate::ncm_utils::{get_betterncm_profile_path, get_file_version, sha256_file, Ncm};
+use crate::operations::{self, config_path, Operation, Plan, Step};
+use crate::policy;
+use crate::receipt::InstallReceipt;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Issue {
+    DllMissing {
+        version: Version,
+    },
+    DllModified {
+        version: Version,
+        expected: String,
+        actual: String,
+    },
+    /// The installed version is not in the manifest, so there is nothing to compare against.
+    DllUnknown {
+        version: Version,
+    },
+    DllYanked {
+        version: Version,
+        advisory: Option<String>,
+    },
+    LegacyExecutable {
+        path: PathBuf,
+    },
+    LegacyConfig {
+        path: PathBuf,
+    },
+    ProfileMissing {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = msgs();
+        let text = match self {
+            Issue::DllMissing { version } => fill(m.issue_dll_missing, &[("version", version)]),
+            Issue::DllModified { version, .. } => {
+                fill(m.issue_dll_modified, &[("version", version)])
+            }
+            Issue::DllUnknown { version } => fill(m.issue_dll_unknown, &[("version", version)]),
+            Issue::DllYanked { version, advisory } => yanked_text(version, advisory.as_deref()),
+            Issue::LegacyExecutable { path } => {
+                fill(m.issue_legacy_executable, &[("path", &path.display())])
+            }
+            Issue::LegacyConfig { path } => {
+                fill(m.issue_legacy_config, &[("path", &path.display())])
+            }
+            Issue::ProfileMissing { path } => {
+                fill(m.issue_profile_missing, &[("path", &path.display())])
+            }
+        };
+        f.write_str(&text)
+    }
+}
+
+pub fn yanked_text(version: &Version, advisory: Option<&str>) -> String {
+    let text = fill(msgs().issue_dll_yanked, &[("version", version)]);
+    match advisory {
+        Some(advisory) => format!("{text}\n{advisory}"),
+        None => text,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verification {
+    pub issues: Vec<Issue>,
+    /// Only touches what is broken; empty when nothing can or needs to be repaired.
+    pub plan: Plan,
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.issues.is_empty() {
+            return writeln!(f, "{}", msgs().verify_ok);
+        }
+        writeln!(f, "{}", msgs().issues_found)?;
+        for issue in &self.issues {
+            writeln!(f, "- {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What verification reads besides the NCM directory and the manifest.
+struct Environment {
+    /// The receipt of the install into this NCM directory.
+    receipt: Option<InstallReceipt>,
+    profile: PathBuf,
+    /// The v1 configuration, when it is left over.
+    legacy_config: Option<PathBuf>,
+    pinned: Option<Version>,
+}
+
+impl Environment {
+    fn current(ncm: &Ncm) -> Environment {
+        Environment {
+            receipt: InstallReceipt::load().filter(|receipt| receipt.ncm_path == ncm.path),
+            profile: get_betterncm_profile_path(),
+            legacy_config: operations::has_legacy_config().then(config_path),
+            pinned: policy::active().and_then(|policy| policy.version.clone()),
+        }
+    }
+
+    fn channel(&self) -> Channel {
+        self.receipt
+            .as_ref()
+            .map_or(Channel::Stable, |receipt| receipt.channel)
+    }
+}
+
+pub fn verify(ncm: &Ncm, manifest: &Manifest) -> Verification {
+    verify_in(ncm, manifest, &Environment::current(ncm))
+}
+
+fn verify_in(ncm: &Ncm, manifest: &Manifest, environment: &Environment) -> Verification {
+    let mut issues = vec![];
+    let mut download_steps = vec![];
+    let mut replace_steps = vec![];
+    let mut closes_ncm = false;
+    let profile = environment.profile.clone();
+
+    let dll = ncm.path.join("msimg32.dll");
+    // A missing DLL only counts when the receipt says this installer put it there
+    let expected_version = match get_file_version(&dll) {
+        Ok(version) => Some(version),
+        Err(_) => environment
+            .receipt
+            .as_ref()
+            .map(|receipt| receipt.betterncm_version.clone()),
+    };
+    // A yanked build is replaced by the recommended one instead of being restored
+    let yanked = expected_version
+        .as_ref()
+        .and_then(|version| manifest.yanked(version));
+    if let (Some(version), Some(yanked)) = (&expected_version, yanked) {
+        issues.push(Issue::DllYanked {
+            version: version.clone(),
+            advisory: yanked.advisory.clone(),
+        });
+        let channel = environment.channel();
+        if let Ok(Some((_, entry))) = manifest.resolve(channel, ncm, environment.pinned.as_ref()) {
+            replace_steps.extend(
+                operations::plan_install(Operation::Update, ncm, channel, entry, &profile)
+                    .steps
+                    .into_iter()
+                    .filter(|step| {
+                        !matches!(step, Step::KillProcess { .. } | Step::LaunchNcm { .. })
+                    }),
+            );
+            closes_ncm = true;
+        }
+    } else if let Some(version) = expected_version {
+        let entry = manifest.find_version(&version);
+        let expected = entry.and_then(|entry| entry.dll_sha256(&ncm.ncm_type));
+        let issue = if !dll.exists() {
+            Some(Issue::DllMissing {
+                version: version.clone(),
+            })
+        } else if entry.is_none() {
+            Some(Issue::DllUnknown {
+                version: version.clone(),
+            })
+        } else {
+            match (expected, sha256_file(&dll)) {
+                (Some(expected), Ok(actual)) if !actual.eq_ignore_ascii_case(expected) => {
+                    Some(Issue::DllModified {
+                        version: version.clone(),
+                        expected: expected.to_string(),
+                        actual,
+                    })
+                }
+                _ => None,
+            }
+        };
+        if let (Some(issue), Some(entry)) = (&issue, entry) {
+            if matches!(issue, Issue::DllUnknown { .. }) {
+                // Nothing to restore it from
+            } else if entry.is_archive() {
+                // The DLL can't be taken out of the archive alone, so all its files go back
+                replace_steps.extend(
+                    operations::plan_install(
+                        Operation::Repair,
+                        ncm,
+                        environment.channel(),
+                        entry,
+                        &profile,
+                    )
+                    .steps
+                    .into_iter()
+                    .filter(|step| {
+                        !matches!(step, Step::KillProcess { .. } | Step::LaunchNcm { .. })
+                    }),
+                );
+                closes_ncm = true;
+            } else {
+                let temp = env::temp_dir().join("betterncm.dll");
+                download_steps.push(Step::Download {
+                    url: entry.url_for(&ncm.ncm_type).to_string(),
+                    mirrors: entry.mirrors_for(&ncm.ncm_type).to_vec(),
+                    dest: temp.clone(),
+                });
+                if let Some(expected) = expected {
+                    download_steps.push(Step::VerifyFile {
+                        path: temp.clone(),
+                        sha256: expected.to_string(),
+                    });
+                }
+                replace_steps.push(Step::CopyFile {
+                    from: temp,
+                    to: dll.clone(),
+                });
+                closes_ncm = true;
+            }
+        }
+        issues.extend(issue);
+    }
+
+    let legacy_executable = ncm.path.join("cloudmusicn.exe");
+    if legacy_executable.exists() {
+        issues.push(Issue::LegacyExecutable {
+            path: legacy_executable,
+        });
+        if let Some(config) = &environment.legacy_config {
+            issues.push(Issue::LegacyConfig {
+                path: config.clone(),
+            });
+        }
+        replace_steps.extend(
+            operations::plan_migrate_legacy(ncm)
+                .steps
+                .into_iter()
+                .filter(|step| !matches!(step, Step::KillProcess { .. } | Step::LaunchNcm { .. })),
+        );
+        closes_ncm = true;
+    } else if let Some(config) = &environment.legacy_config {
+        issues.push(Issue::LegacyConfig {
+            path: config.clone(),
+        });
+        replace_steps.extend(operations::plan_remove_legacy_config());
+    }
+
+    if !profile.is_dir() {
+        issues.push(Issue::ProfileMissing {
+            path: profile.clone(),
+        });
+        replace_steps.push(Step::CreateDir { path: profile });
+    }
+
+    let mut steps = download_steps;
+    if closes_ncm {
+        steps.push(Step::KillProcess {
+            name: "cloudmusic.exe".into(),
+        });
+        steps.push(Step::KillProcess {
+            name: "cloudmusicn.exe".into(),
+        });
+    }
+    steps.extend(replace_steps);
+    if closes_ncm {
+        steps.push(Step::LaunchNcm {
+            path: ncm.path.clone(),
+        });
+    }
+
+    tracing::info!(ncm = ?ncm, issues = ?issues, "verified installation");
+    Verification {
+        issues,
+        plan: Plan {
+            operation: Operation::Repair,
+            steps,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::ncm_utils::NcmType;
+    use crate::test_util::TempDir;
+
+    const DLL: &[u8] = b"BetterNCM 1.0.0 for x64";
+
+    /// An NCM directory with `files`, next to a profile, and a manifest adapting BetterNCM 1.0.0.
+    fn fixture(name: &str, files: &[(&str, &[u8])]) -> (TempDir, Ncm, Manifest) {
+        let dir = TempDir::new(name);
+        let ncm = Ncm {
+            path: dir.join("CloudMusic"),
+            version: Version::new(2, 10, 6),
+            ncm_type: NcmType::X64,
+        };
+        fs::create_dir_all(&ncm.path).unwrap();
+        fs::create_dir_all(dir.join("profile")).unwrap();
+        fs::write(dir.join("expected.dll"), DLL).unwrap();
+        for (name, content) in files {
+            fs::write(ncm.path.join(name), content).unwrap();
+        }
+        let manifest = serde_json::from_value(serde_json::json!({
+            "versions": {
+                ">=2.10.0": {
+                    "version": "1.0.0",
+                    "url_x86": "https://betterncm.invalid/x86/msimg32.dll",
+                    "url_x64": "https://betterncm.invalid/x64/msimg32.dll",
+                    "sha256_x64": sha256_file(&dir.join("expected.dll")).unwrap()
+                }
+            }
+        }))
+        .unwrap();
+        (dir, ncm, manifest)
+    }
+
+    /// The environment after this installer put BetterNCM 1.0.0 into `ncm`.
+    fn installed(dir: &Path, ncm: &Ncm) -> Environment {
+        Environment {
+            receipt: Some(InstallReceipt::new(
+                ncm,
+                Channel::Stable,
+                &Version::new(1, 0, 0),
+            )),
+            profile: dir.join("profile"),
+            legacy_config: None,
+            pinned: None,
+        }
+    }
+
+    fn step_types(plan: &Plan) -> Vec<String> {
+        plan.steps
+            .iter()
+            .map(|step| {
+                serde_json::to_value(step).unwrap()["type"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn healthy_installs_need_no_repair() {
+        let (dir, ncm, manifest) = fixture("verify-healthy", &[("msimg32.dll", DLL)]);
+        let verification = verify_in(&ncm, &manifest, &installed(&dir, &ncm));
+        assert!(verification.issues.is_empty(), "{:?}", verification.issues);
+        assert!(
+            verification.plan.steps.is_empty(),
+            "{:?}",
+            verification.plan
+        );
+    }
+
+    #[test]
+    fn restores_a_modified_dll() {
+        let (dir, ncm, manifest) = fixture("verify-modified", &[("msimg32.dll", b"patched")]);
+        let verification = verify_in(&ncm, &manifest, &installed(&dir, &ncm));
+        assert!(
+            matches!(
+                &verification.issues[..],
+                [Issue::DllModified { version, .. }] if *version == Version::new(1, 0, 0)
+            ),
+            "{:?}",
+            verification.issues
+        );
+        let steps = &verification.plan.steps;
+        assert!(matches!(&steps[0], Step::Download { url, .. } if url.contains("x64")));
+        assert!(matches!(
+            (&steps[1], &steps[4]),
+            (Step::VerifyFile { path: checked, .. }, Step::CopyFile { from, to })
+                if checked == from && *to == ncm.path.join("msimg32.dll")
+        ));
+        assert_eq!(
+            step_types(&verification.plan),
+            [
+                "download",
+                "verify_file",
+                "kill_process",
+                "kill_process",
+                "copy_file",
+                "launch_ncm"
+            ]
+        );
+    }
+
+    #[test]
+    fn restores_a_missing_dll_this_installer_put_there() {
+        let (dir, ncm, manifest) = fixture("verify-missing", &[]);
+        let verification = verify_in(&ncm, &manifest, &installed(&dir, &ncm));
+        assert_eq!(
+            verification.issues,
+            [Issue::DllMissing {
+                version: Version::new(1, 0, 0)
+            }]
+        );
+        assert!(verification.plan.steps.contains(&Step::CopyFile {
+            from: env::temp_dir().join("betterncm.dll"),
+            to: ncm.path.join("msimg32.dll"),
+        }));
+
+        // Without a receipt, NCM simply runs without BetterNCM
+        let clean = Environment {
+            receipt: None,
+            ..installed(&dir, &ncm)
+        };
+        assert!(verify_in(&ncm, &manifest, &clean).issues.is_empty());
+    }
+
+    #[test]
+    fn migrates_a_leftover_legacy_executable() {
+        let (dir, ncm, manifest) = fixture(
+            "verify-legacy",
+            &[("msimg32.dll", DLL), ("cloudmusicn.exe", b"NCM")],
+        );
+        let verification = verify_in(&ncm, &manifest, &installed(&dir, &ncm));
+        assert_eq!(
+            verification.issues,
+            [Issue::LegacyExecutable {
+                path: ncm.path.join("cloudmusicn.exe")
+            }]
+        );
+        assert!(verification.plan.steps.contains(&Step::RenameFile {
+            from: ncm.path.join("cloudmusicn.exe"),
+            to: ncm.path.join("cloudmusic.exe"),
+        }));
+        assert!(matches!(
+            verification.plan.steps.last(),
+            Some(Step::LaunchNcm { path }) if *path == ncm.path
+        ));
+    }
+
+    #[test]
+    fn yanked_builds_show_their_advisory() {
+        let version = Version::new(1, 0, 0);
+        let plain = yanked_text(&version, None);
+        assert!(plain.contains("1.0.0"));
+        assert_eq!(
+            yanked_text(&version, Some("Crashes NCM on start")),
+            format!("{plain}\nCrashes NCM on start")
+        );
+    }
+}